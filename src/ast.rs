@@ -1,6 +1,17 @@
+pub use miniscript::descriptor::Wildcard;
+
+use crate::runtime::Value;
+use crate::types::Type;
+
 /// Expressions have no side-effects and produce a value
 #[derive(Debug, Clone)]
-pub enum Expr {
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum ExprKind {
     Block(Block),
     Call(Call),
     Or(Or),
@@ -9,27 +20,53 @@ pub enum Expr {
     Ident(Ident),
     Array(Array),
     ArrayAccess(ArrayAccess),
+    Slice(Slice),
+    Range(Range),
+    Map(Map),
     ChildDerive(ChildDerive),
     ScriptFrag(ScriptFrag),
     FnExpr(FnExpr),
     Infix(Infix),
     Not(Not),
+    Neg(Neg),
+    IfExpr(IfExpr),
+    Match(Match),
+    Try(Try),
+    Coalesce(Coalesce),
+    InterpolatedStr(InterpolatedStr),
 
     PubKey(String),
+    SecKey(String),
     Bytes(Vec<u8>),
+    String(String),
     Number(i64),
     Duration(Duration),
     DateTime(DateTime),
     BtcAmount(BtcAmount),
+
+    /// A pre-evaluated value, produced by constant folding
+    Value(Box<Value>),
 }
 
-impl_from_variant!(i64, Expr, Number);
+impl_from_variant!(i64, ExprKind, Number);
 
 /// Statements have side-effects and don't produce a value
 #[derive(Debug, Clone)]
-pub enum Stmt {
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+    /// The `///` doc comment preceding the statement. Only attached to top-level function definitions
+    /// and assignments, by parse() and parse_lib().
+    pub doc: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum StmtKind {
     FnDef(FnDef),
     Assign(Assign),
+    Import(Import),
+    ExprStmt(ExprStmt),
+    Test(Test),
 }
 
 /// A collection of statements and a final expression used as the return value.
@@ -39,25 +76,29 @@ pub struct Block {
     pub stmts: Vec<Stmt>,
     pub return_value: Option<Box<Expr>>,
 }
-impl_from_variant!(Block, Expr);
+impl_from_variant!(Block, ExprKind);
 
 /// A function call expression
 #[derive(Debug, Clone)]
 pub struct Call {
     pub ident: Ident,
     pub args: Vec<Expr>,
+    /// Named arguments, following the positional ones (`f(1, amount: 10000)`)
+    pub named_args: Vec<(Ident, Expr)>,
+    /// Called using the pipeline operator (`$x |> f(1)`), with the first argument on its LHS
+    pub piped: bool,
 }
-impl_from_variant!(Call, Expr);
+impl_from_variant!(Call, ExprKind);
 
 /// Logical OR expression
 #[derive(Debug, Clone)]
 pub struct Or(pub Vec<Expr>);
-impl_from_variant!(Or, Expr);
+impl_from_variant!(Or, ExprKind);
 
 /// Logical AND expression
 #[derive(Debug, Clone)]
 pub struct And(pub Vec<Expr>);
-impl_from_variant!(And, Expr);
+impl_from_variant!(And, ExprKind);
 
 /// Threshold expression
 #[derive(Debug, Clone)]
@@ -65,12 +106,12 @@ pub struct Thresh {
     pub thresh: Box<Expr>,
     pub policies: Box<Expr>,
 }
-impl_from_variant!(Thresh, Expr);
+impl_from_variant!(Thresh, ExprKind);
 
 /// A terminal word expression
 #[derive(Debug, Clone, Eq, Hash, PartialEq)]
 pub struct Ident(pub String);
-impl_from_variant!(Ident, Expr);
+impl_from_variant!(Ident, ExprKind);
 impl From<&str> for Ident {
     fn from(s: &str) -> Self {
         Ident(s.into())
@@ -85,36 +126,107 @@ impl std::fmt::Display for Ident {
 /// An array expression
 #[derive(Debug, Clone)]
 pub struct Array(pub Vec<Expr>);
-impl_from_variant!(Array, Expr);
+impl_from_variant!(Array, ExprKind);
+
+/// A map expression, with its keys and value expressions in source order
+#[derive(Debug, Clone)]
+pub struct Map(pub Vec<(String, Expr)>);
+impl_from_variant!(Map, ExprKind);
 
+/// Array element access (`$arr.0`), or map field access (`$map.name`).
+/// Field names are parsed into a String index expression.
 #[derive(Debug, Clone)]
 pub struct ArrayAccess {
     pub array: Box<Expr>,
     pub index: Box<Expr>,
 }
-impl_from_variant!(ArrayAccess, Expr);
+impl_from_variant!(ArrayAccess, ExprKind);
+
+/// Array or bytes slicing (`$arr[1..3]`, `$bytes[..4]`, `$arr[-2..]`), with an exclusive end.
+/// Negative bounds count back from the end.
+#[derive(Debug, Clone)]
+pub struct Slice {
+    pub value: Box<Expr>,
+    pub start: Option<Box<Expr>>,
+    pub end: Option<Box<Expr>>,
+}
+impl_from_variant!(Slice, ExprKind);
 
+/// A range literal (`0..$n`), evaluating to an array of the numbers from `start` up to (excluding) `end`
+#[derive(Debug, Clone)]
+pub struct Range {
+    pub start: Box<Expr>,
+    pub end: Box<Expr>,
+}
+impl_from_variant!(Range, ExprKind);
+
+/// Child key derivation with the `/` operator (`$xpub/1/2/*`).
+/// Doubles as numeric division when the parent evaluates to a Number.
 #[derive(Debug, Clone)]
 pub struct ChildDerive {
     pub parent: Box<Expr>,
-    pub path: Vec<Expr>,
-    pub is_wildcard: bool,
+    pub path: Vec<DeriveStep>,
+    pub wildcard: Wildcard,
+}
+impl_from_variant!(ChildDerive, ExprKind);
+
+/// A child derivation step, optionally hardened (`/84h` or `/$n'`)
+#[derive(Debug, Clone)]
+pub struct DeriveStep {
+    pub child: Expr,
+    pub hardened: bool,
+}
+
+impl ChildDerive {
+    /// Append a child derivation step to the `parent` expression,
+    /// extending its path if it is already a ChildDerive without a wildcard
+    pub fn push_child(parent: Expr, child: Expr, hardened: bool, span: Span) -> Expr {
+        let mut derive = Self::extend(parent);
+        derive.path.push(DeriveStep { child, hardened });
+        Expr::new(derive, span)
+    }
+
+    /// Append a wildcard to the `parent` expression
+    pub fn push_wildcard(parent: Expr, wildcard: Wildcard, span: Span) -> Expr {
+        let mut derive = Self::extend(parent);
+        derive.wildcard = wildcard;
+        Expr::new(derive, span)
+    }
+
+    fn extend(parent: Expr) -> Self {
+        match parent.kind {
+            ExprKind::ChildDerive(derive) if derive.wildcard == Wildcard::None => derive,
+            _ => ChildDerive {
+                parent: parent.into(),
+                path: vec![],
+                wildcard: Wildcard::None,
+            },
+        }
+    }
 }
-impl_from_variant!(ChildDerive, Expr);
 
 #[derive(Debug, Clone)]
 pub struct ScriptFrag {
     pub fragments: Vec<Expr>,
 }
-impl_from_variant!(ScriptFrag, Expr);
+impl_from_variant!(ScriptFrag, ExprKind);
+
+/// The `??` operator, evaluating to the `rhs` fallback if `lhs` is Null.
+/// The fallback is only evaluated when it is used.
+#[derive(Debug, Clone)]
+pub struct Coalesce {
+    pub lhs: Box<Expr>,
+    pub rhs: Box<Expr>,
+}
+impl_from_variant!(Coalesce, ExprKind);
 
 /// An anonymous function expression
 #[derive(Debug, Clone)]
 pub struct FnExpr {
-    pub signature: Vec<Ident>,
+    pub signature: Signature,
     pub body: Box<Expr>,
 }
-impl_from_variant!(FnExpr, Expr);
+impl_from_variant!(FnExpr, ExprKind);
 
 // An infix operator call with exactly two operands
 // The && || operators which can have any number of operands are handled separately.
@@ -124,7 +236,7 @@ pub struct Infix {
     pub lhs: Box<Expr>,
     pub rhs: Box<Expr>,
 }
-impl_from_variant!(Infix, Expr);
+impl_from_variant!(Infix, ExprKind);
 
 #[derive(Debug, Clone, Copy)]
 pub enum InfixOp {
@@ -137,11 +249,107 @@ pub enum InfixOp {
     Gte,
     Lte,
     Prob,
+    Multiply,
+    Mod,
+    Shl,
+    Shr,
+    BitAnd,
+    BitOr,
+    BitXor,
 }
 
 #[derive(Debug, Clone)]
 pub struct Not(pub Box<Expr>);
-impl_from_variant!(Not, Expr);
+impl_from_variant!(Not, ExprKind);
+
+/// Unary minus
+#[derive(Debug, Clone)]
+pub struct Neg(pub Box<Expr>);
+impl_from_variant!(Neg, ExprKind);
+
+/// A conditional expression. Only the branch matching the condition gets evaluated.
+/// `else if` chains are represented as a nested IfExpr in the `else_val` branch.
+#[derive(Debug, Clone)]
+pub struct IfExpr {
+    pub condition: Box<Expr>,
+    pub then_val: Box<Expr>,
+    pub else_val: Box<Expr>,
+}
+impl_from_variant!(IfExpr, ExprKind);
+
+/// A match expression, evaluating to the body of the first arm whose pattern matches the value
+/// and whose guard (if any) is true. Fails if none of the arms match.
+#[derive(Debug, Clone)]
+pub struct Match {
+    pub value: Box<Expr>,
+    pub arms: Vec<MatchArm>,
+}
+impl_from_variant!(Match, ExprKind);
+
+/// A `pattern if guard => body` match arm
+#[derive(Debug, Clone)]
+pub struct MatchArm {
+    pub pattern: MatchPattern,
+    pub guard: Option<Expr>,
+    pub body: Expr,
+    pub span: Span,
+}
+
+/// A match arm pattern. Unlike binding patterns, these may fail to match.
+#[derive(Debug, Clone)]
+pub enum MatchPattern {
+    /// Matches anything (`_`)
+    Wildcard,
+    /// Matches anything, binding the value to the identifier
+    Bind(Ident),
+    /// Matches values equal to the literal (a number, string, bytes, `true`, `false` or `null`)
+    Literal(Expr),
+    /// Matches values of the type, with the inner pattern (`Policy($p)`, `Bytes|String(_)`)
+    Type(Type, Box<MatchPattern>),
+    /// Matches arrays whose elements match, with the remaining elements optionally
+    /// collected into the `rest` array (`[$a, $b, ...$rest]`, or `[$a, ..._]` to ignore them)
+    Array {
+        elements: Vec<MatchPattern>,
+        rest: Option<Ident>,
+    },
+}
+
+impl MatchPattern {
+    /// The identifiers bound by the pattern
+    pub fn idents(&self) -> Vec<&Ident> {
+        match self {
+            MatchPattern::Wildcard | MatchPattern::Literal(_) => vec![],
+            MatchPattern::Bind(ident) => vec![ident],
+            MatchPattern::Type(_, inner) => inner.idents(),
+            MatchPattern::Array { elements, rest } => elements
+                .iter()
+                .flat_map(MatchPattern::idents)
+                .chain(rest.as_ref().filter(|rest| rest.0 != "_"))
+                .collect(),
+        }
+    }
+}
+
+/// A `try { body } catch $err { handler }` expression, evaluating to the handler if the body fails.
+/// The error is bound to the (optional) identifier as a map describing it, see `Error::into_value()`.
+#[derive(Debug, Clone)]
+pub struct Try {
+    pub body: Box<Expr>,
+    pub error: Option<Ident>,
+    pub handler: Box<Expr>,
+}
+impl_from_variant!(Try, ExprKind);
+
+/// A string literal with interpolated `{...}` expressions
+#[derive(Debug, Clone)]
+pub struct InterpolatedStr(pub Vec<StrPart>);
+impl_from_variant!(InterpolatedStr, ExprKind);
+
+#[derive(Debug, Clone)]
+pub enum StrPart {
+    Str(String),
+    Expr(Expr),
+}
 
 // Duration (relative block height or time)
 #[derive(Debug, Clone, PartialEq)]
@@ -152,7 +360,7 @@ pub enum Duration {
         heightwise: bool,
     },
 }
-impl_from_variant!(Duration, Expr);
+impl_from_variant!(Duration, ExprKind);
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum DurationPart {
@@ -167,32 +375,99 @@ pub enum DurationPart {
 // DateTime (YYYY-MM-DD with optional HH:MM)
 #[derive(Debug, Clone, PartialEq)]
 pub struct DateTime(pub String);
-impl_from_variant!(DateTime, Expr);
+impl_from_variant!(DateTime, ExprKind);
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct BtcAmount(pub String);
-impl_from_variant!(BtcAmount, Expr);
+impl_from_variant!(BtcAmount, ExprKind);
 
 /// A function definition statement
 #[derive(Debug, Clone)]
 pub struct FnDef {
     pub ident: Ident,
-    pub signature: Vec<Ident>,
+    pub signature: Signature,
     pub body: Expr,
 }
-impl_from_variant!(FnDef, Stmt);
+impl_from_variant!(FnDef, StmtKind);
 
 /// An assignment statement
 #[derive(Debug, Clone)]
 pub struct Assign(pub Vec<Assignment>);
-impl_from_variant!(Assign, Stmt);
+impl_from_variant!(Assign, StmtKind);
 
 #[derive(Debug, Clone)]
 pub struct Assignment {
-    pub lhs: Ident,
+    pub lhs: Pattern,
+    /// The annotated type (`let $x: Number = ...`), checked statically and at runtime
+    pub ty: Option<Type>,
     pub rhs: Expr,
 }
 
+/// A binding pattern, used for assignments and function parameters
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Ident(Ident),
+    /// Destructure an array into its elements, with the remaining elements optionally
+    /// collected into the `rest` array (`[$head, ...$rest]`)
+    Array {
+        elements: Vec<Pattern>,
+        rest: Option<Ident>,
+    },
+}
+impl_from_variant!(Ident, Pattern);
+
+/// The parameters of a user function. Parameters may have default values, which must come after
+/// the required ones, and may be followed by a variadic parameter collecting the remaining arguments.
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub params: Vec<Param>,
+    pub rest: Option<Ident>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Param {
+    pub pattern: Pattern,
+    /// The annotated type (`$x: Number`), checked statically and at runtime
+    pub ty: Option<Type>,
+    /// Evaluated in the function's scope at call time, when the argument is not provided
+    pub default: Option<Expr>,
+}
+
+impl Signature {
+    /// The minimum number of arguments, for the parameters without a default
+    pub fn min_args(&self) -> usize {
+        self.params.iter().filter(|p| p.default.is_none()).count()
+    }
+
+    /// The maximum number of arguments, unlimited if there's a variadic parameter
+    pub fn max_args(&self) -> Option<usize> {
+        iif!(self.rest.is_none(), Some(self.params.len()), None)
+    }
+}
+
+/// An import statement, executing a library file and bringing its definitions into scope.
+/// With an `alias`, the definitions are namespaced under it as `alias::name`.
+#[derive(Debug, Clone)]
+pub struct Import {
+    pub path: String,
+    pub alias: Option<Ident>,
+}
+impl_from_variant!(Import, StmtKind);
+
+/// A function call evaluated for its side-effects only, with its return value discarded (`assert($x > 0);`)
+#[derive(Debug, Clone)]
+pub struct ExprStmt(pub Expr);
+impl_from_variant!(ExprStmt, StmtKind);
+
+/// A test declaration (`test "name" { ... }`). Tests are run by the test runner and skipped otherwise.
+/// The body has no return value, or one that is checked to not be `false`.
+#[derive(Debug, Clone)]
+pub struct Test {
+    pub name: String,
+    pub body: Expr,
+}
+impl_from_variant!(Test, StmtKind);
+
 /// A library is collection of statements with no return value
 /// This is always parsed at the top-level and is never contained within an Expr/Stmt.
 #[derive(Debug, Clone)]
@@ -200,42 +475,205 @@ pub struct Library {
     pub stmts: Vec<Stmt>,
 }
 
+/// Identifies the source text that a span refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct SourceId(pub usize);
+
+impl SourceId {
+    /// The main program being parsed/evaluated
+    pub const MAIN: SourceId = SourceId(0);
+
+    /// Create a span for the given byte range within this source
+    pub fn span(self, start: usize, end: usize) -> Span {
+        Span {
+            start,
+            end,
+            source: self,
+        }
+    }
+
+    /// Refer to the code starting at the given offset within this source
+    pub fn at(self, offset: usize) -> SourceOffset {
+        SourceOffset {
+            source: self,
+            offset,
+        }
+    }
+}
+
+/// The source of the code being parsed, along with its offset within the source text.
+/// Used by the parser to create spans, so that nested code (like string interpolations)
+/// can be parsed separately with spans pointing into the full source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceOffset {
+    pub source: SourceId,
+    pub offset: usize,
+}
+
+impl SourceOffset {
+    /// Create a span for the given byte range within the parsed code
+    pub fn span(self, start: usize, end: usize) -> Span {
+        self.source.span(self.offset + start, self.offset + end)
+    }
+}
+
+/// A byte range within a source text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub source: SourceId,
+}
+
+impl Expr {
+    pub fn new<K: Into<ExprKind>>(kind: K, span: Span) -> Self {
+        Expr {
+            kind: kind.into(),
+            span,
+        }
+    }
+}
+
+impl Stmt {
+    pub fn new<K: Into<StmtKind>>(kind: K, span: Span) -> Self {
+        Stmt {
+            kind: kind.into(),
+            span,
+            doc: None,
+        }
+    }
+}
+
 use lalrpop_util::ParseError;
 type LalrError = ParseError<usize, lalrpop_util::lexer::Token<'static>, String>;
 
-impl Expr {
-    pub fn bytes_from_hex(s: &str) -> Result<Expr, LalrError> {
+impl Signature {
+    pub fn new(params: Vec<Param>, rest: Option<Ident>) -> Result<Self, LalrError> {
+        let first_default = params.iter().position(|p| p.default.is_some());
+        if let Some(first_default) = first_default {
+            if params[first_default..].iter().any(|p| p.default.is_none()) {
+                return Err(ParseError::User {
+                    error: "Parameters with default values must come after the required ones"
+                        .into(),
+                });
+            }
+        }
+        Ok(Signature { params, rest })
+    }
+}
+
+impl ExprKind {
+    pub fn bytes_from_hex(s: &str) -> Result<ExprKind, LalrError> {
         use miniscript::bitcoin::hashes::hex::FromHex;
 
-        Ok(Expr::Bytes(Vec::from_hex(s).map_err(|e| {
+        Ok(ExprKind::Bytes(Vec::from_hex(s).map_err(|e| {
             ParseError::User {
                 error: format!("Invalid bytes hex string {}: {}", s, e),
             }
         })?))
     }
 
-    /// Expand escape characters in string literals (\", \\, \n, \r and \t)
-    /// and return the string as Bytes.
-    pub fn bytes_from_escaped_str(s: &str) -> Expr {
-        Expr::Bytes(if !s.contains('\\') {
-            s.as_bytes().to_owned()
-        } else {
-            let mut iter = s.chars();
-            let mut s_new = String::new();
-            while let Some(mut ch) = iter.next() {
-                if ch == '\\' {
-                    let next_ch = iter.next().expect("well formed string guaranteed by regex");
-                    ch = match next_ch {
-                        '\\' | '\"' => next_ch,
-                        'n' => '\n',
-                        'r' => '\r',
-                        't' => '\t',
-                        _ => unreachable!("only valid escape sequences accepted by the regex"),
-                    };
+    /// Parse a string literal (including its quotes) located at the `start` offset of the parsed code.
+    ///
+    /// `{...}` within the string are parsed as interpolated block expressions, with their spans
+    /// pointing into the original source. Empty `{}`s are kept as-is, for use as format() placeholders.
+    pub fn string_from_literal(
+        s: &str,
+        start: usize,
+        source: SourceOffset,
+    ) -> Result<ExprKind, LalrError> {
+        let inner = &s[1..s.len() - 1];
+        let inner_start = start + 1;
+
+        let mut parts = vec![];
+        // The literal text accumulated so far, with escape sequences not yet expanded
+        let mut text = String::new();
+        let mut chars = inner.char_indices();
+        while let Some((pos, ch)) = chars.next() {
+            match ch {
+                '\\' => {
+                    text.push(ch);
+                    text.extend(chars.next().map(|(_, ch)| ch));
                 }
-                s_new.push(ch);
+                '{' => {
+                    let mut depth = 1;
+                    let end = chars
+                        .by_ref()
+                        .find(|(_, ch)| {
+                            match ch {
+                                '{' => depth += 1,
+                                '}' => depth -= 1,
+                                _ => (),
+                            };
+                            depth == 0
+                        })
+                        .map(|(end, _)| end)
+                        .ok_or_else(|| ParseError::User {
+                            error: "Unterminated string interpolation, use \\{ for a literal brace"
+                                .into(),
+                        })?;
+
+                    let code = &inner[pos + 1..end];
+                    if code.trim().is_empty() {
+                        text.push_str(&inner[pos..=end]);
+                    } else {
+                        if !text.is_empty() {
+                            parts.push(StrPart::Str(unescape_str(&text)));
+                            text.clear();
+                        }
+                        let expr = parse_interpolated(code, inner_start + pos + 1, source)?;
+                        parts.push(StrPart::Expr(expr));
+                    }
+                }
+                _ => text.push(ch),
             }
-            s_new.into_bytes()
+        }
+
+        if parts.is_empty() {
+            Ok(ExprKind::String(unescape_str(&text)))
+        } else {
+            if !text.is_empty() {
+                parts.push(StrPart::Str(unescape_str(&text)));
+            }
+            Ok(InterpolatedStr(parts).into())
+        }
+    }
+}
+
+// Parse an interpolated expression as a block, located at the `offset` of the parsed code.
+// Its spans and error locations are shifted to match its position within the full source.
+fn parse_interpolated(code: &str, offset: usize, source: SourceOffset) -> Result<Expr, LalrError> {
+    let offset = source.offset + offset;
+    crate::grammar::BodyParser::new()
+        .parse(source.source.at(offset), code)
+        .map_err(|e| ParseError::User {
+            error: format!(
+                "Invalid string interpolation: {}",
+                e.map_location(|loc| loc + offset)
+            ),
         })
+}
+
+/// Expand escape characters in string literals (\", \\, \n, \r, \t, \{ and \})
+pub fn unescape_str(s: &str) -> String {
+    if !s.contains('\\') {
+        s.to_owned()
+    } else {
+        let mut iter = s.chars();
+        let mut s_new = String::new();
+        while let Some(mut ch) = iter.next() {
+            if ch == '\\' {
+                let next_ch = iter.next().expect("well formed string guaranteed by regex");
+                ch = match next_ch {
+                    '\\' | '\"' | '{' | '}' => next_ch,
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    _ => unreachable!("only valid escape sequences accepted by the regex"),
+                };
+            }
+            s_new.push(ch);
+        }
+        s_new
     }
 }
@@ -0,0 +1,109 @@
+use std::cell::Cell;
+
+use crate::error::{Error, Result};
+
+/// Limits on the resources an evaluation may use, for running untrusted code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum depth of nested user function calls
+    pub max_depth: usize,
+    /// The maximum number of expressions evaluated
+    pub max_steps: usize,
+    /// The maximum total number of array elements, map entries and bytes allocated,
+    /// including the bytes of output written by `print()` and friends
+    pub max_alloc: usize,
+}
+
+impl Limits {
+    /// No limits, the behaviour when evaluating without a budget
+    pub fn unlimited() -> Self {
+        Limits {
+            max_depth: usize::MAX,
+            max_steps: usize::MAX,
+            max_alloc: usize::MAX,
+        }
+    }
+}
+
+// The defaults are suitable for interactive use, and keep the call depth well within the
+// (1MB by default) stack available to WASM
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_depth: 100,
+            max_steps: 10_000_000,
+            max_alloc: 10_000_000,
+        }
+    }
+}
+
+/// Tracks the resources used by a single evaluation against its Limits.
+///
+/// The budget is attached to the evaluation's Scope and shared by all of its child scopes,
+/// including the scopes of functions called from it.
+#[derive(Debug)]
+pub struct Budget {
+    limits: Limits,
+    depth: Cell<usize>,
+    steps: Cell<usize>,
+    alloc: Cell<usize>,
+}
+
+impl Budget {
+    pub fn new(limits: Limits) -> Self {
+        Budget {
+            limits,
+            depth: Cell::new(0),
+            steps: Cell::new(0),
+            alloc: Cell::new(0),
+        }
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Account for the evaluation of an expression
+    pub fn step(&self) -> Result<()> {
+        let steps = self.steps.get() + 1;
+        ensure!(
+            steps <= self.limits.max_steps,
+            Error::StepLimitExceeded(self.limits.max_steps)
+        );
+        self.steps.set(steps);
+        Ok(())
+    }
+
+    /// Account for the allocation of `size` array elements, map entries or bytes.
+    /// Should be called before allocating, where the size is known in advance.
+    pub fn alloc(&self, size: usize) -> Result<()> {
+        let alloc = self.alloc.get().saturating_add(size);
+        ensure!(
+            alloc <= self.limits.max_alloc,
+            Error::AllocLimitExceeded(self.limits.max_alloc)
+        );
+        self.alloc.set(alloc);
+        Ok(())
+    }
+
+    /// Enter a function call. The call depth is restored when the returned guard is dropped.
+    pub fn enter_call(&self) -> Result<CallGuard<'_>> {
+        let depth = self.depth.get() + 1;
+        ensure!(
+            depth <= self.limits.max_depth,
+            Error::DepthLimitExceeded(self.limits.max_depth)
+        );
+        self.depth.set(depth);
+        Ok(CallGuard(self))
+    }
+}
+
+/// Decrements the call depth when the function call returns
+pub struct CallGuard<'a>(&'a Budget);
+
+impl Drop for CallGuard<'_> {
+    fn drop(&mut self) {
+        let depth = &self.0.depth;
+        depth.set(depth.get() - 1);
+    }
+}
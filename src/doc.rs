@@ -0,0 +1,296 @@
+//! Doc comments and the reference documentation generator behind `minsc doc`.
+//!
+//! `///` comments are skipped by the lexer like any other comment. They're collected from the
+//! source after parsing and attached to the top-level function definitions and assignments that
+//! immediately follow them. The reference is generated from these, along with the descriptions of
+//! native functions.
+
+use crate::ast::{self, Pattern, Stmt, StmtKind};
+use crate::function::{Function, NativeFunction};
+use crate::pretty::comments;
+use crate::stdlib::{self, CTV_LIB_SOURCE, ELEMENTS_STDLIB_SOURCE, MINSC_STDLIB_SOURCE};
+use crate::{parse_lib_source, Scope, Value};
+
+/// A documented module: the native functions, or the definitions of a Minsc library
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub name: String,
+    pub items: Vec<Item>,
+}
+
+/// A documented function or variable definition
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    /// The defined name. Assignments of several variables at once are documented as a single item.
+    pub name: String,
+    /// The function signature (`fn tail($arr)`), or the assigned variables for assignments
+    pub signature: String,
+    pub doc: Option<String>,
+}
+
+/// Attach the `///` comments that immediately precede the function definitions and assignments
+/// to their statement, with the `///` prefix (and a single space following it) stripped.
+pub(crate) fn attach_doc_comments(stmts: &mut [Stmt], code: &str) {
+    let comments = comments(code);
+    for stmt in stmts {
+        if !matches!(stmt.kind, StmtKind::FnDef(_) | StmtKind::Assign(_)) {
+            continue;
+        }
+        let before = comments.partition_point(|comment| comment.start < stmt.span.start);
+        let mut lines = vec![];
+        let mut cursor = stmt.span.start;
+        for comment in comments[..before].iter().rev() {
+            // `////` is not a doc comment, to allow for decorative separators
+            let is_doc = comment.text.starts_with("///") && !comment.text.starts_with("////");
+            if !is_doc || !code[comment.end..cursor].trim().is_empty() {
+                break;
+            }
+            let line = &comment.text[3..];
+            lines.push(line.strip_prefix(' ').unwrap_or(line));
+            cursor = comment.start;
+        }
+        if !lines.is_empty() {
+            lines.reverse();
+            stmt.doc = Some(lines.join("\n"));
+        }
+    }
+}
+
+/// Document the standard library: the native functions, followed by the built-in Minsc libraries
+pub fn stdlib_docs() -> Vec<Module> {
+    let mut modules = vec![native_docs("Built-in functions", &Scope::root())];
+    for source in &[MINSC_STDLIB_SOURCE, ELEMENTS_STDLIB_SOURCE, CTV_LIB_SOURCE] {
+        let (name, code) = stdlib::builtin_source(*source).expect("built-in library");
+        let library = parse_lib_source(code, *source).expect("built-in libraries are valid");
+        let name = name.trim_start_matches('<').trim_end_matches('>');
+        modules.push(library_docs(name, &library));
+    }
+    modules
+}
+
+/// Document the native functions set directly in the scope (excluding its parents),
+/// in alphabetical order
+pub fn native_docs(name: &str, scope: &Scope) -> Module {
+    let mut items: Vec<Item> = scope
+        .locals()
+        .into_iter()
+        .filter_map(|(ident, value)| match value {
+            Value::Function(Function::Native(native)) => Some(Item {
+                signature: native_signature(&ident.0, &native),
+                doc: native.doc.map(Into::into),
+                name: ident.0,
+            }),
+            _ => None,
+        })
+        .collect();
+    items.sort_by_key(|item| item.name.to_lowercase());
+    Module {
+        name: name.into(),
+        items,
+    }
+}
+
+/// Document the library's top-level function definitions and assignments, in their source order
+pub fn library_docs(name: &str, library: &ast::Library) -> Module {
+    let items = library
+        .stmts
+        .iter()
+        .filter_map(|stmt| {
+            let (name, signature) = match &stmt.kind {
+                StmtKind::FnDef(def) => {
+                    let signature = format!("fn {}({})", def.ident, def.signature);
+                    (def.ident.0.clone(), signature)
+                }
+                StmtKind::Assign(ast::Assign(assignments)) => {
+                    let names: Vec<String> = assignments
+                        .iter()
+                        .flat_map(|assignment| pattern_names(&assignment.lhs))
+                        .collect();
+                    // Type annotations are only allowed for variables (see LetAssignment)
+                    let vars: Vec<String> = assignments
+                        .iter()
+                        .map(|assignment| match (&assignment.lhs, assignment.ty) {
+                            (Pattern::Ident(ident), Some(ty)) => format!("{}: {}", ident, ty),
+                            (lhs, _) => pattern_names(lhs).join(", "),
+                        })
+                        .filter(|vars| !vars.is_empty())
+                        .collect();
+                    (names.first()?.clone(), vars.join(", "))
+                }
+                StmtKind::Import(_) | StmtKind::ExprStmt(_) | StmtKind::Test(_) => return None,
+            };
+            Some(Item {
+                name,
+                signature,
+                doc: stmt.doc.clone(),
+            })
+        })
+        .collect();
+    Module {
+        name: name.into(),
+        items,
+    }
+}
+
+fn pattern_names(pattern: &Pattern) -> Vec<String> {
+    match pattern {
+        Pattern::Ident(ident) => vec![ident.0.clone()],
+        Pattern::Array { elements, rest } => elements
+            .iter()
+            .flat_map(pattern_names)
+            .chain(rest.iter().map(|rest| rest.0.clone()))
+            .collect(),
+    }
+}
+
+/// Format the signature using the declared parameter names and types,
+/// like `tapBranch(node_a: Bytes, node_b: Bytes) -> Bytes`
+fn native_signature(name: &str, native: &NativeFunction) -> String {
+    let sig = match &native.sig {
+        Some(sig) => sig,
+        None => return format!("{}(...)", name),
+    };
+    let params = sig.params.iter().enumerate().map(|(i, ty)| {
+        let optional = iif!(i < sig.required, "", "?");
+        match native.params.get(i) {
+            Some(param) => format!("{}: {}{}", param, ty, optional),
+            None => format!("{}{}", ty, optional),
+        }
+    });
+    let rest = sig.rest.iter().map(|ty| format!("{}...", ty));
+    let params: Vec<String> = params.chain(rest).collect();
+    format!("{}({}) -> {}", name, params.join(", "), sig.ret)
+}
+
+/// Render the reference as a Markdown document
+pub fn to_markdown(modules: &[Module]) -> String {
+    let mut out = String::from("# Minsc reference\n");
+    for module in modules {
+        out.push_str(&format!("\n## {}\n", module.name));
+        for item in &module.items {
+            out.push_str(&format!("\n### `{}`\n", item.signature));
+            if let Some(doc) = &item.doc {
+                // Lines are kept apart using hard line breaks, with `<` escaped outside of code
+                // spans so that placeholders like `<data blob>` aren't taken for HTML tags
+                let paragraphs: Vec<String> = doc
+                    .split("\n\n")
+                    .map(|paragraph| {
+                        let lines: Vec<String> = paragraph
+                            .lines()
+                            .map(|line| {
+                                let text = |text: &str| text.replace('<', "\\<");
+                                render_line(line, text, |code| format!("`{}`", code))
+                            })
+                            .collect();
+                        lines.join("  \n")
+                    })
+                    .collect();
+                out.push_str(&format!("\n{}\n", paragraphs.join("\n\n")));
+            }
+        }
+    }
+    out
+}
+
+/// Render the reference as a standalone HTML page, with a navigation sidebar linking to the items
+pub fn to_html(modules: &[Module]) -> String {
+    let mut nav = String::new();
+    let mut main = String::new();
+    for module in modules {
+        let module_id = slug(&module.name);
+        nav.push_str(&format!(
+            "<li><a href=\"#{}\">{}</a><ul>\n",
+            module_id,
+            escape_html(&module.name)
+        ));
+        main.push_str(&format!(
+            "<section id=\"{}\">\n<h2>{}</h2>\n",
+            module_id,
+            escape_html(&module.name)
+        ));
+        for item in &module.items {
+            let item_id = format!("{}-{}", module_id, slug(&item.name));
+            nav.push_str(&format!(
+                "<li><a href=\"#{}\">{}</a></li>\n",
+                item_id,
+                escape_html(&item.name)
+            ));
+            main.push_str(&format!(
+                "<div class=\"item\" id=\"{}\">\n<h3><code>{}</code></h3>\n",
+                item_id,
+                escape_html(&item.signature)
+            ));
+            if let Some(doc) = &item.doc {
+                for paragraph in doc.split("\n\n") {
+                    let lines: Vec<String> = paragraph
+                        .lines()
+                        .map(|line| {
+                            let code = |code: &str| format!("<code>{}</code>", escape_html(code));
+                            render_line(line, escape_html, code)
+                        })
+                        .collect();
+                    main.push_str(&format!("<p>{}</p>\n", lines.join("<br>\n")));
+                }
+            }
+            main.push_str("</div>\n");
+        }
+        nav.push_str("</ul></li>\n");
+        main.push_str("</section>\n");
+    }
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Minsc reference</title>
+<style>{}</style>
+</head>
+<body>
+<nav>
+<ul>
+{}</ul>
+</nav>
+<main>
+<h1>Minsc reference</h1>
+{}</main>
+</body>
+</html>
+"#,
+        HTML_STYLE, nav, main
+    )
+}
+
+const HTML_STYLE: &str = r#"
+body { display: flex; margin: 0; font-family: sans-serif; }
+nav { width: 280px; height: 100vh; overflow-y: auto; position: sticky; top: 0; font-size: 14px; }
+nav li { white-space: nowrap; overflow: hidden; text-overflow: ellipsis; }
+main { flex: 1; max-width: 900px; padding: 0 2em; border-left: 1px solid #ddd; }
+.item { border-top: 1px solid #eee; }
+code { background: #f5f5f5; padding: 1px 3px; }
+"#;
+
+/// Render a line of documentation text, with the backtick-delimited code spans rendered separately.
+/// Lines with unbalanced backticks are rendered as plain text.
+fn render_line(line: &str, text: impl Fn(&str) -> String, code: impl Fn(&str) -> String) -> String {
+    if line.matches('`').count() % 2 == 1 {
+        return text(line);
+    }
+    line.split('`')
+        .enumerate()
+        .map(|(i, part)| iif!(i % 2 == 0, text(part), code(part)))
+        .collect()
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Turn a name into an HTML element id
+fn slug(name: &str) -> String {
+    name.chars()
+        .map(|c| iif!(c.is_ascii_alphanumeric() || c == '_', c, '-'))
+        .collect()
+}
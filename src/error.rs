@@ -1,12 +1,16 @@
 use lalrpop_util::ParseError;
+use std::collections::BTreeMap;
 use std::fmt;
 
 use miniscript::bitcoin::{self, hashes, util as bu};
 use miniscript::descriptor;
 use miniscript::policy::compiler::CompilerError;
 
-use crate::ast::{Ident, InfixOp};
+use crate::ast::{Ident, InfixOp, SourceId, Span};
+use crate::import::Importer;
 use crate::runtime::Value;
+use crate::stdlib;
+use crate::types::Type;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -36,9 +40,21 @@ pub enum Error {
     #[error("Expected a boolean, not {0:?}")]
     NotBool(Value),
 
+    #[error("Expected a string, not {0:?}")]
+    NotString(Value),
+
+    #[error("Expected a map, not {0:?}")]
+    NotMap(Value),
+
+    #[error("Format string expected {0} arguments, not {1}")]
+    FormatArgsMismatch(usize, usize),
+
     #[error("Expected a pubkey, not {0:?}")]
     NotPubKey(Value),
 
+    #[error("Expected a private key, not {0:?}")]
+    NotSecKey(Value),
+
     #[error("Expected hash bytes, not {0:?}")]
     NotHashLike(Value),
 
@@ -60,14 +76,50 @@ pub enum Error {
     #[error("Expected a value coercible into Script, not {0:?}")]
     NotScriptLike(Value),
 
+    #[error("Expected {0}, not {1}")]
+    TypeMismatch(Type, Type),
+
+    #[error("Unsupported operand types {0} and {1}")]
+    InvalidOperands(Type, Type),
+
+    #[error("Invalid type: {0}")]
+    InvalidType(String),
+
     #[error("Invalid script fragment {0:?}")]
     InvalidScriptFrag(Value),
 
     #[error("Array index out of range")]
     ArrayIndexOutOfRange,
 
-    #[error("Function {0} expected {1} arguments, not {2}")]
-    ArgumentMismatch(Ident, usize, usize),
+    #[error("Slice [{}] out of range for length {2}", describe_range(*.0, *.1))]
+    SliceOutOfRange(Option<i64>, Option<i64>, usize),
+
+    #[error("Map key {0:?} not found")]
+    MapKeyNotFound(String),
+
+    #[error("Map key {0:?} is defined more than once")]
+    MapKeyExists(String),
+
+    #[error("Function {0} expected {} arguments, not {3}", describe_arity(*.1, *.2))]
+    ArgumentMismatch(Ident, usize, Option<usize>, usize),
+
+    #[error("Unknown named argument {0}")]
+    UnknownArgument(Ident),
+
+    #[error("Argument {0} was provided more than once")]
+    DuplicateArgument(Ident),
+
+    #[error("Missing the required argument {0}")]
+    MissingArgument(Ident),
+
+    #[error("Named arguments are not supported by this function")]
+    NamedArgsUnsupported,
+
+    #[error("Array destructuring expected {}{} elements, not {2}", iif!(*.1, "at least ", ""), .0)]
+    PatternMismatch(usize, bool, usize),
+
+    #[error("No match arm matched {0}")]
+    NoMatchingArm(Value),
 
     #[error("Invalid datetime string: {0}")]
     InvalidDateTime(chrono::ParseError),
@@ -99,21 +151,69 @@ pub enum Error {
     #[error("Standalone keys cannot be derived")]
     NonDeriveableSingle,
 
+    #[error("Hardened derivation requires a private key and cannot be used with a public key")]
+    HardenedDerivationOnPubKey,
+
+    #[error("Mainnet private keys are rejected, use secKey(\"...\", _$$_RECKLESSLY_RISK_MY_BITCOINS_$$_) if you really mean it")]
+    MainnetSecKey,
+
+    #[error("Private key is for {0}, not {1}")]
+    SecKeyNetworkMismatch(bitcoin::Network, bitcoin::Network),
+
     #[error("sh() can only wrap wsh() or wpkh()")]
     InvalidShUse,
 
     #[error("Script cannot be represented as an address: {0}")]
     NotAddressable(bitcoin::Script),
 
+    #[error("Assertion failed{}", .0.as_ref().map_or_else(String::new, |msg| format!(": {}", msg)))]
+    AssertionFailed(Option<String>),
+
+    #[error("{0}")]
+    UserError(String),
+
+    #[error("Thrown value: {0}")]
+    Thrown(Value),
+
     #[error("Number operation overflowed")]
     Overflow,
 
+    #[error("Division by zero")]
+    DivideByZero,
+
+    #[error("Maximum call depth of {0} exceeded")]
+    DepthLimitExceeded(usize),
+
+    #[error("Maximum evaluation steps of {0} exceeded")]
+    StepLimitExceeded(usize),
+
+    #[error("Maximum allocation size of {0} exceeded")]
+    AllocLimitExceeded(usize),
+
     #[error("in {0}(): {1}")]
     CallError(Ident, Box<Error>),
 
     #[error("in {0:?}: {1}")]
     OpError(InfixOp, Box<Error>),
 
+    #[error("{1}")]
+    Located(Span, Box<Error>),
+
+    #[error("in import \"{0}\": {1}")]
+    ImportError(String, Box<Error>),
+
+    #[error("Imported file not found: {0}")]
+    ImportNotFound(String),
+
+    #[error("Import cycle detected: {}", .0.join(" -> "))]
+    ImportCycle(Vec<String>),
+
+    #[error("Import statements are not available in this environment")]
+    ImportUnavailable,
+
+    #[error("Output is not available in this environment")]
+    OutputUnavailable,
+
     #[error("Descriptor key parse error: {0}")]
     DescriptorKeyParse(descriptor::DescriptorKeyParseError),
 
@@ -167,6 +267,345 @@ pub enum Error {
 
     #[error("UTF-8 error: {0}")]
     Utf8Error(std::string::FromUtf8Error),
+
+    #[error("Memory allocation failed: {0}")]
+    TryReserve(std::collections::TryReserveError),
+}
+
+// Describe the number of arguments accepted by a function, i.e. "2", "1 to 3" or "at least 1"
+fn describe_arity(min: usize, max: Option<usize>) -> String {
+    match max {
+        Some(max) if max == min => min.to_string(),
+        Some(max) => format!("{} to {}", min, max),
+        None => format!("at least {}", min),
+    }
+}
+
+// Describe the bounds of a slice as they were given, i.e. "1..3", "..-1" or "2.."
+fn describe_range(start: Option<i64>, end: Option<i64>) -> String {
+    let bound = |bound: Option<i64>| bound.map_or_else(String::new, |n| n.to_string());
+    format!("{}..{}", bound(start), bound(end))
+}
+
+impl Error {
+    /// Convert a parser error into an Error located at the offending token (when known)
+    pub fn from_parse_error<T, E>(err: ParseError<usize, T, E>, source: SourceId) -> Self
+    where
+        T: fmt::Display,
+        E: fmt::Display,
+    {
+        let span = match &err {
+            ParseError::InvalidToken { location }
+            | ParseError::UnrecognizedEOF { location, .. } => {
+                Some(source.span(*location, *location))
+            }
+            ParseError::UnrecognizedToken {
+                token: (l, _, r), ..
+            }
+            | ParseError::ExtraToken { token: (l, _, r) } => Some(source.span(*l, *r)),
+            ParseError::User { .. } => None,
+        };
+        let err = Error::ParseError(err.to_string());
+        match span {
+            Some(span) => Error::Located(span, err.into()),
+            None => err,
+        }
+    }
+
+    /// The source location the error originated from, if known
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Error::Located(span, _) => Some(*span),
+            _ => None,
+        }
+    }
+
+    /// Attach the source location to the error, unless it already has one
+    pub fn with_span(self, span: Span) -> Self {
+        match self {
+            located @ Error::Located(..) => located,
+            err => Error::Located(span, err.into()),
+        }
+    }
+
+    /// The name of the error variant, like `MiniscriptCompilerError` or `UserError`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::AssignedVariableExists(..) => "AssignedVariableExists",
+            Error::NoReturnValue => "NoReturnValue",
+            Error::FnNotFound(..) => "FnNotFound",
+            Error::VarNotFound(..) => "VarNotFound",
+            Error::NotFn(..) => "NotFn",
+            Error::NotArray(..) => "NotArray",
+            Error::NotNumber(..) => "NotNumber",
+            Error::NotBool(..) => "NotBool",
+            Error::NotString(..) => "NotString",
+            Error::NotMap(..) => "NotMap",
+            Error::FormatArgsMismatch(..) => "FormatArgsMismatch",
+            Error::NotPubKey(..) => "NotPubKey",
+            Error::NotSecKey(..) => "NotSecKey",
+            Error::NotHashLike(..) => "NotHashLike",
+            Error::NotNetwork(..) => "NotNetwork",
+            Error::NotBytes(..) => "NotBytes",
+            Error::NotPolicyLike(..) => "NotPolicyLike",
+            Error::NotMiniscriptLike(..) => "NotMiniscriptLike",
+            Error::NotDescriptorLike(..) => "NotDescriptorLike",
+            Error::NotScriptLike(..) => "NotScriptLike",
+            Error::TypeMismatch(..) => "TypeMismatch",
+            Error::InvalidOperands(..) => "InvalidOperands",
+            Error::InvalidType(..) => "InvalidType",
+            Error::InvalidScriptFrag(..) => "InvalidScriptFrag",
+            Error::ArrayIndexOutOfRange => "ArrayIndexOutOfRange",
+            Error::SliceOutOfRange(..) => "SliceOutOfRange",
+            Error::MapKeyNotFound(..) => "MapKeyNotFound",
+            Error::MapKeyExists(..) => "MapKeyExists",
+            Error::ArgumentMismatch(..) => "ArgumentMismatch",
+            Error::UnknownArgument(..) => "UnknownArgument",
+            Error::DuplicateArgument(..) => "DuplicateArgument",
+            Error::MissingArgument(..) => "MissingArgument",
+            Error::NamedArgsUnsupported => "NamedArgsUnsupported",
+            Error::PatternMismatch(..) => "PatternMismatch",
+            Error::NoMatchingArm(..) => "NoMatchingArm",
+            Error::InvalidDateTime(..) => "InvalidDateTime",
+            Error::InvalidDateTimeOutOfRange => "InvalidDateTimeOutOfRange",
+            Error::InvalidDurationHeightwise => "InvalidDurationHeightwise",
+            Error::InvalidDurationBlocksOutOfRange => "InvalidDurationBlocksOutOfRange",
+            Error::InvalidDurationTimeOutOfRange => "InvalidDurationTimeOutOfRange",
+            Error::ParseError(..) => "ParseError",
+            Error::InvalidArguments => "InvalidArguments",
+            Error::NonDeriveableNoWildcard => "NonDeriveableNoWildcard",
+            Error::NonDeriveableType => "NonDeriveableType",
+            Error::NonDeriveableSingle => "NonDeriveableSingle",
+            Error::HardenedDerivationOnPubKey => "HardenedDerivationOnPubKey",
+            Error::MainnetSecKey => "MainnetSecKey",
+            Error::SecKeyNetworkMismatch(..) => "SecKeyNetworkMismatch",
+            Error::InvalidShUse => "InvalidShUse",
+            Error::NotAddressable(..) => "NotAddressable",
+            Error::AssertionFailed(..) => "AssertionFailed",
+            Error::UserError(..) => "UserError",
+            Error::Thrown(..) => "Thrown",
+            Error::Overflow => "Overflow",
+            Error::DivideByZero => "DivideByZero",
+            Error::DepthLimitExceeded(..) => "DepthLimitExceeded",
+            Error::StepLimitExceeded(..) => "StepLimitExceeded",
+            Error::AllocLimitExceeded(..) => "AllocLimitExceeded",
+            Error::CallError(..) => "CallError",
+            Error::OpError(..) => "OpError",
+            Error::Located(..) => "Located",
+            Error::ImportError(..) => "ImportError",
+            Error::ImportNotFound(..) => "ImportNotFound",
+            Error::ImportCycle(..) => "ImportCycle",
+            Error::ImportUnavailable => "ImportUnavailable",
+            Error::OutputUnavailable => "OutputUnavailable",
+            Error::DescriptorKeyParse(..) => "DescriptorKeyParse",
+            Error::DescriptorConversion(..) => "DescriptorConversion",
+            Error::MiniscriptError(..) => "MiniscriptError",
+            Error::MiniscriptCompilerError(..) => "MiniscriptCompilerError",
+            Error::TaprootError(..) => "TaprootError",
+            Error::TaprootBuilderError(..) => "TaprootBuilderError",
+            Error::Secp256k1Error(..) => "Secp256k1Error",
+            Error::HashError(..) => "HashError",
+            Error::HexError(..) => "HexError",
+            Error::Io(..) => "Io",
+            Error::BitcoinKey(..) => "BitcoinKey",
+            Error::Bip32(..) => "Bip32",
+            Error::ParseAmountError(..) => "ParseAmountError",
+            Error::TryFromInt(..) => "TryFromInt",
+            Error::InvalidPubKeyLen(..) => "InvalidPubKeyLen",
+            Error::InvalidMerkleLen(..) => "InvalidMerkleLen",
+            Error::TaprootInvalidNestedTree => "TaprootInvalidNestedTree",
+            Error::Utf8Error(..) => "Utf8Error",
+            Error::TryReserve(..) => "TryReserve",
+        }
+    }
+
+    /// Whether the error can be caught by `try`/`catch`. Exceeding the evaluation Limits
+    /// cannot be recovered from, so that evaluation is guaranteed to stop once they're reached.
+    /// Neither can the output being unavailable, which the folder relies on to defer it to runtime.
+    pub fn is_catchable(&self) -> bool {
+        !matches!(
+            self.root(),
+            Error::DepthLimitExceeded(_)
+                | Error::StepLimitExceeded(_)
+                | Error::AllocLimitExceeded(_)
+                | Error::OutputUnavailable
+        )
+    }
+
+    /// The underlying error, without the Located/CallError/OpError/ImportError layers wrapping it
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Located(_, inner)
+            | Error::CallError(_, inner)
+            | Error::OpError(_, inner)
+            | Error::ImportError(_, inner) => inner.root(),
+            err => err,
+        }
+    }
+
+    /// Convert the error into the value bound by `catch`: a map with the error `kind`, its
+    /// `message` and the names of the functions it was raised through (`calls`, outermost first).
+    /// Errors raised by `throw()` also have the thrown `value`.
+    pub fn into_value(self) -> Value {
+        let mut calls = vec![];
+        let mut err = self;
+        loop {
+            err = match err {
+                Error::Located(_, inner)
+                | Error::OpError(_, inner)
+                | Error::ImportError(_, inner) => *inner,
+                Error::CallError(ident, inner) => {
+                    calls.push(Value::String(ident.0));
+                    *inner
+                }
+                _ => break,
+            };
+        }
+        let mut map = BTreeMap::new();
+        map.insert("kind".into(), err.kind().to_string().into());
+        map.insert("message".into(), err.to_string().into());
+        map.insert("calls".into(), calls.into());
+        if let Error::Thrown(value) = err {
+            map.insert("value".into(), value);
+        }
+        Value::Map(map)
+    }
+
+    /// Render the error as a `file:line:col` diagnostic, with a caret-underlined
+    /// source snippet and the chain of call sites that led to it.
+    ///
+    /// `name` and `code` are used for the main program, built-in library sources are resolved automatically.
+    pub fn render(&self, name: &str, code: &str) -> String {
+        self.render_with(|source| {
+            if source == SourceId::MAIN {
+                Some((name, code))
+            } else {
+                stdlib::builtin_source(source)
+            }
+        })
+    }
+
+    /// Like render(), but also resolving locations within the files loaded by the importer
+    pub fn render_with_importer(&self, name: &str, code: &str, importer: &Importer) -> String {
+        let imports = importer.sources();
+        self.render_with(|source| {
+            if source == SourceId::MAIN {
+                Some((name, code))
+            } else if let Some((_, path, code)) = imports.iter().find(|(id, ..)| *id == source) {
+                Some((path, code))
+            } else {
+                stdlib::builtin_source(source)
+            }
+        })
+    }
+
+    fn render_with<'a, F>(&self, lookup: F) -> String
+    where
+        F: Fn(SourceId) -> Option<(&'a str, &'a str)>,
+    {
+        // Unwrap the Located/CallError/ImportError layers, collecting the call and import sites along the way
+        let mut location = None;
+        let mut frames = vec![];
+        let mut err = self;
+        loop {
+            match err {
+                Error::Located(span, inner) => {
+                    location = Some(*span);
+                    err = inner;
+                }
+                Error::CallError(ident, inner) => {
+                    frames.push((format!("in {}()", ident), "called", location.take()));
+                    err = inner;
+                }
+                Error::ImportError(path, inner) => {
+                    frames.push((
+                        format!("in import \"{}\"", path),
+                        "imported",
+                        location.take(),
+                    ));
+                    err = inner;
+                }
+                _ => break,
+            }
+        }
+        // The innermost location is the error origin. If the error was raised by a native
+        // function, this is the location of the last call site.
+        let location = location.or_else(|| frames.last().and_then(|(_, _, span)| *span));
+
+        let mut out = format!("error: {}", err);
+        if let Some(loc) = location.and_then(|span| SourceLocation::resolve(span, &lookup)) {
+            out.push_str(&loc.snippet());
+        }
+        for (frame, verb, span) in frames.iter().rev() {
+            match span.and_then(|span| SourceLocation::resolve(span, &lookup)) {
+                Some(loc) => out.push_str(&format!("\n  = {}, {} at {}", frame, verb, loc)),
+                None => out.push_str(&format!("\n  = {}", frame)),
+            }
+        }
+        out
+    }
+}
+
+/// A span resolved into a human readable line/column location
+pub(crate) struct SourceLocation<'a> {
+    name: &'a str,
+    line: usize,
+    col: usize,
+    line_text: &'a str,
+    // The number of characters to underline
+    len: usize,
+}
+
+impl<'a> SourceLocation<'a> {
+    pub(crate) fn resolve<F>(span: Span, lookup: &F) -> Option<Self>
+    where
+        F: Fn(SourceId) -> Option<(&'a str, &'a str)>,
+    {
+        let (name, code) = lookup(span.source)?;
+        // Spans that don't fit within the source are silently ignored
+        let start = code.get(..span.start)?;
+        let line_start = start.rfind('\n').map_or(0, |pos| pos + 1);
+        let line_end = code[span.start..]
+            .find('\n')
+            .map_or(code.len(), |pos| span.start + pos);
+        let line_text = code[line_start..line_end].trim_end_matches('\r');
+        let underline_end = span.end.min(line_start + line_text.len()).max(span.start);
+        Some(SourceLocation {
+            name,
+            line: start.matches('\n').count() + 1,
+            col: code[line_start..span.start].chars().count() + 1,
+            line_text,
+            len: code
+                .get(span.start..underline_end)
+                .map_or(0, |s| s.chars().count())
+                .max(1),
+        })
+    }
+
+    pub(crate) fn snippet(&self) -> String {
+        let gutter = " ".repeat(self.line.to_string().len());
+        let indent: String = self
+            .line_text
+            .chars()
+            .take(self.col - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        format!(
+            "\n{g}--> {loc}\n{g} |\n{line} | {text}\n{g} | {indent}{carets}",
+            g = gutter,
+            loc = self,
+            line = self.line,
+            text = self.line_text,
+            indent = indent,
+            carets = "^".repeat(self.len),
+        )
+    }
+}
+
+impl fmt::Display for SourceLocation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.name, self.line, self.col)
+    }
 }
 
 impl<L, T, E> From<ParseError<L, T, E>> for Error
@@ -200,4 +639,5 @@ impl_from_variant!(
 );
 
 impl_from_variant!(std::string::FromUtf8Error, Error, Utf8Error);
+impl_from_variant!(std::collections::TryReserveError, Error, TryReserve);
 impl_from_variant!(bu::amount::ParseAmountError, Error, ParseAmountError);
@@ -0,0 +1,647 @@
+//! Constant folding and partial evaluation.
+//!
+//! Subexpressions that don't depend on any unknown variables are evaluated ahead of time and replaced
+//! with their values, leaving the parts that do (like the ones depending on a contract's parameters)
+//! to be evaluated later. Folding a program once and evaluating it for many parameter instances avoids
+//! repeating the same work, like compiling the parameter-independent sub-policies.
+//!
+//! Values that cannot be written back as source code (like descriptors, addresses and functions) are kept
+//! as the expressions producing them, so that the folded program can be printed and parsed again.
+//!
+//! Expressions that fail to evaluate are kept as-is, so that the error is raised at runtime as usual.
+//! This includes expressions that write output (using `print()` and friends), which is deferred to runtime.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use miniscript::bitcoin::Network;
+use miniscript::descriptor::{DescriptorPublicKey, SinglePubKey};
+
+use crate::ast::{self, Expr, ExprKind, Ident, Pattern, Stmt, StmtKind};
+use crate::function::Function;
+use crate::{Budget, Error, Evaluate, Limits, Output, Result, Scope, Value};
+
+/// Fold the constant subexpressions of the program. Built-ins are resolved from the given `scope`,
+/// while any other undefined variables are left open, to be provided when the program is evaluated.
+pub fn fold(expr: &Expr, scope: &Scope) -> Expr {
+    Folder::new(scope).expr(expr).0
+}
+
+/// Fold the library definitions, pre-evaluating the constant ones into values and folding
+/// the constant subexpressions within function bodies, leaving their parameters open.
+pub fn fold_lib(lib: &ast::Library, scope: &Scope) -> ast::Library {
+    let mut folder = Folder::new(scope);
+    folder.push_frame();
+    let stmts = folder.stmts(&lib.stmts);
+    ast::Library { stmts }
+}
+
+struct Folder {
+    base: Scope,
+    frames: Vec<Frame>,
+    /// The frame index where each of the (nested) functions being folded begins,
+    /// and whether it refers to variables that are unknown outside of it
+    functions: Vec<(usize, bool)>,
+}
+
+struct Frame {
+    /// The variables defined in this frame, with their value if it is known
+    vars: HashMap<Ident, Option<Value>>,
+    /// Set following an import statement, which may bring any definitions into the scope
+    opaque: bool,
+    /// The runtime scope holding the known values, used to evaluate expressions within this frame
+    scope: Scope,
+}
+
+impl Folder {
+    fn new(scope: &Scope) -> Self {
+        Folder {
+            base: scope.clone(),
+            frames: vec![],
+            functions: vec![],
+        }
+    }
+
+    fn scope(&self) -> &Scope {
+        self.frames.last().map_or(&self.base, |frame| &frame.scope)
+    }
+
+    fn push_frame(&mut self) {
+        let scope = self.scope().child();
+        self.frames.push(Frame {
+            vars: HashMap::new(),
+            opaque: false,
+            scope,
+        });
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("within a frame")
+    }
+
+    /// Get the variable's value if it is known, marking the functions it is unknown to
+    fn lookup(&mut self, ident: &Ident) -> Option<Value> {
+        let mut unknown_at = None;
+        for (i, frame) in self.frames.iter().enumerate().rev() {
+            match frame.vars.get(ident) {
+                Some(Some(value)) => return Some(value.clone()),
+                Some(None) => unknown_at = Some(i),
+                None if frame.opaque => unknown_at = Some(i),
+                None => continue,
+            }
+            break;
+        }
+        let value = match unknown_at {
+            Some(_) => None,
+            None => self.base.get(ident),
+        };
+        if value.is_none() {
+            // Variables that aren't defined anywhere are unknown to all functions
+            for (base, unknown) in &mut self.functions {
+                if !matches!(unknown_at, Some(i) if i >= *base) {
+                    *unknown = true;
+                }
+            }
+        }
+        value
+    }
+
+    /// Evaluate the expression, whose subexpressions are known to be constant.
+    /// Values that cannot be represented as an expression are left to be evaluated again at runtime.
+    fn eval(&self, kind: ExprKind, span: ast::Span) -> (Expr, bool) {
+        let expr = Expr::new(kind, span);
+        match expr.eval(&self.sandbox()) {
+            Ok(value) => (value_expr(value, span).unwrap_or(expr), true),
+            Err(_) => (expr, false),
+        }
+    }
+
+    /// Get the value of a constant expression, evaluating it again if it wasn't folded into a literal
+    fn const_value(&self, expr: &Expr) -> Option<Value> {
+        literal_value(expr).or_else(|| expr.eval(&self.sandbox()).ok())
+    }
+
+    fn sandbox(&self) -> Scope {
+        // Bound the work done ahead of time, i.e. for non-terminating recursion
+        let budget = Rc::new(Budget::new(Limits::default()));
+        self.scope()
+            .child_with_budget(Some(budget))
+            .child_with_output(Rc::new(NoOutput))
+    }
+
+    /// Fold the expression, returning whether it is constant
+    fn expr(&mut self, expr: &Expr) -> (Expr, bool) {
+        let span = expr.span;
+        let (kind, is_const) = match &expr.kind {
+            ExprKind::Ident(ident) => match self.lookup(ident) {
+                // Values that cannot be represented as an expression (like functions) are kept referenced by name
+                Some(value) => {
+                    return (
+                        value_expr(value, span).unwrap_or_else(|| expr.clone()),
+                        true,
+                    )
+                }
+                None => (ExprKind::Ident(ident.clone()), false),
+            },
+            ExprKind::Call(call) => {
+                let (args, args_const) = self.exprs(&call.args);
+                let mut named_args = Vec::with_capacity(call.named_args.len());
+                let mut named_const = true;
+                for (name, arg) in &call.named_args {
+                    let (arg, is_const) = self.expr(arg);
+                    named_args.push((name.clone(), arg));
+                    named_const &= is_const;
+                }
+                let fn_known = self.lookup(&call.ident).is_some();
+                let call = ast::Call {
+                    ident: call.ident.clone(),
+                    args,
+                    named_args,
+                    piped: call.piped,
+                };
+                (call.into(), fn_known && args_const && named_const)
+            }
+            ExprKind::Or(ast::Or(operands)) => {
+                let (operands, is_const) = self.exprs(operands);
+                (ast::Or(operands).into(), is_const)
+            }
+            ExprKind::And(ast::And(operands)) => {
+                let (operands, is_const) = self.exprs(operands);
+                (ast::And(operands).into(), is_const)
+            }
+            ExprKind::Thresh(x) => {
+                let (thresh, thresh_const) = self.expr(&x.thresh);
+                let (policies, policies_const) = self.expr(&x.policies);
+                let thresh = ast::Thresh {
+                    thresh: thresh.into(),
+                    policies: policies.into(),
+                };
+                (thresh.into(), thresh_const && policies_const)
+            }
+            ExprKind::Block(block) => return self.block(block, span),
+            ExprKind::Array(ast::Array(elements)) => {
+                let (elements, is_const) = self.exprs(elements);
+                (ast::Array(elements).into(), is_const)
+            }
+            ExprKind::Map(ast::Map(entries)) => {
+                let mut folded = Vec::with_capacity(entries.len());
+                let mut all_const = true;
+                for (key, value) in entries {
+                    let (value, is_const) = self.expr(value);
+                    folded.push((key.clone(), value));
+                    all_const &= is_const;
+                }
+                (ast::Map(folded).into(), all_const)
+            }
+            ExprKind::ArrayAccess(x) => {
+                let (array, array_const) = self.expr(&x.array);
+                let (index, index_const) = self.expr(&x.index);
+                let access = ast::ArrayAccess {
+                    array: array.into(),
+                    index: index.into(),
+                };
+                (access.into(), array_const && index_const)
+            }
+            ExprKind::Slice(x) => {
+                let (value, mut all_const) = self.expr(&x.value);
+                let mut bound = |bound: &Option<Box<Expr>>| {
+                    bound.as_ref().map(|bound| {
+                        let (bound, is_const) = self.expr(bound);
+                        all_const &= is_const;
+                        bound.into()
+                    })
+                };
+                let slice = ast::Slice {
+                    value: value.into(),
+                    start: bound(&x.start),
+                    end: bound(&x.end),
+                };
+                (slice.into(), all_const)
+            }
+            ExprKind::Range(x) => {
+                let (start, start_const) = self.expr(&x.start);
+                let (end, end_const) = self.expr(&x.end);
+                let range = ast::Range {
+                    start: start.into(),
+                    end: end.into(),
+                };
+                (range.into(), start_const && end_const)
+            }
+            ExprKind::ChildDerive(x) => {
+                let (parent, mut all_const) = self.expr(&x.parent);
+                let mut path = Vec::with_capacity(x.path.len());
+                for step in &x.path {
+                    let (child, is_const) = self.expr(&step.child);
+                    path.push(ast::DeriveStep {
+                        child,
+                        hardened: step.hardened,
+                    });
+                    all_const &= is_const;
+                }
+                let derive = ast::ChildDerive {
+                    parent: parent.into(),
+                    path,
+                    wildcard: x.wildcard,
+                };
+                (derive.into(), all_const)
+            }
+            ExprKind::ScriptFrag(x) => {
+                let (fragments, is_const) = self.exprs(&x.fragments);
+                (ast::ScriptFrag { fragments }.into(), is_const)
+            }
+            ExprKind::FnExpr(x) => {
+                let (signature, body, is_const) = self.function(&x.signature, &x.body);
+                let fn_expr = ast::FnExpr {
+                    signature,
+                    body: body.into(),
+                };
+                // Kept as a function expression rather than evaluated, like functions referenced by name
+                return (Expr::new(fn_expr, span), is_const);
+            }
+            ExprKind::Infix(x) => {
+                let (lhs, lhs_const) = self.expr(&x.lhs);
+                let (rhs, rhs_const) = self.expr(&x.rhs);
+                let infix = ast::Infix {
+                    op: x.op,
+                    lhs: lhs.into(),
+                    rhs: rhs.into(),
+                };
+                (infix.into(), lhs_const && rhs_const)
+            }
+            ExprKind::Not(ast::Not(x)) => {
+                let (x, is_const) = self.expr(x);
+                (ast::Not(x.into()).into(), is_const)
+            }
+            ExprKind::Neg(ast::Neg(x)) => {
+                let (x, is_const) = self.expr(x);
+                (ast::Neg(x.into()).into(), is_const)
+            }
+            ExprKind::IfExpr(x) => {
+                let (condition, condition_const) = self.expr(&x.condition);
+                // Only the branch matching a known condition is kept
+                if let (true, ExprKind::Value(value)) = (condition_const, &condition.kind) {
+                    if let Value::Bool(condition) = **value {
+                        return self.expr(iif!(condition, &x.then_val, &x.else_val));
+                    }
+                }
+                let if_expr = ast::IfExpr {
+                    condition: condition.into(),
+                    then_val: self.expr(&x.then_val).0.into(),
+                    else_val: self.expr(&x.else_val).0.into(),
+                };
+                (if_expr.into(), false)
+            }
+            ExprKind::Match(x) => {
+                let (value, _) = self.expr(&x.value);
+                // The arms are folded with their pattern bindings unknown, and the match itself
+                // is left to be evaluated at runtime
+                let arms = x
+                    .arms
+                    .iter()
+                    .map(|arm| {
+                        self.push_frame();
+                        for ident in arm.pattern.idents() {
+                            self.declare(ident);
+                        }
+                        let guard = arm.guard.as_ref().map(|guard| self.expr(guard).0);
+                        let body = self.expr(&arm.body).0;
+                        self.frames.pop();
+                        ast::MatchArm {
+                            pattern: arm.pattern.clone(),
+                            guard,
+                            body,
+                            span: arm.span,
+                        }
+                    })
+                    .collect();
+                let match_expr = ast::Match {
+                    value: value.into(),
+                    arms,
+                };
+                (match_expr.into(), false)
+            }
+            ExprKind::Try(x) => {
+                // Known bodies were evaluated successfully, so the handler can be dropped
+                let (body, body_const) = self.expr(&x.body);
+                if body_const {
+                    return (body, true);
+                }
+                self.push_frame();
+                if let Some(error) = &x.error {
+                    self.declare(error);
+                }
+                let handler = self.expr(&x.handler).0;
+                self.frames.pop();
+                let try_expr = ast::Try {
+                    body: body.into(),
+                    error: x.error.clone(),
+                    handler: handler.into(),
+                };
+                (try_expr.into(), false)
+            }
+            ExprKind::Coalesce(x) => {
+                let (lhs, lhs_const) = self.expr(&x.lhs);
+                if lhs_const {
+                    return match &lhs.kind {
+                        ExprKind::Value(value) if matches!(**value, Value::Null) => {
+                            self.expr(&x.rhs)
+                        }
+                        _ => (lhs, true),
+                    };
+                }
+                let coalesce = ast::Coalesce {
+                    lhs: lhs.into(),
+                    rhs: self.expr(&x.rhs).0.into(),
+                };
+                (coalesce.into(), false)
+            }
+            ExprKind::InterpolatedStr(ast::InterpolatedStr(parts)) => {
+                let mut folded = Vec::with_capacity(parts.len());
+                let mut all_const = true;
+                for part in parts {
+                    folded.push(match part {
+                        ast::StrPart::Str(s) => ast::StrPart::Str(s.clone()),
+                        ast::StrPart::Expr(expr) => {
+                            let (expr, is_const) = self.expr(expr);
+                            all_const &= is_const;
+                            ast::StrPart::Expr(expr)
+                        }
+                    });
+                }
+                (ast::InterpolatedStr(folded).into(), all_const)
+            }
+            // Durations depend on the BLOCK_INTERVAL, which may be redefined
+            ExprKind::Duration(x) => {
+                let is_const = self.lookup(&"BLOCK_INTERVAL".into()).is_some();
+                (x.clone().into(), is_const)
+            }
+            ExprKind::Value(_) => return (expr.clone(), true),
+            kind @ ExprKind::PubKey(_)
+            | kind @ ExprKind::SecKey(_)
+            | kind @ ExprKind::Bytes(_)
+            | kind @ ExprKind::String(_)
+            | kind @ ExprKind::Number(_)
+            | kind @ ExprKind::DateTime(_)
+            | kind @ ExprKind::BtcAmount(_) => (kind.clone(), true),
+        };
+        if is_const {
+            self.eval(kind, span)
+        } else {
+            (Expr::new(kind, span), false)
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[Expr]) -> (Vec<Expr>, bool) {
+        let mut all_const = true;
+        let folded = exprs
+            .iter()
+            .map(|expr| {
+                let (expr, is_const) = self.expr(expr);
+                all_const &= is_const;
+                expr
+            })
+            .collect();
+        (folded, all_const)
+    }
+
+    fn block(&mut self, block: &ast::Block, span: ast::Span) -> (Expr, bool) {
+        // Blocks without statements are unwrapped into their return value
+        if let (true, Some(return_value)) = (block.stmts.is_empty(), &block.return_value) {
+            return self.expr(return_value);
+        }
+        self.push_frame();
+        let stmts = self.stmts(&block.stmts);
+        let return_value = block.return_value.as_ref().map(|expr| self.expr(expr));
+        let frame = self.frames.pop().expect("pushed above");
+
+        // Other than expression statements, statements have no side-effects. So once all the definitions
+        // are known, the block can be replaced with its return value. Unless it refers to the block's
+        // functions by name.
+        let defs_known = !frame.opaque && frame.vars.values().all(Option::is_some);
+        let has_effects = stmts
+            .iter()
+            .any(|stmt| matches!(stmt.kind, StmtKind::ExprStmt(_)));
+        match return_value {
+            Some((return_value, true)) if defs_known && !has_effects && is_value(&return_value) => {
+                (return_value, true)
+            }
+            return_value => {
+                let block = ast::Block {
+                    stmts,
+                    return_value: return_value.map(|(expr, _)| expr.into()),
+                };
+                (Expr::new(block, span), false)
+            }
+        }
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) -> Vec<Stmt> {
+        // Definitions are visible throughout the block (i.e. to functions defined before them) and shadow
+        // the outer ones, so they're declared upfront as unknown and get their value as they're folded
+        for stmt in stmts {
+            match &stmt.kind {
+                StmtKind::FnDef(def) => self.declare(&def.ident),
+                StmtKind::Assign(assign) => {
+                    for assignment in &assign.0 {
+                        for ident in pattern_idents(&assignment.lhs) {
+                            self.declare(ident);
+                        }
+                    }
+                }
+                StmtKind::Import(_) | StmtKind::ExprStmt(_) | StmtKind::Test(_) => (),
+            }
+        }
+        stmts
+            .iter()
+            .map(|stmt| Stmt {
+                kind: self.stmt(&stmt.kind),
+                span: stmt.span,
+                doc: stmt.doc.clone(),
+            })
+            .collect()
+    }
+
+    fn declare(&mut self, ident: &Ident) {
+        self.frame().vars.insert(ident.clone(), None);
+    }
+
+    fn stmt(&mut self, stmt: &StmtKind) -> StmtKind {
+        match stmt {
+            StmtKind::FnDef(def) => {
+                let (signature, body, is_const) = self.function(&def.signature, &def.body);
+                let def = ast::FnDef {
+                    ident: def.ident.clone(),
+                    signature,
+                    body,
+                };
+                if is_const {
+                    let frame = self.frame();
+                    // Stored in the scope it captures, which only keeps a weak reference to it (see Scope)
+                    let func: Value = Function::from_def(def.clone(), &frame.scope).into();
+                    if frame.scope.set(def.ident.clone(), func.clone()).is_ok() {
+                        frame.vars.insert(def.ident.clone(), Some(func));
+                    }
+                }
+                def.into()
+            }
+            StmtKind::Assign(assign) => {
+                let assignments = assign
+                    .0
+                    .iter()
+                    .map(|assignment| {
+                        let (rhs, is_const) = self.expr(&assignment.rhs);
+                        // Functions evaluated ahead of time would keep the folding scopes alive, so only
+                        // the ones defined with `fn` are known
+                        let value = iif!(is_const, self.const_value(&rhs), None)
+                            .filter(|value| !value.holds_user_fn());
+                        if let Some(value) = value {
+                            self.bind(&assignment.lhs, assignment.ty, value);
+                        }
+                        ast::Assignment {
+                            lhs: assignment.lhs.clone(),
+                            ty: assignment.ty,
+                            rhs,
+                        }
+                    })
+                    .collect();
+                ast::Assign(assignments).into()
+            }
+            StmtKind::Import(import) => {
+                self.frame().opaque = true;
+                import.clone().into()
+            }
+            // Kept even if it evaluates successfully, since it may fail under other parameters
+            StmtKind::ExprStmt(ast::ExprStmt(expr)) => ast::ExprStmt(self.expr(expr).0).into(),
+            StmtKind::Test(test) => test.clone().into(),
+        }
+    }
+
+    /// Bind the known value to the pattern, leaving its variables unknown if it doesn't match
+    fn bind(&mut self, pattern: &Pattern, ty: Option<crate::Type>, value: Value) {
+        if let Some(ty) = ty {
+            if ty.ensure(&value).is_err() {
+                return;
+            }
+        }
+        let frame = self.frame();
+        if pattern.bind(value, &mut frame.scope).is_ok() {
+            for ident in pattern_idents(pattern) {
+                let value = frame.scope.get(ident);
+                frame.vars.insert(ident.clone(), value);
+            }
+        }
+    }
+
+    /// Fold the function's parameter defaults and body,
+    /// returning whether it only refers to known variables from outside of it
+    fn function(
+        &mut self,
+        signature: &ast::Signature,
+        body: &Expr,
+    ) -> (ast::Signature, Expr, bool) {
+        self.push_frame();
+        self.functions.push((self.frames.len() - 1, false));
+
+        let mut params = Vec::with_capacity(signature.params.len());
+        for param in &signature.params {
+            let default = param.default.as_ref().map(|default| self.expr(default).0);
+            for ident in pattern_idents(&param.pattern) {
+                self.declare(ident);
+            }
+            params.push(ast::Param {
+                pattern: param.pattern.clone(),
+                ty: param.ty,
+                default,
+            });
+        }
+        if let Some(rest) = &signature.rest {
+            self.declare(rest);
+        }
+        let (body, _) = self.expr(body);
+
+        let (_, has_unknown) = self.functions.pop().expect("pushed above");
+        self.frames.pop();
+        let signature = ast::Signature {
+            params,
+            rest: signature.rest.clone(),
+        };
+        (signature, body, !has_unknown)
+    }
+}
+
+/// Rejects output, so that the expressions writing it fail to fold and are kept for runtime
+struct NoOutput;
+
+impl Output for NoOutput {
+    fn write(&self, _: &str) -> Result<()> {
+        Err(Error::OutputUnavailable)
+    }
+}
+
+/// Represent the value as an expression, using the literal expressions where possible.
+/// Returns None if the value has no source form that evaluates back into it.
+fn value_expr(value: Value, span: ast::Span) -> Option<Expr> {
+    if !has_source_form(&value) {
+        return None;
+    }
+    let kind = match value {
+        Value::Number(num) => ExprKind::Number(num),
+        Value::Bytes(bytes) => ExprKind::Bytes(bytes),
+        Value::String(string) => ExprKind::String(string),
+        value => ExprKind::Value(value.into()),
+    };
+    Some(Expr::new(kind, span))
+}
+
+/// Whether the value can be printed as a literal or constructor call that evaluates back into it
+fn has_source_form(value: &Value) -> bool {
+    match value {
+        Value::Number(_) | Value::Bytes(_) | Value::String(_) | Value::Bool(_) | Value::Null => {
+            true
+        }
+        Value::Policy(_) | Value::Script(_) => true,
+        // X-only keys are printed as 32 bytes of hex, which parse back as a hash
+        Value::PubKey(DescriptorPublicKey::SinglePub(key)) => {
+            !matches!(key.key, SinglePubKey::XOnly(_))
+        }
+        Value::PubKey(_) => true,
+        // Mainnet is only available under its reckless name, not as `bitcoin`
+        Value::Network(network) => *network != Network::Bitcoin,
+        Value::WithProb(_, value) => has_source_form(value),
+        Value::Array(elements) => elements.iter().all(has_source_form),
+        Value::Map(entries) => entries.values().all(has_source_form),
+        // Descriptors are printed with their checksum and miniscript with its own syntax, while
+        // functions cannot be printed along with the scope they captured
+        Value::Miniscript(_) | Value::Descriptor(_) | Value::Address(_) | Value::Function(_) => {
+            false
+        }
+        // Private keys are never written out, unless explicitly revealed with revealSecKey()
+        Value::SecKey(_) => false,
+    }
+}
+
+/// Whether the expression is a value produced by folding (not referring to any functions by name)
+fn is_value(expr: &Expr) -> bool {
+    literal_value(expr).is_some()
+}
+
+fn literal_value(expr: &Expr) -> Option<Value> {
+    Some(match &expr.kind {
+        ExprKind::Number(num) => Value::Number(*num),
+        ExprKind::Bytes(bytes) => Value::Bytes(bytes.clone()),
+        ExprKind::String(string) => Value::String(string.clone()),
+        ExprKind::Value(value) => (**value).clone(),
+        _ => return None,
+    })
+}
+
+fn pattern_idents(pattern: &Pattern) -> Vec<&Ident> {
+    match pattern {
+        Pattern::Ident(ident) => vec![ident],
+        Pattern::Array { elements, rest } => elements
+            .iter()
+            .flat_map(pattern_idents)
+            .chain(rest.as_ref())
+            .collect(),
+    }
+}
@@ -1,9 +1,11 @@
 use std::fmt;
+use std::rc::Rc;
 
-use crate::ast::{self, Expr, Ident};
+use crate::ast::{self, Expr, Ident, Pattern, Signature};
 use crate::error::{Error, Result};
 use crate::runtime::{Evaluate, Value};
-use crate::scope::Scope;
+use crate::scope::{Scope, WeakScope};
+use crate::types::FnType;
 
 #[derive(Debug, Clone)]
 pub enum Function {
@@ -12,17 +14,36 @@ pub enum Function {
 }
 
 /// A user-defined function implemented in Minsc
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct UserFunction {
     pub ident: Ident,
-    pub signature: Vec<Ident>,
-    pub body: Expr,
+    pub signature: Signature,
+    /// Shared by the copies of the function value, identifying the function for equality
+    pub body: Rc<Expr>,
+    /// The scope the function was defined in, used to resolve variables lexically
+    pub scope: CapturedScope,
 }
 impl_from_variant!(UserFunction, Function, User);
 
+/// The scope captured by a user function
+#[derive(Clone)]
+pub enum CapturedScope {
+    Strong(Scope),
+    /// Used while the function is stored in the scope it captured (see `Scope`)
+    Weak(WeakScope),
+}
+
 /// A native function implemented in Rust
 #[derive(Clone)]
-pub struct NativeFunction(pub NativeFunctionPt);
+pub struct NativeFunction {
+    pub pt: NativeFunctionPt,
+    /// The parameter names (if declared), used for named arguments
+    pub params: &'static [&'static str],
+    /// The type signature (if declared), used by the type checker
+    pub sig: Option<Rc<FnType>>,
+    /// A short description, used by the documentation generator
+    pub doc: Option<&'static str>,
+}
 
 pub type NativeFunctionPt = fn(Vec<Value>, &Scope) -> Result<Value>;
 
@@ -34,6 +55,16 @@ impl fmt::Debug for NativeFunction {
     }
 }
 
+impl fmt::Debug for UserFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UserFunction")
+            .field("ident", &self.ident)
+            .field("signature", &self.signature)
+            .field("body", &self.body)
+            .finish()
+    }
+}
+
 pub trait Call {
     fn call(&self, args: Vec<Value>, scope: &Scope) -> Result<Value>;
 }
@@ -48,18 +79,118 @@ impl Call for Function {
 }
 
 impl Call for UserFunction {
-    fn call(&self, args: Vec<Value>, scope: &Scope) -> Result<Value> {
-        if self.signature.len() != args.len() {
+    // Evaluate the body in a child of the function's defining scope. The caller's scope
+    // is not visible to the function body, but its budget and output are used for the call.
+    fn call(&self, args: Vec<Value>, caller_scope: &Scope) -> Result<Value> {
+        let (min_args, max_args) = (self.signature.min_args(), self.signature.max_args());
+        if args.len() < min_args || matches!(max_args, Some(max) if args.len() > max) {
             return Err(Error::ArgumentMismatch(
                 self.ident.clone(),
-                self.signature.len(),
+                min_args,
+                max_args,
                 args.len(),
             ));
         }
-        let mut scope = scope.child();
-        for (index, value) in args.into_iter().enumerate() {
-            let ident = self.signature.get(index).unwrap();
-            scope.set(ident.clone(), value)?;
+        let params_len = self.signature.params.len();
+        let mut args = args;
+        let rest = args.split_off(params_len.min(args.len()));
+        self.call_arranged(args.into_iter().map(Some).collect(), rest, caller_scope)
+    }
+}
+
+impl UserFunction {
+    /// The scope the function was defined in
+    pub fn scope(&self) -> Scope {
+        match &self.scope {
+            CapturedScope::Strong(scope) => scope.clone(),
+            // Weak references only exist within the scope itself, which is alive while it's accessible
+            CapturedScope::Weak(scope) => scope.upgrade().expect("stored in its captured scope"),
+        }
+    }
+
+    /// Weaken the reference to the captured scope if it is `scope`, for storing the function in it
+    pub(crate) fn weaken_for(self, scope: &Scope) -> Self {
+        match &self.scope {
+            CapturedScope::Strong(captured) if captured.ptr_eq(scope) => UserFunction {
+                scope: CapturedScope::Weak(scope.downgrade()),
+                ..self
+            },
+            _ => self,
+        }
+    }
+
+    /// Restore the strong reference to the captured scope, for using the function outside of it
+    pub(crate) fn strengthen(self) -> Self {
+        match &self.scope {
+            CapturedScope::Weak(_) => UserFunction {
+                scope: CapturedScope::Strong(self.scope()),
+                ..self
+            },
+            CapturedScope::Strong(_) => self,
+        }
+    }
+
+    /// Call the function with positional arguments followed by named arguments,
+    /// which are bound to the parameters with the same name
+    pub fn call_named(
+        &self,
+        args: Vec<Value>,
+        named_args: Vec<(Ident, Value)>,
+        caller_scope: &Scope,
+    ) -> Result<Value> {
+        let param_names: Vec<Option<&str>> = self
+            .signature
+            .params
+            .iter()
+            .map(|param| match &param.pattern {
+                Pattern::Ident(ident) => Some(ident.0.as_str()),
+                Pattern::Array { .. } => None,
+            })
+            .collect();
+        let (args, rest) = arrange_args(&param_names, args, named_args)?;
+
+        if !rest.is_empty() && self.signature.rest.is_none() {
+            let provided = args.iter().flatten().count() + rest.len();
+            let (min_args, max_args) = (self.signature.min_args(), self.signature.max_args());
+            bail!(Error::ArgumentMismatch(
+                self.ident.clone(),
+                min_args,
+                max_args,
+                provided
+            ));
+        }
+        self.call_arranged(args, rest, caller_scope)
+    }
+
+    // Call with the arguments arranged by their parameter position, using the default value where
+    // an argument is missing. The `rest` arguments are collected into the variadic parameter.
+    fn call_arranged(
+        &self,
+        args: Vec<Option<Value>>,
+        rest: Vec<Value>,
+        caller_scope: &Scope,
+    ) -> Result<Value> {
+        let _guard = caller_scope.budget().map(|b| b.enter_call()).transpose()?;
+
+        let mut scope = self.scope().child_for_caller(caller_scope);
+        let mut args = args.into_iter();
+        for param in &self.signature.params {
+            let value = match (args.next().flatten(), &param.default) {
+                (Some(value), _) => value,
+                // Defaults are evaluated in the function scope, and can refer to the preceding parameters
+                (None, Some(default)) => default.eval(&scope)?,
+                (None, None) => match &param.pattern {
+                    Pattern::Ident(ident) => bail!(Error::MissingArgument(ident.clone())),
+                    Pattern::Array { .. } => bail!(Error::InvalidArguments),
+                },
+            };
+            if let Some(ty) = param.ty {
+                ty.ensure(&value)?;
+            }
+            param.pattern.bind(value, &mut scope)?;
+        }
+        if let Some(rest_ident) = &self.signature.rest {
+            scope.set(rest_ident.clone(), rest)?;
         }
         self.body.eval(&scope)
     }
@@ -67,7 +198,73 @@ impl Call for UserFunction {
 
 impl Call for NativeFunction {
     fn call(&self, args: Vec<Value>, scope: &Scope) -> Result<Value> {
-        (self.0)(args, scope)
+        (self.pt)(args, scope)
+    }
+}
+
+impl NativeFunction {
+    /// Call the native function with named arguments, bound using its declared parameter names.
+    /// Optional parameters are determined by the number of arguments, so only trailing ones may be omitted.
+    pub fn call_named(
+        &self,
+        args: Vec<Value>,
+        named_args: Vec<(Ident, Value)>,
+        scope: &Scope,
+    ) -> Result<Value> {
+        ensure!(!self.params.is_empty(), Error::NamedArgsUnsupported);
+
+        let param_names: Vec<Option<&str>> = self.params.iter().copied().map(Some).collect();
+        let (arranged, rest) = arrange_args(&param_names, args, named_args)?;
+
+        let provided_len = arranged
+            .iter()
+            .rposition(Option::is_some)
+            .map_or(0, |i| i + 1);
+        let mut args = Vec::with_capacity(provided_len + rest.len());
+        for (name, arg) in self.params.iter().zip(arranged).take(provided_len) {
+            args.push(arg.ok_or_else(|| Error::MissingArgument((*name).into()))?);
+        }
+        args.extend(rest);
+        self.call(args, scope)
+    }
+}
+
+/// Arrange the positional and named arguments by their parameter position, leaving the parameters
+/// that weren't provided empty. Positional arguments beyond the parameters are returned separately.
+/// Names are matched with or without the `$` prefix, so `amount: 1` binds to `$amount`.
+fn arrange_args(
+    param_names: &[Option<&str>],
+    mut args: Vec<Value>,
+    named_args: Vec<(Ident, Value)>,
+) -> Result<(Vec<Option<Value>>, Vec<Value>)> {
+    let rest = args.split_off(param_names.len().min(args.len()));
+    let mut arranged: Vec<Option<Value>> = args.into_iter().map(Some).collect();
+    arranged.resize_with(param_names.len(), || None);
+
+    let strip = |name: &str| name.trim_start_matches('$').to_string();
+    for (name, value) in named_args {
+        let index = param_names
+            .iter()
+            .position(|param| param.map(strip) == Some(strip(&name.0)))
+            .ok_or_else(|| Error::UnknownArgument(name.clone()))?;
+        ensure!(arranged[index].is_none(), Error::DuplicateArgument(name));
+        arranged[index] = Some(value);
+    }
+    Ok((arranged, rest))
+}
+
+impl Function {
+    /// Call the function with positional arguments followed by named arguments
+    pub fn call_named(
+        &self,
+        args: Vec<Value>,
+        named_args: Vec<(Ident, Value)>,
+        scope: &Scope,
+    ) -> Result<Value> {
+        match self {
+            Function::User(x) => x.call_named(args, named_args, scope),
+            Function::Native(x) => x.call_named(args, named_args, scope),
+        }
     }
 }
 
@@ -82,27 +279,35 @@ impl Call for Value {
 
 impl From<NativeFunctionPt> for Function {
     fn from(f: NativeFunctionPt) -> Self {
-        NativeFunction(f).into()
+        NativeFunction {
+            pt: f,
+            params: &[],
+            sig: None,
+            doc: None,
+        }
+        .into()
     }
 }
 
-impl From<ast::FnDef> for Function {
-    fn from(fn_def: ast::FnDef) -> Self {
+impl Function {
+    /// Create a named function from its definition, capturing the scope it was defined in
+    pub fn from_def(fn_def: ast::FnDef, scope: &Scope) -> Self {
         UserFunction {
             ident: fn_def.ident,
             signature: fn_def.signature,
-            body: fn_def.body,
+            body: Rc::new(fn_def.body),
+            scope: CapturedScope::Strong(scope.clone()),
         }
         .into()
     }
-}
 
-impl From<ast::FnExpr> for Function {
-    fn from(fn_expr: ast::FnExpr) -> Self {
+    /// Create an anonymous function from a function expression, capturing the scope it was evaluated in
+    pub fn from_expr(fn_expr: ast::FnExpr, scope: &Scope) -> Self {
         UserFunction {
             ident: "_anon".into(),
             signature: fn_expr.signature,
-            body: *fn_expr.body,
+            body: Rc::new(*fn_expr.body),
+            scope: CapturedScope::Strong(scope.clone()),
         }
         .into()
     }
@@ -111,14 +316,13 @@ impl From<ast::FnExpr> for Function {
 impl PartialEq for Function {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (Function::Native(a), Function::Native(b)) => a.0 as usize == b.0 as usize,
+            (Function::Native(a), Function::Native(b)) => a.pt as usize == b.pt as usize,
             (Function::Native(_), Function::User(_)) | (Function::User(_), Function::Native(_)) => {
                 false
             }
-            (Function::User(_), Function::User(_)) => {
-                // TODO this requires implementing PartialEq for ast::Expr
-                unimplemented!("user defined functions cannot be compared")
-            }
+            // User functions are equal to the copies of themselves, created by the same
+            // evaluation of their definition
+            (Function::User(a), Function::User(b)) => Rc::ptr_eq(&a.body, &b.body),
         }
     }
 }
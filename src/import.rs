@@ -0,0 +1,188 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::{fs, io};
+
+use crate::ast::{Ident, SourceId};
+use crate::error::{Error, Result};
+use crate::runtime::{Execute, Value};
+use crate::{parse_lib_source, Scope};
+
+/// Source ids below this are reserved for the main program and the built-in libraries
+const FIRST_IMPORT_SOURCE: usize = 16;
+
+/// The definitions exported by an imported library
+pub type Module = Rc<Vec<(Ident, Value)>>;
+
+/// Resolves import paths into source code
+pub trait Resolver {
+    /// Read the source code of the file at the given (already resolved) path
+    fn load(&self, path: &str) -> Result<String>;
+
+    /// Resolve the imported `path` into the path used to load and identify the file.
+    /// Relative paths are resolved against the file containing the import statement.
+    fn resolve_path(&self, path: &str, importer: Option<&str>) -> String {
+        join_path(path, importer)
+    }
+}
+
+/// Resolve imports from the filesystem
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FsResolver;
+
+impl Resolver for FsResolver {
+    fn load(&self, path: &str) -> Result<String> {
+        fs::read_to_string(path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => Error::ImportNotFound(path.into()),
+            _ => e.into(),
+        })
+    }
+}
+
+/// Resolve imports from an in-memory set of files, used where there's no filesystem (i.e. WASM)
+#[derive(Debug, Default, Clone)]
+pub struct MemoryResolver {
+    files: HashMap<String, String>,
+}
+
+impl MemoryResolver {
+    pub fn add_file(&mut self, path: &str, code: &str) {
+        self.files.insert(join_path(path, None), code.into());
+    }
+
+    pub fn clear(&mut self) {
+        self.files.clear();
+    }
+}
+
+impl Resolver for MemoryResolver {
+    fn load(&self, path: &str) -> Result<String> {
+        let code = self.files.get(path);
+        Ok(code
+            .ok_or_else(|| Error::ImportNotFound(path.into()))?
+            .clone())
+    }
+}
+
+/// Resolve `path` relative to the directory of the `importer` file,
+/// normalizing away `.` and `..` components
+pub fn join_path(path: &str, importer: Option<&str>) -> String {
+    let base = importer
+        .and_then(|importer| Path::new(importer).parent())
+        .unwrap_or_else(|| Path::new(""));
+
+    let mut joined = PathBuf::new();
+    for component in base.join(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(joined.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                joined.pop();
+            }
+            component => joined.push(component),
+        }
+    }
+    joined.to_string_lossy().into_owned()
+}
+
+/// Loads imported libraries for the evaluation of a program. Each file is executed once,
+/// with its exported definitions cached for subsequent imports. Import cycles are rejected.
+pub struct Importer {
+    resolver: Box<dyn Resolver>,
+    // The path of the main program, if it was read from a file
+    main_path: Option<String>,
+    // Exported definitions of the executed libraries, by their resolved path
+    modules: RefCell<HashMap<String, Module>>,
+    // Paths and source code of the loaded files, indexed by their SourceId (offset by FIRST_IMPORT_SOURCE)
+    sources: RefCell<Vec<(String, Rc<str>)>>,
+    // Paths of the files currently being imported, for cycle detection
+    loading: RefCell<Vec<String>>,
+}
+
+impl Importer {
+    pub fn new<R: Resolver + 'static>(resolver: R, main_path: Option<String>) -> Self {
+        Importer {
+            resolver: Box::new(resolver),
+            main_path: main_path.map(|path| join_path(&path, None)),
+            modules: RefCell::new(HashMap::new()),
+            sources: RefCell::new(vec![]),
+            loading: RefCell::new(vec![]),
+        }
+    }
+
+    /// Import the library at `path`, from within the source identified by `importer_source`.
+    ///
+    /// The library is executed in a child of the `root` scope, so that it can only see the
+    /// built-ins and its own definitions, not the importing scope.
+    pub fn import(
+        self: &Rc<Self>,
+        path: &str,
+        importer_source: SourceId,
+        root: &Scope,
+    ) -> Result<Module> {
+        let importer_path = self.source_path(importer_source);
+        let path = self.resolver.resolve_path(path, importer_path.as_deref());
+
+        if let Some(module) = self.modules.borrow().get(&path) {
+            return Ok(module.clone());
+        }
+
+        let stack: Vec<String> = self
+            .main_path
+            .iter()
+            .chain(self.loading.borrow().iter())
+            .cloned()
+            .collect();
+        if let Some(pos) = stack.iter().position(|p| *p == path) {
+            let mut cycle = stack[pos..].to_vec();
+            cycle.push(path);
+            bail!(Error::ImportCycle(cycle));
+        }
+
+        let code: Rc<str> = self.resolver.load(&path)?.into();
+        let source = {
+            let mut sources = self.sources.borrow_mut();
+            sources.push((path.clone(), code.clone()));
+            SourceId(FIRST_IMPORT_SOURCE + sources.len() - 1)
+        };
+        let library = parse_lib_source(&code, source)?;
+
+        let mut scope = root.child_with_importer(self.clone());
+        self.loading.borrow_mut().push(path.clone());
+        let result = library.exec(&mut scope);
+        self.loading.borrow_mut().pop();
+        result?;
+
+        let module = Rc::new(scope.locals());
+        self.modules.borrow_mut().insert(path, module.clone());
+        Ok(module)
+    }
+
+    /// Get the paths and source code of the imported files, indexed by their SourceId
+    pub fn sources(&self) -> Vec<(SourceId, String, Rc<str>)> {
+        let sources = self.sources.borrow();
+        let sources = sources.iter().enumerate();
+        sources
+            .map(|(i, (path, code))| {
+                (
+                    SourceId(FIRST_IMPORT_SOURCE + i),
+                    path.clone(),
+                    code.clone(),
+                )
+            })
+            .collect()
+    }
+
+    // The path of the file identified by the source id. Built-in libraries have no path,
+    // and neither does the main program if it wasn't read from a file.
+    fn source_path(&self, source: SourceId) -> Option<String> {
+        if source == SourceId::MAIN {
+            self.main_path.clone()
+        } else {
+            let index = source.0.checked_sub(FIRST_IMPORT_SOURCE)?;
+            Some(self.sources.borrow().get(index)?.0.clone())
+        }
+    }
+}
@@ -1,23 +1,40 @@
-#[macro_use]
-extern crate lalrpop_util;
 #[cfg(feature = "lazy_static")]
 #[macro_use]
 extern crate lazy_static;
 
-lalrpop_mod!(
-    #[allow(clippy::all)]
-    grammar
-);
+// The generated parser. Included directly rather than with `lalrpop_mod!`, to expose its lexer.
+#[allow(clippy::all)]
+mod grammar {
+    include!(concat!(env!("OUT_DIR"), "/grammar.rs"));
+
+    thread_local! {
+        static LEXER: lalrpop_util::lexer::MatcherBuilder = __intern_token::new_builder();
+    }
+
+    /// Run the lexer used by the parsers, which skips over whitespace and comments
+    pub(crate) fn with_lexer<T>(f: impl FnOnce(&lalrpop_util::lexer::MatcherBuilder) -> T) -> T {
+        LEXER.with(f)
+    }
+}
 
 #[macro_use]
 mod macros;
 pub mod ast;
+pub mod budget;
+pub mod doc;
 pub mod error;
+pub mod fold;
 pub mod function;
+pub mod import;
+pub mod lint;
+pub mod output;
+pub mod pretty;
 pub mod runtime;
 pub mod scope;
 pub mod stdlib;
+pub mod testing;
 pub mod time;
+pub mod types;
 pub mod util;
 
 #[cfg(feature = "playground")]
@@ -25,12 +42,21 @@ pub mod playground;
 #[cfg(feature = "wasm")]
 pub mod wasm;
 
-pub use ast::{Expr, Ident};
+pub use ast::{Expr, Ident, SourceId, Span};
+pub use budget::{Budget, Limits};
 pub use error::{Error, Result};
+pub use fold::{fold, fold_lib};
+pub use import::{FsResolver, Importer, MemoryResolver, Resolver};
+pub use lint::{lint, Lint, Warning};
+pub use output::{LogOutput, Output, StderrOutput};
+pub use pretty::{format_program, format_source};
 pub use runtime::{Evaluate, Value};
 pub use scope::Scope;
+pub use testing::{run_tests, TestResult};
+pub use types::Type;
 
 use miniscript::{descriptor, policy};
+use std::rc::Rc;
 
 pub type Policy = policy::concrete::Policy<descriptor::DescriptorPublicKey>;
 pub type Miniscript = miniscript::Miniscript<descriptor::DescriptorPublicKey, miniscript::Segwitv0>;
@@ -38,18 +64,59 @@ pub type Descriptor = descriptor::Descriptor<descriptor::DescriptorPublicKey>;
 
 pub fn parse(s: &str) -> Result<Expr> {
     let parser = grammar::ProgramParser::new();
-    Ok(parser.parse(s)?)
+    let mut program = parser
+        .parse(SourceId::MAIN.at(0), s)
+        .map_err(|e| Error::from_parse_error(e, SourceId::MAIN))?;
+    if let ast::ExprKind::Block(block) = &mut program.kind {
+        doc::attach_doc_comments(&mut block.stmts, s);
+    }
+    Ok(program)
 }
 
+/// Type check the program and evaluate it
 pub fn eval(expr: Expr) -> Result<Value> {
-    expr.eval(&Scope::root())
+    let scope = Scope::root();
+    types::check(&expr, &scope)?;
+    expr.eval(&scope)
 }
 
 pub fn run(s: &str) -> Result<Value> {
     eval(parse(s)?)
 }
 
+/// Evaluate the program, resolving its import statements using the given importer
+pub fn eval_with_importer(expr: Expr, importer: &Rc<Importer>) -> Result<Value> {
+    let scope = Scope::root().child_with_importer(importer.clone());
+    types::check(&expr, &scope)?;
+    expr.eval(&scope)
+}
+
+pub fn run_with_importer(s: &str, importer: &Rc<Importer>) -> Result<Value> {
+    eval_with_importer(parse(s)?, importer)
+}
+
+/// Evaluate the program under the given resource limits, for running untrusted code
+pub fn eval_with_limits(expr: Expr, limits: Limits) -> Result<Value> {
+    let budget = Rc::new(Budget::new(limits));
+    let scope = Scope::root().child_with_budget(Some(budget));
+    types::check(&expr, &scope)?;
+    expr.eval(&scope)
+}
+
+pub fn run_with_limits(s: &str, limits: Limits) -> Result<Value> {
+    eval_with_limits(parse(s)?, limits)
+}
+
 pub fn parse_lib(s: &str) -> Result<ast::Library> {
+    parse_lib_source(s, SourceId::MAIN)
+}
+
+/// Parse a library, tagging its AST spans with the given source id
+pub fn parse_lib_source(s: &str, source: SourceId) -> Result<ast::Library> {
     let parser = grammar::LibraryParser::new();
-    Ok(parser.parse(s)?)
+    let mut library = parser
+        .parse(source.at(0), s)
+        .map_err(|e| Error::from_parse_error(e, source))?;
+    doc::attach_doc_comments(&mut library.stmts, s);
+    Ok(library)
 }
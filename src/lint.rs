@@ -0,0 +1,430 @@
+//! Lints for programs that evaluate fine, but are likely to be mistaken.
+//!
+//! The lint pass walks the AST without evaluating it, producing warnings with a code identifying
+//! the lint and the span of the offending code. Warnings never prevent the program from running.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::ast::{self, Expr, ExprKind, Ident, Pattern, Span, Stmt, StmtKind};
+use crate::error::SourceLocation;
+use crate::{fold, stdlib, time, Scope, SourceId, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lint {
+    /// A variable or function that is never used. Top-level definitions and names starting
+    /// with `$_` are exempt.
+    UnusedBinding,
+    /// A definition that shadows a built-in function, like `fn sha256()`
+    ShadowedBuiltin,
+    /// The same branch repeated within `||`/`&&`
+    IdenticalBranches,
+    /// Height-based and time-based `older()`/`after()` timelocks combined in the same `&&`,
+    /// which cannot both be satisfied by a single transaction
+    MixedTimelocks,
+    /// A field name that is also a variable in scope, like `$arr.CONST`. Field names always
+    /// access the map field of that name, with the variable available as `$arr.(CONST)`.
+    AmbiguousFieldName,
+}
+
+impl Lint {
+    pub fn code(self) -> &'static str {
+        match self {
+            Lint::UnusedBinding => "unused-binding",
+            Lint::ShadowedBuiltin => "shadowed-builtin",
+            Lint::IdenticalBranches => "identical-branches",
+            Lint::MixedTimelocks => "mixed-timelocks",
+            Lint::AmbiguousFieldName => "ambiguous-field-name",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub lint: Lint,
+    pub message: String,
+    pub span: Span,
+}
+
+impl Warning {
+    /// Render the warning with its source location and a caret-underlined snippet, like Error::render()
+    pub fn render(&self, name: &str, code: &str) -> String {
+        let lookup = |source| {
+            if source == SourceId::MAIN {
+                Some((name, code))
+            } else {
+                stdlib::builtin_source(source)
+            }
+        };
+        let mut out = self.to_string();
+        if let Some(loc) = SourceLocation::resolve(self.span, &lookup) {
+            out.push_str(&loc.snippet());
+        }
+        out
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "warning[{}]: {}", self.lint.code(), self.message)
+    }
+}
+
+/// Lint the program, with the built-ins resolved from the given `scope`.
+/// Warnings are returned in the order of their location within the source.
+pub fn lint(expr: &Expr, scope: &Scope) -> Vec<Warning> {
+    let mut linter = Linter {
+        scope,
+        frames: vec![],
+        warnings: vec![],
+    };
+    linter.expr(expr);
+    let mut warnings = linter.warnings;
+    warnings.sort_by_key(|warning| warning.span.start);
+    warnings
+}
+
+struct Linter<'a> {
+    scope: &'a Scope,
+    frames: Vec<HashMap<Ident, Binding>>,
+    warnings: Vec<Warning>,
+}
+
+struct Binding {
+    span: Span,
+    used: bool,
+    /// Reported when unused. Function parameters are not.
+    report_unused: bool,
+    /// The assigned expression, used to resolve timelock values
+    value: Option<Expr>,
+}
+
+impl<'a> Linter<'a> {
+    fn warn(&mut self, lint: Lint, message: String, span: Span) {
+        self.warnings.push(Warning {
+            lint,
+            message,
+            span,
+        });
+    }
+
+    fn push_frame(&mut self) {
+        self.frames.push(HashMap::new());
+    }
+
+    fn pop_frame(&mut self) {
+        let frame = self.frames.pop().expect("pushed before");
+        let mut unused: Vec<_> = frame
+            .into_iter()
+            .filter(|(ident, b)| b.report_unused && !b.used && !ident.0.starts_with("$_"))
+            .collect();
+        unused.sort_by_key(|(_, b)| b.span.start);
+        for (ident, binding) in unused {
+            let message = format!("{} is never used", ident);
+            self.warn(Lint::UnusedBinding, message, binding.span);
+        }
+    }
+
+    fn declare(&mut self, ident: &Ident, span: Span, report_unused: bool, value: Option<Expr>) {
+        if let Some(Value::Function(_)) = self.scope.get(ident) {
+            let message = format!("{} shadows the built-in function", ident);
+            self.warn(Lint::ShadowedBuiltin, message, span);
+        }
+        let binding = Binding {
+            span,
+            used: false,
+            report_unused,
+            value,
+        };
+        let frame = self.frames.last_mut().expect("within a frame");
+        frame.insert(ident.clone(), binding);
+    }
+
+    fn declare_pattern(&mut self, pattern: &Pattern, span: Span, report_unused: bool) {
+        match pattern {
+            Pattern::Ident(ident) => self.declare(ident, span, report_unused, None),
+            Pattern::Array { elements, rest } => {
+                for element in elements {
+                    self.declare_pattern(element, span, report_unused);
+                }
+                if let Some(rest) = rest {
+                    self.declare(rest, span, report_unused, None);
+                }
+            }
+        }
+    }
+
+    fn match_pattern(&mut self, pattern: &ast::MatchPattern, span: Span) {
+        match pattern {
+            ast::MatchPattern::Wildcard => (),
+            ast::MatchPattern::Bind(ident) => self.declare(ident, span, true, None),
+            ast::MatchPattern::Literal(literal) => self.expr(literal),
+            ast::MatchPattern::Type(_, inner) => self.match_pattern(inner, span),
+            ast::MatchPattern::Array { elements, rest } => {
+                for element in elements {
+                    self.match_pattern(element, span);
+                }
+                if let Some(rest) = rest.as_ref().filter(|rest| rest.0 != "_") {
+                    self.declare(rest, span, true, None);
+                }
+            }
+        }
+    }
+
+    /// Mark the variable as used, returning its binding
+    fn use_ident(&mut self, ident: &Ident) -> Option<&Binding> {
+        let binding = self
+            .frames
+            .iter_mut()
+            .rev()
+            .find_map(|f| f.get_mut(ident))?;
+        binding.used = true;
+        Some(binding)
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Ident(ident) => {
+                self.use_ident(ident);
+            }
+            ExprKind::Call(call) => {
+                self.use_ident(&call.ident);
+                self.exprs(&call.args);
+                for (_, arg) in &call.named_args {
+                    self.expr(arg);
+                }
+                if call.ident.0 == "and" {
+                    self.timelocks(&call.args, expr.span);
+                }
+                if call.ident.0 == "and" || call.ident.0 == "or" {
+                    self.identical_branches(&call.args, &call.ident.0);
+                }
+            }
+            ExprKind::Or(ast::Or(operands)) => {
+                self.exprs(operands);
+                self.identical_branches(operands, "||");
+            }
+            ExprKind::And(ast::And(operands)) => {
+                self.exprs(operands);
+                self.identical_branches(operands, "&&");
+                self.timelocks(operands, expr.span);
+            }
+            ExprKind::Thresh(x) => {
+                self.expr(&x.thresh);
+                self.expr(&x.policies);
+            }
+            ExprKind::Block(block) => self.block(&block.stmts, block.return_value.as_deref()),
+            ExprKind::Array(ast::Array(elements)) => self.exprs(elements),
+            ExprKind::Map(ast::Map(entries)) => {
+                for (_, value) in entries {
+                    self.expr(value);
+                }
+            }
+            ExprKind::ArrayAccess(x) => {
+                self.expr(&x.array);
+                self.expr(&x.index);
+                if let ExprKind::String(field) = &x.index.kind {
+                    self.field_name(field, x.index.span);
+                }
+            }
+            ExprKind::Slice(x) => {
+                self.expr(&x.value);
+                for bound in x.start.iter().chain(&x.end) {
+                    self.expr(bound);
+                }
+            }
+            ExprKind::Range(x) => {
+                self.expr(&x.start);
+                self.expr(&x.end);
+            }
+            ExprKind::ChildDerive(x) => {
+                self.expr(&x.parent);
+                for step in &x.path {
+                    self.expr(&step.child);
+                }
+            }
+            ExprKind::ScriptFrag(x) => self.exprs(&x.fragments),
+            ExprKind::FnExpr(x) => self.function(&x.signature, &x.body, expr.span),
+            ExprKind::Infix(x) => {
+                self.expr(&x.lhs);
+                self.expr(&x.rhs);
+            }
+            ExprKind::Not(ast::Not(x)) | ExprKind::Neg(ast::Neg(x)) => self.expr(x),
+            ExprKind::IfExpr(x) => {
+                self.expr(&x.condition);
+                self.expr(&x.then_val);
+                self.expr(&x.else_val);
+            }
+            ExprKind::Match(x) => {
+                self.expr(&x.value);
+                for arm in &x.arms {
+                    self.push_frame();
+                    self.match_pattern(&arm.pattern, arm.span);
+                    if let Some(guard) = &arm.guard {
+                        self.expr(guard);
+                    }
+                    self.expr(&arm.body);
+                    self.pop_frame();
+                }
+            }
+            ExprKind::Try(x) => {
+                self.expr(&x.body);
+                self.push_frame();
+                if let Some(error) = &x.error {
+                    self.declare(error, expr.span, true, None);
+                }
+                self.expr(&x.handler);
+                self.pop_frame();
+            }
+            ExprKind::Coalesce(x) => {
+                self.expr(&x.lhs);
+                self.expr(&x.rhs);
+            }
+            ExprKind::InterpolatedStr(ast::InterpolatedStr(parts)) => {
+                for part in parts {
+                    if let ast::StrPart::Expr(expr) = part {
+                        self.expr(expr);
+                    }
+                }
+            }
+            // Durations are converted using the BLOCK_INTERVAL, which may be redefined
+            ExprKind::Duration(_) => {
+                self.use_ident(&"BLOCK_INTERVAL".into());
+            }
+            ExprKind::PubKey(_)
+            | ExprKind::SecKey(_)
+            | ExprKind::Bytes(_)
+            | ExprKind::String(_)
+            | ExprKind::Number(_)
+            | ExprKind::DateTime(_)
+            | ExprKind::BtcAmount(_)
+            | ExprKind::Value(_) => (),
+        }
+    }
+
+    fn field_name(&mut self, field: &str, span: Span) {
+        let ident = Ident::from(field);
+        if self.frames.iter().any(|frame| frame.contains_key(&ident)) {
+            let message = format!(
+                ".{0} accesses the \"{0}\" field rather than the {0} variable, use .({0}) to index by it",
+                field
+            );
+            self.warn(Lint::AmbiguousFieldName, message, span);
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[Expr]) {
+        for expr in exprs {
+            self.expr(expr);
+        }
+    }
+
+    fn block(&mut self, stmts: &[Stmt], return_value: Option<&Expr>) {
+        // Top-level definitions may be used without being referenced, by the runtime calling main()
+        // or by the programs importing them
+        let report_unused = !self.frames.is_empty();
+        self.push_frame();
+        // Definitions are hoisted, so that they're visible throughout the block
+        for stmt in stmts {
+            match &stmt.kind {
+                StmtKind::FnDef(def) => self.declare(&def.ident, stmt.span, report_unused, None),
+                StmtKind::Assign(assign) => {
+                    for assignment in &assign.0 {
+                        match &assignment.lhs {
+                            Pattern::Ident(ident) => {
+                                let value = Some(assignment.rhs.clone());
+                                self.declare(ident, stmt.span, report_unused, value)
+                            }
+                            pattern => self.declare_pattern(pattern, stmt.span, report_unused),
+                        }
+                    }
+                }
+                StmtKind::Import(_) | StmtKind::ExprStmt(_) | StmtKind::Test(_) => (),
+            }
+        }
+        for stmt in stmts {
+            match &stmt.kind {
+                StmtKind::FnDef(def) => self.function(&def.signature, &def.body, stmt.span),
+                StmtKind::Assign(assign) => {
+                    for assignment in &assign.0 {
+                        self.expr(&assignment.rhs);
+                    }
+                }
+                StmtKind::Import(_) => (),
+                StmtKind::ExprStmt(ast::ExprStmt(expr)) => self.expr(expr),
+                StmtKind::Test(test) => self.expr(&test.body),
+            }
+        }
+        if let Some(return_value) = return_value {
+            self.expr(return_value);
+        }
+        self.pop_frame();
+    }
+
+    fn function(&mut self, signature: &ast::Signature, body: &Expr, span: Span) {
+        self.push_frame();
+        for param in &signature.params {
+            if let Some(default) = &param.default {
+                self.expr(default);
+            }
+            self.declare_pattern(&param.pattern, span, false);
+        }
+        if let Some(rest) = &signature.rest {
+            self.declare(rest, span, false, None);
+        }
+        self.expr(body);
+        self.pop_frame();
+    }
+
+    /// Warn about branches that are repeated within the same `||`/`&&` (or `or()`/`and()`)
+    fn identical_branches(&mut self, branches: &[Expr], op: &str) {
+        // Compared by their source code representation, ignoring formatting
+        let printed: Vec<String> = branches.iter().map(ToString::to_string).collect();
+        for (i, branch) in branches.iter().enumerate() {
+            if printed[..i].contains(&printed[i]) {
+                let message = format!("Identical branch {} repeated in {}", printed[i], op);
+                self.warn(Lint::IdenticalBranches, message, branch.span);
+            }
+        }
+    }
+
+    /// Warn about `&&` branches combining height-based and time-based `older()`/`after()` timelocks
+    fn timelocks(&mut self, branches: &[Expr], span: Span) {
+        for (func, is_time_based) in &[
+            ("older", time::is_time_based_seq as fn(i64) -> bool),
+            ("after", time::is_timestamp_locktime),
+        ] {
+            let kinds: Vec<bool> = branches
+                .iter()
+                .filter_map(|branch| match &branch.kind {
+                    ExprKind::Call(call) if call.ident.0 == *func && call.args.len() == 1 => {
+                        self.number(&call.args[0], 0)
+                    }
+                    _ => None,
+                })
+                .map(is_time_based)
+                .collect();
+            if kinds.contains(&true) && kinds.contains(&false) {
+                let message = format!(
+                    "Height-based and time-based {}() timelocks cannot be combined with &&",
+                    func
+                );
+                self.warn(Lint::MixedTimelocks, message, span);
+            }
+        }
+    }
+
+    /// Resolve the expression into a Number, if it is a constant one (possibly via variables)
+    fn number(&mut self, expr: &Expr, depth: usize) -> Option<i64> {
+        match &expr.kind {
+            ExprKind::Ident(ident) if depth < 10 => {
+                let value = self.use_ident(ident)?.value.clone()?;
+                self.number(&value, depth + 1)
+            }
+            _ => match fold(expr, self.scope).kind {
+                ExprKind::Number(num) => Some(num),
+                _ => None,
+            },
+        }
+    }
+}
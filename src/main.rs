@@ -1,32 +1,230 @@
-use minsc::{eval, parse, Result};
-use std::{env, fs, io};
+use minsc::{
+    doc, fold, format_program, format_source, lint, parse, parse_lib, run_tests, types, Evaluate,
+    FsResolver, Importer, Result, Scope, StderrOutput,
+};
+use std::rc::Rc;
+use std::{env, fs, io, process};
 
 fn main_() -> Result<()> {
     let mut args = env::args();
     let input = args.nth(1).unwrap_or_else(|| "-".into());
+    if input == "fmt" {
+        return fmt(args.collect());
+    }
+    if input == "lint" {
+        return lint_files(args.collect());
+    }
+    if input == "test" {
+        return test_files(args.collect());
+    }
+    if input == "doc" {
+        return doc_files(args.collect());
+    }
 
     let arg = args.next();
     let print_ast = arg == Some("--ast".into());
+    let print_folded = arg == Some("--fold".into());
     let debug = arg == Some("--debug".into());
 
     let mut reader: Box<dyn io::Read> = match &*input {
         "-" => Box::new(io::stdin()),
-        _ => Box::new(fs::File::open(input)?),
+        _ => Box::new(fs::File::open(&input)?),
     };
 
     let mut code = String::new();
     reader.read_to_string(&mut code)?;
 
+    // Imports are resolved relative to the input file, or to the working directory for stdin
+    let main_path = if input == "-" {
+        None
+    } else {
+        Some(input.clone())
+    };
+    let importer = Rc::new(Importer::new(FsResolver, main_path));
+
+    if let Err(e) = run(&code, &importer, print_ast, print_folded, debug) {
+        // Render parse and evaluation errors with their location in the source code
+        let name = if input == "-" { "<stdin>" } else { &input };
+        eprintln!("{}", e.render_with_importer(name, &code, &importer));
+    }
+
+    Ok(())
+}
+
+fn run(
+    code: &str,
+    importer: &Rc<Importer>,
+    print_ast: bool,
+    print_folded: bool,
+    debug: bool,
+) -> Result<()> {
     if print_ast {
-        println!("{:#?}", parse(&code)?);
+        println!("{:#?}", parse(code)?);
+    } else if print_folded {
+        print!("{}", format_program(&fold(&parse(code)?, &Scope::root())));
     } else {
-        let res = eval(parse(&code)?)?;
+        let expr = parse(code)?;
+        let scope = cli_scope(importer);
+        types::check(&expr, &scope)?;
+        let res = expr.eval(&scope)?;
         println!("{}", res);
         if debug {
             println!("\n\n{:#?}", res);
         }
     }
+    Ok(())
+}
+
+/// The evaluation scope, resolving imports from the filesystem and writing the output of `print()`,
+/// `trace()` and `dbg()` to stderr
+fn cli_scope(importer: &Rc<Importer>) -> Scope {
+    Scope::root()
+        .child_with_importer(importer.clone())
+        .child_with_output(Rc::new(StderrOutput))
+}
+
+/// Format the files in place, or check that they are already formatted with `--check`.
+/// Formats stdin to stdout when no files are given.
+fn fmt(args: Vec<String>) -> Result<()> {
+    let check = args.iter().any(|arg| arg == "--check");
+    let files: Vec<&String> = args.iter().filter(|arg| *arg != "--check").collect();
 
+    let mut failed = false;
+    if files.is_empty() || files == ["-"] {
+        let mut code = String::new();
+        io::Read::read_to_string(&mut io::stdin(), &mut code)?;
+        match format_source(&code) {
+            Ok(formatted) if check => failed = formatted != code,
+            Ok(formatted) => print!("{}", formatted),
+            Err(e) => {
+                eprintln!("{}", e.render("<stdin>", &code));
+                failed = true;
+            }
+        }
+    } else {
+        for path in files {
+            let code = fs::read_to_string(path)?;
+            match format_source(&code) {
+                Ok(formatted) if formatted == code => (),
+                Ok(_) if check => {
+                    println!("{} is not formatted", path);
+                    failed = true;
+                }
+                Ok(formatted) => fs::write(path, formatted)?,
+                Err(e) => {
+                    eprintln!("{}", e.render(path, &code));
+                    failed = true;
+                }
+            }
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Print the lint warnings for the files (or stdin when no files are given).
+/// Exits with 1 if there were any warnings or errors.
+fn lint_files(files: Vec<String>) -> Result<()> {
+    let files = if files.is_empty() {
+        vec!["-".to_string()]
+    } else {
+        files
+    };
+    let mut failed = false;
+    for path in files {
+        let (name, code) = if path == "-" {
+            let mut code = String::new();
+            io::Read::read_to_string(&mut io::stdin(), &mut code)?;
+            ("<stdin>".to_string(), code)
+        } else {
+            (path.clone(), fs::read_to_string(&path)?)
+        };
+        match parse(&code) {
+            Ok(program) => {
+                for warning in lint(&program, &Scope::root()) {
+                    eprintln!("{}\n", warning.render(&name, &code));
+                    failed = true;
+                }
+            }
+            Err(e) => {
+                eprintln!("{}", e.render(&name, &code));
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Run the test declarations in the files, reporting each test's outcome.
+/// Exits with 1 if any of them failed.
+fn test_files(files: Vec<String>) -> Result<()> {
+    let (mut passed, mut failed) = (0, 0);
+    for path in files {
+        let code = fs::read_to_string(&path)?;
+        let importer = Rc::new(Importer::new(FsResolver, Some(path.clone())));
+        let scope = cli_scope(&importer);
+        let results = parse(&code).and_then(|program| run_tests(&program, &scope));
+        match results {
+            Ok(results) => {
+                for test in results {
+                    match test.result {
+                        Ok(()) => {
+                            println!("test {:?} ... ok", test.name);
+                            passed += 1;
+                        }
+                        Err(e) => {
+                            println!("test {:?} ... FAILED", test.name);
+                            println!("{}\n", e.render_with_importer(&path, &code, &importer));
+                            failed += 1;
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                println!("{}\n", e.render_with_importer(&path, &code, &importer));
+                failed += 1;
+            }
+        }
+    }
+    println!("\n{} passed; {} failed", passed, failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Print the reference documentation for the libraries' definitions, or for the standard library
+/// when no files are given. Generated as Markdown, or as a standalone HTML page with `--html`.
+fn doc_files(args: Vec<String>) -> Result<()> {
+    let html = args.iter().any(|arg| arg == "--html");
+    let files: Vec<&String> = args.iter().filter(|arg| *arg != "--html").collect();
+
+    let modules = if files.is_empty() {
+        doc::stdlib_docs()
+    } else {
+        let mut modules = vec![];
+        for path in files {
+            let code = fs::read_to_string(path)?;
+            match parse_lib(&code) {
+                Ok(library) => modules.push(doc::library_docs(path, &library)),
+                Err(e) => {
+                    eprintln!("{}", e.render(path, &code));
+                    process::exit(1);
+                }
+            }
+        }
+        modules
+    };
+    if html {
+        print!("{}", doc::to_html(&modules));
+    } else {
+        print!("{}", doc::to_markdown(&modules));
+    }
     Ok(())
 }
 
@@ -0,0 +1,40 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+
+use crate::error::Result;
+
+/// Receives the debugging output of `print()`, `trace()` and `dbg()`, one line per call
+pub trait Output {
+    fn write(&self, line: &str) -> Result<()>;
+}
+
+/// Write the output to stderr, used by the CLI
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StderrOutput;
+
+impl Output for StderrOutput {
+    fn write(&self, line: &str) -> Result<()> {
+        writeln!(io::stderr(), "{}", line)?;
+        Ok(())
+    }
+}
+
+/// Collect the output lines in memory, used where there's no stderr (i.e. WASM)
+#[derive(Debug, Default)]
+pub struct LogOutput {
+    lines: RefCell<Vec<String>>,
+}
+
+impl LogOutput {
+    /// Take the lines collected so far, leaving the log empty
+    pub fn take(&self) -> Vec<String> {
+        self.lines.take()
+    }
+}
+
+impl Output for LogOutput {
+    fn write(&self, line: &str) -> Result<()> {
+        self.lines.borrow_mut().push(line.into());
+        Ok(())
+    }
+}
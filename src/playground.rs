@@ -2,11 +2,12 @@ use miniscript::bitcoin::hashes::hex::FromHex;
 use miniscript::bitcoin::{Address, Network, Script};
 use miniscript::descriptor::Descriptor;
 use serde::Serialize;
+use std::rc::Rc;
 use std::str::FromStr;
 use wasm_bindgen::prelude::*;
 
 use crate::util::DescriptorExt;
-use crate::{parse, Evaluate, Result, Scope, Value};
+use crate::{lint, parse, types, wasm, Evaluate, Importer, Result, Scope, Value};
 
 #[derive(Serialize)]
 pub struct PlaygroundResult {
@@ -23,7 +24,9 @@ pub struct PlaygroundResult {
 pub fn run_playground(code: &str, network: &str) -> std::result::Result<JsValue, JsValue> {
     let network = Network::from_str(network).map_err(stringify)?;
 
-    let value = run(code).map_err(stringify)?;
+    let importer = wasm::importer();
+    let value =
+        run(code, &importer).map_err(|e| e.render_with_importer("input", code, &importer))?;
 
     let (policy, miniscript, desc, script, addr, other) = match value {
         Value::Policy(policy) => {
@@ -73,8 +76,43 @@ pub fn run_playground(code: &str, network: &str) -> std::result::Result<JsValue,
     .unwrap())
 }
 
-fn run(code: &str) -> Result<Value> {
-    parse(code)?.eval(&DEMO_SCOPE)
+#[derive(Serialize)]
+pub struct LintWarning {
+    code: &'static str,
+    message: String,
+    // The character offsets of the warned code, for underlining it in the editor
+    start: usize,
+    end: usize,
+}
+
+#[wasm_bindgen]
+pub fn lint_playground(code: &str) -> std::result::Result<JsValue, JsValue> {
+    let expr = parse(code).map_err(|e| e.render("input", code))?;
+    let char_offset = |pos: usize| code.get(..pos).map_or(0, |s| s.chars().count());
+    let warnings: Vec<LintWarning> = DEMO_SCOPE.with(|scope| {
+        lint(&expr, scope)
+            .into_iter()
+            .map(|warning| LintWarning {
+                code: warning.lint.code(),
+                message: warning.message,
+                start: char_offset(warning.span.start),
+                end: char_offset(warning.span.end),
+            })
+            .collect()
+    });
+    Ok(JsValue::from_serde(&warnings).unwrap())
+}
+
+fn run(code: &str, importer: &Rc<Importer>) -> Result<Value> {
+    let expr = parse(code)?;
+    DEMO_SCOPE.with(|scope| {
+        let scope = scope
+            .child_with_importer(importer.clone())
+            .child_with_budget(Some(wasm::budget()))
+            .child_with_output(wasm::output());
+        types::check(&expr, &scope)?;
+        expr.eval(&scope)
+    })
 }
 
 fn get_script_asm(script: &Script) -> String {
@@ -86,9 +124,9 @@ fn stringify<T: ToString>(e: T) -> String {
     e.to_string()
 }
 
-lazy_static! {
+thread_local! {
     // Provide some built-in example pubkeys and hashes in the web demo env
-    static ref DEMO_SCOPE: Scope<'static> = {
+    static DEMO_SCOPE: Scope = {
         let mut scope = Scope::root();
         let mut add_key = |name, key: &str| {
             scope
@@ -0,0 +1,1330 @@
+use std::fmt;
+
+use crate::ast::{self, Expr, ExprKind, InfixOp, Pattern, Signature, Stmt, StmtKind, Wildcard};
+use crate::{Result, Value};
+
+/// The maximum line width. Expressions that don't fit are broken over multiple lines.
+const MAX_WIDTH: usize = 100;
+
+const INDENT: &str = "  ";
+
+/// Keywords that cannot be used as plain map keys or field names, and must be quoted instead
+const KEYWORDS: &[&str] = &[
+    "if",
+    "else",
+    "fn",
+    "let",
+    "return",
+    "import",
+    "as",
+    "of",
+    "heightwise",
+    "test",
+    "match",
+    "try",
+    "catch",
+];
+
+// Operator precedence levels, from the lowest to the highest (matching the grammar)
+const PREC_LOWEST: u8 = 0;
+const PREC_PIPE: u8 = 1;
+const PREC_COALESCE: u8 = 2;
+const PREC_RANGE: u8 = 3;
+const PREC_OR: u8 = 4;
+const PREC_AND: u8 = 5;
+const PREC_COMPARISON: u8 = 6;
+const PREC_MULTIPLICATIVE: u8 = 12;
+const PREC_UNARY: u8 = 13;
+const PREC_SIMPLE: u8 = 14;
+
+/// Format Minsc source code into its canonical form, preserving comments
+pub fn format_source(code: &str) -> Result<String> {
+    let program = crate::parse(code)?;
+    let mut printer = Printer::new(Some(code));
+    match &program.kind {
+        ExprKind::Block(block) => printer.program(&block.stmts, block.return_value.as_deref()),
+        _ => unreachable!("programs are always parsed as a Block"),
+    }
+    Ok(printer.out)
+}
+
+/// Format a parsed (or constant-folded) program. Without the source code, comments are not retained.
+pub fn format_program(program: &Expr) -> String {
+    let mut printer = Printer::new(None);
+    match &program.kind {
+        ExprKind::Block(block) => printer.program(&block.stmts, block.return_value.as_deref()),
+        _ => printer.program(&[], Some(program)),
+    }
+    printer.out
+}
+
+/// A `//` comment within the source code.
+///
+/// Comments are skipped by the parser's lexer as trivia between the tokens. They're kept alongside
+/// the AST, then placed back according to their position relative to its spans.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+/// Collect the comments in the source code, in their order of appearance.
+///
+/// Runs the parser's lexer over the code and collects the comments from the trivia it skips between
+/// the tokens, so that strings and other tokens containing `//` are never mistaken for comments.
+pub fn comments(code: &str) -> Vec<Comment> {
+    let mut comments = vec![];
+    let mut trivia_start = 0;
+    crate::grammar::with_lexer(|lexer| {
+        for token in lexer.matcher::<&str>(code) {
+            // Code that fails to lex cannot be parsed either, so there's nothing to format
+            let (start, _, end) = match token {
+                Ok(token) => token,
+                Err(_) => {
+                    trivia_start = code.len();
+                    break;
+                }
+            };
+            trivia_comments(&code[trivia_start..start], trivia_start, &mut comments);
+            trivia_start = end;
+        }
+    });
+    trivia_comments(&code[trivia_start..], trivia_start, &mut comments);
+    comments
+}
+
+// The trivia between tokens consists of whitespace and comments only, so any `//` starts a comment
+fn trivia_comments(trivia: &str, offset: usize, comments: &mut Vec<Comment>) {
+    let mut pos = 0;
+    while let Some(found) = trivia[pos..].find("//") {
+        let start = pos + found;
+        let end = trivia[start..]
+            .find(&['\n', '\r'][..])
+            .map_or(trivia.len(), |len| start + len);
+        comments.push(Comment {
+            start: offset + start,
+            end: offset + end,
+            text: trivia[start..end].trim_end().into(),
+        });
+        pos = end;
+    }
+}
+
+struct Printer<'a> {
+    /// The source code being formatted, used to place comments and preserve blank lines and
+    /// script fragment line breaks. Not available when displaying an AST constructed elsewhere.
+    source: Option<&'a str>,
+    comments: Vec<Comment>,
+    /// The next comment to be printed. All comments before it were already printed.
+    next_comment: usize,
+    out: String,
+    indent: usize,
+    /// Lay everything out on a single line. Used to try out the inline layout.
+    inline_only: bool,
+    /// Set when the inline layout was attempted for something that cannot be inlined
+    inline_failed: bool,
+    /// Allow blocks with statements to be inlined, for string interpolation
+    inline_stmts: bool,
+}
+
+impl<'a> Printer<'a> {
+    fn new(source: Option<&'a str>) -> Self {
+        Printer {
+            source,
+            comments: source.map_or_else(Vec::new, comments),
+            next_comment: 0,
+            out: String::new(),
+            indent: 0,
+            inline_only: false,
+            inline_failed: false,
+            inline_stmts: false,
+        }
+    }
+
+    // Printing primitives
+
+    fn write(&mut self, s: &str) {
+        self.out.push_str(s);
+    }
+
+    fn newline(&mut self) {
+        let trimmed_len = self.out.trim_end_matches(' ').len();
+        self.out.truncate(trimmed_len);
+        self.out.push('\n');
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+    }
+
+    /// The current column on the last line
+    fn col(&self) -> usize {
+        let line_start = self.out.rfind('\n').map_or(0, |pos| pos + 1);
+        self.out[line_start..].chars().count()
+    }
+
+    // Comments and blank lines
+
+    /// Get the next comment to be printed, if it starts before `pos`
+    fn comment_before(&self, pos: usize) -> Option<Comment> {
+        self.comments
+            .get(self.next_comment)
+            .filter(|comment| comment.start < pos)
+            .cloned()
+    }
+
+    fn has_comments(&self, start: usize, end: usize) -> bool {
+        self.comments[self.next_comment..]
+            .iter()
+            .any(|comment| comment.start >= start && comment.start < end)
+    }
+
+    /// Check whether the source had a blank line between `prev_end` and `start`
+    fn blank_line_between(&self, prev_end: Option<usize>, start: usize) -> bool {
+        match (self.source, prev_end) {
+            (Some(source), Some(prev_end)) if prev_end < start => {
+                source[prev_end..start].matches('\n').count() > 1
+            }
+            _ => false,
+        }
+    }
+
+    /// Check whether the source had a line break between `prev_end` and `start`
+    fn line_break_between(&self, prev_end: usize, start: usize) -> bool {
+        match self.source {
+            Some(source) if prev_end < start => source[prev_end..start].contains('\n'),
+            _ => false,
+        }
+    }
+
+    /// Print the comments preceding an item that starts at `start`, each on its own line, followed
+    /// by a blank line if the item had one before it. Expected to be called at the start of a line.
+    fn leading_comments(&mut self, mut prev_end: Option<usize>, start: usize) {
+        while let Some(comment) = self.comment_before(start) {
+            if self.blank_line_between(prev_end, comment.start) {
+                self.newline();
+            }
+            self.write(&comment.text);
+            self.newline();
+            prev_end = Some(comment.end);
+            self.next_comment += 1;
+        }
+        if self.blank_line_between(prev_end, start) {
+            self.newline();
+        }
+    }
+
+    /// Print a comment that follows the item ending at `end` on the same line, before the next
+    /// item starting at `next_start`. Must be followed by a line break. Returns whether there was one.
+    fn trailing_comment(&mut self, end: usize, next_start: usize) -> bool {
+        let comment = match self.comments.get(self.next_comment) {
+            Some(comment)
+                if comment.start >= end
+                    && comment.start < next_start
+                    && !self.line_break_between(end, comment.start) =>
+            {
+                comment.clone()
+            }
+            _ => return false,
+        };
+        self.write(" ");
+        self.write(&comment.text);
+        self.next_comment += 1;
+        true
+    }
+
+    /// Print the comments remaining before the end of a multi-line construct, each on a new line
+    fn closing_comments(&mut self, mut prev_end: Option<usize>, end: usize) {
+        while let Some(comment) = self.comment_before(end) {
+            self.newline();
+            if self.blank_line_between(prev_end, comment.start) {
+                self.newline();
+            }
+            self.write(&comment.text);
+            prev_end = Some(comment.end);
+            self.next_comment += 1;
+        }
+    }
+
+    /// Find the end of a block whose last item ends at `end`, including the comments and
+    /// whitespace that follow it, up to its closing brace.
+    fn block_end(&self, end: usize) -> usize {
+        let source = match self.source {
+            Some(source) => source,
+            None => return end,
+        };
+        let mut pos = end;
+        loop {
+            let rest = &source[pos..];
+            let trimmed = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ';');
+            pos += rest.len() - trimmed.len();
+            if trimmed.starts_with("//") {
+                pos += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else {
+                return pos;
+            }
+        }
+    }
+
+    // Layout selection
+
+    /// Render using the inline layout. Returns None if it cannot be inlined or contains comments.
+    fn try_inline(&self, start: usize, end: usize, f: impl FnOnce(&mut Printer)) -> Option<String> {
+        if self.has_comments(start, end) {
+            return None;
+        }
+        let mut printer = self.inline_printer();
+        f(&mut printer);
+        iif!(printer.inline_failed, None, Some(printer.out))
+    }
+
+    fn inline_printer(&self) -> Printer<'a> {
+        Printer {
+            source: self.source,
+            comments: vec![],
+            next_comment: 0,
+            out: String::new(),
+            indent: 0,
+            inline_only: true,
+            inline_failed: false,
+            inline_stmts: self.inline_stmts,
+        }
+    }
+
+    /// Print using the inline layout if it fits within the line, or using the multi-line layout otherwise
+    fn expr(&mut self, expr: &Expr) {
+        if self.inline_only {
+            return self.layout(expr, false);
+        }
+        let end = iif!(
+            matches!(expr.kind, ExprKind::Block(_)),
+            self.block_end(expr.span.end),
+            expr.span.end
+        );
+        if let Some(inline) = self.try_inline(expr.span.start, end, |p| p.layout(expr, false)) {
+            if self.col() + inline.chars().count() <= MAX_WIDTH {
+                return self.write(&inline);
+            }
+        }
+        self.layout(expr, true)
+    }
+
+    /// Print the expression, wrapped in parenthesis if its precedence is lower than `min_prec`
+    fn expr_prec(&mut self, expr: &Expr, min_prec: u8) {
+        if precedence(expr) < min_prec {
+            self.write("(");
+            self.expr(expr);
+            self.write(")");
+        } else {
+            self.expr(expr);
+        }
+    }
+
+    /// Print an expression in a position that only accepts a limited set of expression kinds,
+    /// wrapping it in a block expression otherwise
+    fn expr_restricted(&mut self, expr: &Expr, allowed: fn(&ExprKind) -> bool) {
+        if allowed(&expr.kind) {
+            self.expr(expr);
+        } else {
+            self.write("{ ");
+            self.expr(expr);
+            self.write(" }");
+        }
+    }
+
+    // Programs and statements
+
+    fn program(&mut self, stmts: &[Stmt], return_value: Option<&Expr>) {
+        let end = self.source.map_or(0, str::len);
+        if stmts.is_empty() && return_value.is_none() {
+            self.leading_comments(None, end);
+        } else {
+            self.body(stmts, return_value, end);
+        }
+        let trimmed_len = self.out.trim_end().len();
+        self.out.truncate(trimmed_len);
+        if !self.out.is_empty() {
+            self.out.push('\n');
+        }
+    }
+
+    /// Print the statements and return value of a block, each on its own line
+    fn body(&mut self, stmts: &[Stmt], return_value: Option<&Expr>, end: usize) {
+        let mut prev_end = None;
+        for (i, stmt) in stmts.iter().enumerate() {
+            if i > 0 {
+                self.newline();
+            }
+            self.leading_comments(prev_end, stmt.span.start);
+            self.stmt(stmt);
+            let next_start = stmts
+                .get(i + 1)
+                .map(|next| next.span.start)
+                .or_else(|| return_value.map(|ret| ret.span.start))
+                .unwrap_or(end);
+            self.trailing_comment(stmt.span.end, next_start);
+            prev_end = Some(stmt.span.end);
+        }
+        if let Some(return_value) = return_value {
+            if !stmts.is_empty() {
+                self.newline();
+            }
+            self.leading_comments(prev_end, return_value.span.start);
+            self.expr(return_value);
+            self.trailing_comment(return_value.span.end, end);
+            prev_end = Some(return_value.span.end);
+        }
+        self.closing_comments(prev_end, end);
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::FnDef(fn_def) => {
+                self.write("fn ");
+                self.write(&fn_def.ident.0);
+                self.write("(");
+                self.signature(&fn_def.signature, PREC_LOWEST);
+                self.write(")");
+                if let ExprKind::Block(_) = fn_def.body.kind {
+                    self.write(" ");
+                    self.expr(&fn_def.body);
+                } else {
+                    self.write(" = ");
+                    self.expr(&fn_def.body);
+                    self.write(";");
+                }
+            }
+            StmtKind::Assign(ast::Assign(assignments)) => {
+                let inline = self.try_inline(stmt.span.start, stmt.span.end, |p| {
+                    p.assignments(assignments, false)
+                });
+                match inline {
+                    Some(inline) if self.col() + inline.chars().count() <= MAX_WIDTH => {
+                        self.write(&inline)
+                    }
+                    _ => self.assignments(assignments, true),
+                }
+            }
+            StmtKind::Import(import) => {
+                self.write("import ");
+                self.write(&quote(&import.path));
+                if let Some(alias) = &import.alias {
+                    self.write(" as ");
+                    self.write(&alias.0);
+                }
+                self.write(";");
+            }
+            StmtKind::ExprStmt(ast::ExprStmt(expr)) => {
+                self.expr(expr);
+                self.write(";");
+            }
+            StmtKind::Test(test) => {
+                self.write("test ");
+                self.write(&quote(&test.name));
+                self.write(" ");
+                self.expr(&test.body);
+            }
+        }
+    }
+
+    fn assignments(&mut self, assignments: &[ast::Assignment], broken: bool) {
+        // Destructuring patterns and type annotations require the `let` keyword, which is otherwise omitted
+        if assignments.iter().any(|assignment| {
+            matches!(assignment.lhs, Pattern::Array { .. }) || assignment.ty.is_some()
+        }) {
+            self.write("let ");
+        }
+        for (i, assignment) in assignments.iter().enumerate() {
+            if i > 0 {
+                self.write(",");
+                if broken {
+                    self.indent += 1;
+                    self.newline();
+                    self.indent -= 1;
+                } else {
+                    self.write(" ");
+                }
+            }
+            self.pattern(&assignment.lhs);
+            if let Some(ty) = assignment.ty {
+                self.write(&format!(": {}", ty));
+            }
+            self.write(" = ");
+            self.expr(&assignment.rhs);
+        }
+        self.write(";");
+    }
+
+    fn pattern(&mut self, pattern: &Pattern) {
+        match pattern {
+            Pattern::Ident(ident) => self.write(&ident.0),
+            Pattern::Array { elements, rest } => {
+                self.write("[");
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        self.write(", ");
+                    }
+                    self.pattern(element);
+                }
+                if let Some(rest) = rest {
+                    if !elements.is_empty() {
+                        self.write(", ");
+                    }
+                    self.write("...");
+                    self.write(&rest.0);
+                }
+                self.write("]");
+            }
+        }
+    }
+
+    /// Print the function parameters, with default values of at least `default_prec` precedence
+    fn signature(&mut self, signature: &Signature, default_prec: u8) {
+        for (i, param) in signature.params.iter().enumerate() {
+            if i > 0 {
+                self.write(", ");
+            }
+            self.pattern(&param.pattern);
+            if let Some(ty) = param.ty {
+                self.write(&format!(": {}", ty));
+            }
+            if let Some(default) = &param.default {
+                self.write(" = ");
+                self.expr_prec(default, default_prec);
+            }
+        }
+        if let Some(rest) = &signature.rest {
+            if !signature.params.is_empty() {
+                self.write(", ");
+            }
+            self.write("...");
+            self.write(&rest.0);
+        }
+    }
+
+    // Expressions
+
+    /// Print the expression using the inline or multi-line layout
+    fn layout(&mut self, expr: &Expr, broken: bool) {
+        match &expr.kind {
+            ExprKind::Block(block) => self.block(block, expr.span.end, broken),
+            ExprKind::Call(call) if call.piped => self.pipeline(expr, broken),
+            ExprKind::Call(call) => {
+                self.write(&call.ident.0);
+                let items = call
+                    .args
+                    .iter()
+                    .map(|arg| (None, arg))
+                    .chain(
+                        call.named_args
+                            .iter()
+                            .map(|(name, arg)| (Some(&name.0), arg)),
+                    )
+                    .collect::<Vec<_>>();
+                self.list(
+                    "(",
+                    ")",
+                    false,
+                    &items,
+                    expr.span.end,
+                    broken,
+                    |p, (name, arg)| {
+                        if let Some(name) = name {
+                            p.write(name);
+                            p.write(": ");
+                        }
+                        p.expr(arg);
+                    },
+                );
+            }
+            ExprKind::Or(ast::Or(branches)) => self.branches(branches, "||", PREC_AND, broken),
+            ExprKind::And(ast::And(branches)) => {
+                self.branches(branches, "&&", PREC_COMPARISON, broken)
+            }
+            ExprKind::Thresh(thresh) => {
+                self.expr_restricted(&thresh.thresh, is_sexpr);
+                self.write(" of ");
+                self.expr_prec(&thresh.policies, PREC_SIMPLE);
+            }
+            ExprKind::Ident(ident) => self.write(&ident.0),
+            ExprKind::Array(ast::Array(elements)) => {
+                let items = elements.iter().map(|e| ((), e)).collect::<Vec<_>>();
+                self.list(
+                    "[",
+                    "]",
+                    true,
+                    &items,
+                    expr.span.end,
+                    broken,
+                    |p, (_, element)| p.expr(element),
+                );
+            }
+            ExprKind::Map(ast::Map(entries)) => {
+                let items = entries
+                    .iter()
+                    .map(|(key, value)| (Some(key), value))
+                    .collect::<Vec<_>>();
+                self.list(
+                    "{",
+                    "}",
+                    true,
+                    &items,
+                    expr.span.end,
+                    broken,
+                    |p, (key, value)| {
+                        p.write(&map_key(key.expect("map entries have a key")));
+                        p.write(": ");
+                        p.expr(value);
+                    },
+                );
+            }
+            ExprKind::ArrayAccess(access) => {
+                self.expr_restricted(&access.array, is_access_lhs);
+                self.write(".");
+                match &access.index.kind {
+                    ExprKind::String(field) if is_plain_name(field) => self.write(field),
+                    ExprKind::Ident(ident)
+                        if ident.0.starts_with('$') || ident.0.contains("::") =>
+                    {
+                        self.write(&ident.0)
+                    }
+                    ExprKind::Number(_)
+                    | ExprKind::Bytes(_)
+                    | ExprKind::String(_)
+                    | ExprKind::InterpolatedStr(_)
+                    | ExprKind::Call(_)
+                    | ExprKind::Block(_)
+                        if !is_pipe(&access.index.kind) && !is_negative(&access.index.kind) =>
+                    {
+                        self.expr(&access.index)
+                    }
+                    _ => {
+                        self.write("(");
+                        self.expr(&access.index);
+                        self.write(")");
+                    }
+                }
+            }
+            ExprKind::Slice(slice) => {
+                if is_slice_lhs(&slice.value.kind) {
+                    self.expr(&slice.value);
+                } else {
+                    self.write("(");
+                    self.expr(&slice.value);
+                    self.write(")");
+                }
+                self.write("[");
+                if let Some(start) = &slice.start {
+                    self.expr_prec(start, PREC_OR);
+                }
+                self.write("..");
+                if let Some(end) = &slice.end {
+                    self.expr_prec(end, PREC_OR);
+                }
+                self.write("]");
+            }
+            ExprKind::Range(range) => {
+                // Non-associative, so both sides require parenthesis at the same precedence
+                self.expr_prec(&range.start, PREC_OR);
+                self.write("..");
+                self.expr_prec(&range.end, PREC_OR);
+            }
+            ExprKind::ChildDerive(derive) => {
+                self.expr_prec(&derive.parent, PREC_MULTIPLICATIVE);
+                for step in &derive.path {
+                    self.write("/");
+                    match (&step.child.kind, step.hardened) {
+                        (ExprKind::Number(num), true) if *num >= 0 => {
+                            self.write(&format!("{}h", num))
+                        }
+                        (_, true) => {
+                            self.expr_prec(&step.child, PREC_UNARY);
+                            self.write("'");
+                        }
+                        (_, false) => self.expr_prec(&step.child, PREC_UNARY),
+                    }
+                }
+                match derive.wildcard {
+                    Wildcard::None => (),
+                    Wildcard::Unhardened => self.write("/*"),
+                    Wildcard::Hardened => self.write("/*h"),
+                }
+            }
+            ExprKind::ScriptFrag(frag) => self.script_frag(&frag.fragments, expr.span.end, broken),
+            ExprKind::FnExpr(fn_expr) => {
+                let signature = &fn_expr.signature;
+                if signature.params.is_empty() && signature.rest.is_none() {
+                    self.write("||");
+                } else {
+                    self.write("|");
+                    // Default values are limited to unary expressions, see FnExprSignature in the grammar
+                    self.signature(signature, PREC_UNARY);
+                    self.write("|");
+                }
+                self.write(" ");
+                self.expr(&fn_expr.body);
+            }
+            ExprKind::Infix(infix) => match infix.op {
+                InfixOp::Prob => {
+                    self.expr_restricted(&infix.lhs, is_sexpr);
+                    self.write("@");
+                    self.expr_prec(&infix.rhs, PREC_SIMPLE);
+                }
+                op => {
+                    // Left-associative, so only the RHS requires parenthesis at the same precedence
+                    let prec = infix_precedence(op);
+                    self.expr_prec(&infix.lhs, prec);
+                    self.write(" ");
+                    self.write(infix_op(op));
+                    self.write(" ");
+                    self.expr_prec(&infix.rhs, prec + 1);
+                }
+            },
+            ExprKind::Not(ast::Not(inner)) => {
+                self.write("!");
+                self.expr_prec(inner, PREC_UNARY);
+            }
+            ExprKind::Neg(ast::Neg(inner)) => {
+                self.write("-");
+                self.expr_prec(inner, PREC_UNARY);
+            }
+            ExprKind::IfExpr(if_expr) => self.if_expr(if_expr, broken),
+            ExprKind::Match(match_expr) => self.match_expr(match_expr, expr.span.end, broken),
+            ExprKind::Try(try_expr) => {
+                self.write("try ");
+                self.branch_block(&try_expr.body, broken);
+                self.write(" catch ");
+                if let Some(error) = &try_expr.error {
+                    self.write(&error.0);
+                    self.write(" ");
+                }
+                self.branch_block(&try_expr.handler, broken);
+            }
+            ExprKind::Coalesce(coalesce) => {
+                // Right-associative, so only the LHS requires parenthesis at the same precedence
+                self.expr_prec(&coalesce.lhs, PREC_RANGE);
+                self.write(" ?? ");
+                self.expr_prec(&coalesce.rhs, PREC_COALESCE);
+            }
+            ExprKind::InterpolatedStr(ast::InterpolatedStr(parts)) => {
+                let mut quoted = String::from("\"");
+                for part in parts {
+                    match part {
+                        ast::StrPart::Str(s) => quoted.push_str(&escape_str(s)),
+                        ast::StrPart::Expr(expr) => {
+                            quoted.push('{');
+                            quoted.push_str(&self.interpolation(expr));
+                            quoted.push('}');
+                        }
+                    }
+                }
+                quoted.push('"');
+                self.write(&quoted);
+            }
+
+            ExprKind::PubKey(key) | ExprKind::SecKey(key) => self.write(key),
+            ExprKind::Bytes(bytes) => {
+                use miniscript::bitcoin::hashes::hex::ToHex;
+                self.write(&format!("0x{}", bytes.to_hex()))
+            }
+            ExprKind::String(s) => self.write(&quote(s)),
+            ExprKind::Number(num) => self.write(&num.to_string()),
+            ExprKind::Duration(duration) => self.write(&fmt_duration(duration)),
+            ExprKind::DateTime(ast::DateTime(datetime)) => {
+                self.write(&datetime.split_whitespace().collect::<Vec<_>>().join(" "))
+            }
+            ExprKind::BtcAmount(ast::BtcAmount(amount)) => self.write(amount),
+            ExprKind::Value(value) => self.value(value),
+        }
+    }
+
+    /// Print a pre-evaluated value using the syntax that evaluates back into it. Values without one
+    /// (like descriptors and functions) are never folded into the AST, and are printed as displayed.
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Script(script) => {
+                use miniscript::bitcoin::hashes::hex::ToHex;
+                self.write(&format!("rawscript(0x{})", script.as_bytes().to_hex()))
+            }
+            Value::String(s) => self.write(&quote(s)),
+            Value::Array(elements) if elements.is_empty() => self.write("[]"),
+            Value::Array(elements) => {
+                self.write("[ ");
+                for (i, element) in elements.iter().enumerate() {
+                    self.write(iif!(i > 0, ", ", ""));
+                    self.value(element);
+                }
+                self.write(" ]");
+            }
+            Value::Map(entries) if entries.is_empty() => self.write("{}"),
+            Value::Map(entries) => {
+                self.write("{ ");
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    self.write(iif!(i > 0, ", ", ""));
+                    self.write(&map_key(key));
+                    self.write(": ");
+                    self.value(value);
+                }
+                self.write(" }");
+            }
+            value => self.write(&value.to_string()),
+        }
+    }
+
+    /// Print a delimited, comma-separated list. The multi-line layout has each item on its own line.
+    #[allow(clippy::too_many_arguments)]
+    fn list<T>(
+        &mut self,
+        open: &str,
+        close: &str,
+        padded: bool,
+        items: &[(T, &Expr)],
+        end: usize,
+        broken: bool,
+        print_item: impl Fn(&mut Self, &(T, &Expr)),
+    ) {
+        self.write(open);
+        if items.is_empty() && !broken {
+            return self.write(close);
+        }
+        if !broken {
+            if padded {
+                self.write(" ");
+            }
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    self.write(", ");
+                }
+                print_item(self, item);
+            }
+            if padded {
+                self.write(" ");
+            }
+        } else {
+            self.indent += 1;
+            let mut prev_end = None;
+            for (i, item) in items.iter().enumerate() {
+                let span = item.1.span;
+                self.newline();
+                self.leading_comments(prev_end, span.start);
+                print_item(self, item);
+                if i < items.len() - 1 {
+                    self.write(",");
+                }
+                let next_start = items.get(i + 1).map_or(end, |next| next.1.span.start);
+                self.trailing_comment(span.end, next_start);
+                prev_end = Some(span.end);
+            }
+            self.closing_comments(prev_end, end);
+            self.indent -= 1;
+            self.newline();
+        }
+        self.write(close);
+    }
+
+    /// Print a `|>` pipeline, with each stage on its own line when broken
+    fn pipeline(&mut self, expr: &Expr, broken: bool) {
+        // Unwind the left-associative chain of piped calls into its head and stages
+        let mut stages = vec![];
+        let mut head = expr;
+        while let ExprKind::Call(call) = &head.kind {
+            if !call.piped {
+                break;
+            }
+            stages.push((call, head.span.end));
+            head = &call.args[0];
+        }
+        self.expr_prec(head, PREC_COALESCE);
+
+        self.indent += 1;
+        let mut prev_end = head.span.end;
+        for (call, end) in stages.into_iter().rev() {
+            // The stage starts at its `|>`, which is not part of any AST node
+            let start = self.source.and_then(|source| source[prev_end..].find("|>"));
+            let start = start.map_or(prev_end, |offset| prev_end + offset);
+            if broken {
+                self.newline();
+                self.leading_comments(Some(prev_end), start);
+            } else {
+                self.write(" ");
+            }
+            self.write("|> ");
+            let stage = ast::Call {
+                ident: call.ident.clone(),
+                args: call.args[1..].to_vec(),
+                named_args: call.named_args.clone(),
+                piped: false,
+            };
+            let span = ast::Span {
+                start,
+                end,
+                ..expr.span
+            };
+            self.expr(&Expr::new(stage, span));
+            prev_end = end;
+        }
+        self.indent -= 1;
+    }
+
+    /// Print the branches of a policy OR/AND. The multi-line layout has each branch on its own line.
+    fn branches(&mut self, branches: &[Expr], op: &str, min_prec: u8, broken: bool) {
+        self.indent += 1;
+        let mut prev_end = None;
+        for (i, branch) in branches.iter().enumerate() {
+            if i > 0 {
+                if broken {
+                    self.newline();
+                    self.leading_comments(prev_end, branch.span.start);
+                } else {
+                    self.write(" ");
+                }
+                self.write(op);
+                self.write(" ");
+            }
+            self.expr_prec(branch, min_prec);
+            prev_end = Some(branch.span.end);
+        }
+        self.indent -= 1;
+    }
+
+    fn block(&mut self, block: &ast::Block, end: usize, broken: bool) {
+        if !broken {
+            if !block.stmts.is_empty() && !self.inline_stmts {
+                self.inline_failed = true;
+            }
+            self.write("{ ");
+            for stmt in &block.stmts {
+                self.stmt(stmt);
+                self.write(" ");
+            }
+            if let Some(return_value) = &block.return_value {
+                self.expr(return_value);
+            }
+            self.write(" }");
+        } else {
+            self.write("{");
+            self.indent += 1;
+            self.newline();
+            let end = self.block_end(end);
+            self.body(&block.stmts, block.return_value.as_deref(), end);
+            self.indent -= 1;
+            self.newline();
+            self.write("}");
+        }
+    }
+
+    /// Print an if/else branch, which is always a block expression when parsed
+    fn branch_block(&mut self, expr: &Expr, broken: bool) {
+        match &expr.kind {
+            ExprKind::Block(block) => self.block(block, expr.span.end, broken),
+            _ => {
+                self.write("{ ");
+                self.expr(expr);
+                self.write(" }");
+            }
+        }
+    }
+
+    fn if_expr(&mut self, if_expr: &ast::IfExpr, broken: bool) {
+        self.write("if ");
+        self.expr(&if_expr.condition);
+        self.write(" ");
+        self.branch_block(&if_expr.then_val, broken);
+        self.write(" else ");
+        match &if_expr.else_val.kind {
+            ExprKind::IfExpr(else_if) => self.if_expr(else_if, broken),
+            _ => self.branch_block(&if_expr.else_val, broken),
+        }
+    }
+
+    /// Print a match expression. The multi-line layout has each arm on its own line, followed by a comma.
+    fn match_expr(&mut self, match_expr: &ast::Match, end: usize, broken: bool) {
+        let arms = &match_expr.arms;
+        self.write("match ");
+        self.expr(&match_expr.value);
+        self.write(" {");
+        if !broken {
+            for (i, arm) in arms.iter().enumerate() {
+                self.write(iif!(i > 0, ", ", " "));
+                self.match_arm(arm);
+            }
+            return self.write(iif!(arms.is_empty(), "}", " }"));
+        }
+        self.indent += 1;
+        let mut prev_end = None;
+        for (i, arm) in arms.iter().enumerate() {
+            self.newline();
+            self.leading_comments(prev_end, arm.span.start);
+            self.match_arm(arm);
+            self.write(",");
+            let next_start = arms.get(i + 1).map_or(end, |next| next.span.start);
+            self.trailing_comment(arm.span.end, next_start);
+            prev_end = Some(arm.span.end);
+        }
+        self.closing_comments(prev_end, end);
+        self.indent -= 1;
+        self.newline();
+        self.write("}");
+    }
+
+    fn match_arm(&mut self, arm: &ast::MatchArm) {
+        self.match_pattern(&arm.pattern);
+        if let Some(guard) = &arm.guard {
+            self.write(" if ");
+            self.expr(guard);
+        }
+        self.write(" => ");
+        self.expr(&arm.body);
+    }
+
+    fn match_pattern(&mut self, pattern: &ast::MatchPattern) {
+        match pattern {
+            ast::MatchPattern::Wildcard => self.write("_"),
+            ast::MatchPattern::Bind(ident) => self.write(&ident.0),
+            ast::MatchPattern::Literal(literal) => self.expr(literal),
+            ast::MatchPattern::Type(ty, inner) => {
+                self.write(&format!("{}(", ty));
+                self.match_pattern(inner);
+                self.write(")");
+            }
+            ast::MatchPattern::Array { elements, rest } => {
+                self.write("[");
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        self.write(", ");
+                    }
+                    self.match_pattern(element);
+                }
+                if let Some(rest) = rest {
+                    if !elements.is_empty() {
+                        self.write(", ");
+                    }
+                    self.write("...");
+                    self.write(&rest.0);
+                }
+                self.write("]");
+            }
+        }
+    }
+
+    /// Print a script fragment. The multi-line layout keeps the line breaks between the fragment
+    /// parts from the source, and adds more as needed to fit the line width.
+    fn script_frag(&mut self, parts: &[Expr], end: usize, broken: bool) {
+        if !broken {
+            self.write("`");
+            for (i, part) in parts.iter().enumerate() {
+                if i > 0 {
+                    if self.line_break_between(parts[i - 1].span.end, part.span.start) {
+                        self.inline_failed = true;
+                    }
+                    self.write(" ");
+                }
+                self.script_frag_part(part);
+            }
+            return self.write("`");
+        }
+
+        self.write("`");
+        self.indent += 1;
+        let mut prev_end = None;
+        let mut had_comment = false;
+        for (i, part) in parts.iter().enumerate() {
+            let span = part.span;
+            let line_break = i == 0
+                || had_comment
+                || self.line_break_between(prev_end.unwrap_or(0), span.start)
+                || self.comment_before(span.start).is_some()
+                || !matches!(
+                    self.try_inline(span.start, span.end, |p| p.script_frag_part(part)),
+                    Some(inline) if self.col() + 1 + inline.chars().count() <= MAX_WIDTH
+                );
+            if line_break {
+                self.newline();
+                self.leading_comments(prev_end, span.start);
+            } else {
+                self.write(" ");
+            }
+            self.script_frag_part(part);
+            let next_start = parts.get(i + 1).map_or(end, |next| next.span.start);
+            had_comment = self.trailing_comment(span.end, next_start);
+            prev_end = Some(span.end);
+        }
+        self.closing_comments(prev_end, end);
+        self.indent -= 1;
+        self.newline();
+        self.write("`");
+    }
+
+    fn script_frag_part(&mut self, part: &Expr) {
+        match part.kind {
+            ExprKind::Ident(_)
+            | ExprKind::Number(_)
+            | ExprKind::Call(_)
+            | ExprKind::Block(_)
+            | ExprKind::IfExpr(_)
+            | ExprKind::ArrayAccess(_)
+            | ExprKind::PubKey(_)
+            | ExprKind::SecKey(_)
+            | ExprKind::Bytes(_)
+            | ExprKind::String(_)
+            | ExprKind::InterpolatedStr(_)
+            | ExprKind::Value(_)
+                if !is_compound_value(&part.kind) && !is_pipe(&part.kind) =>
+            {
+                self.expr(part)
+            }
+            // Other expressions have to be wrapped in angle brackets
+            _ => {
+                self.write("<");
+                self.expr_prec(part, PREC_SIMPLE);
+                self.write(">");
+            }
+        }
+    }
+
+    /// Render an interpolated string expression, always on a single line
+    fn interpolation(&self, expr: &Expr) -> String {
+        let mut printer = self.inline_printer();
+        printer.inline_stmts = true;
+        match &expr.kind {
+            // Interpolated code is parsed as a program, but printed without the block braces
+            ExprKind::Block(block) => {
+                for (i, stmt) in block.stmts.iter().enumerate() {
+                    if i > 0 {
+                        printer.write(" ");
+                    }
+                    printer.stmt(stmt);
+                }
+                if let Some(return_value) = &block.return_value {
+                    if !block.stmts.is_empty() {
+                        printer.write(" ");
+                    }
+                    printer.expr(return_value);
+                }
+            }
+            _ => printer.expr(expr),
+        }
+        printer.out
+    }
+}
+
+fn precedence(expr: &Expr) -> u8 {
+    match &expr.kind {
+        ExprKind::FnExpr(_) => PREC_LOWEST,
+        kind if is_compound_value(kind) => PREC_LOWEST,
+        kind if is_pipe(kind) => PREC_PIPE,
+        ExprKind::Coalesce(_) => PREC_COALESCE,
+        ExprKind::Range(_) => PREC_RANGE,
+        ExprKind::Or(_) => PREC_OR,
+        ExprKind::And(_) => PREC_AND,
+        ExprKind::Infix(infix) => infix_precedence(infix.op),
+        ExprKind::ChildDerive(_) => PREC_MULTIPLICATIVE,
+        ExprKind::Not(_) | ExprKind::Neg(_) => PREC_UNARY,
+        kind if is_negative(kind) => PREC_UNARY,
+        _ => PREC_SIMPLE,
+    }
+}
+
+fn infix_precedence(op: InfixOp) -> u8 {
+    use InfixOp::*;
+    match op {
+        Eq | NotEq | Gt | Lt | Gte | Lte => PREC_COMPARISON,
+        BitOr => 7,
+        BitXor => 8,
+        BitAnd => 9,
+        Shl | Shr => 10,
+        Add | Subtract => 11,
+        Multiply | Mod => PREC_MULTIPLICATIVE,
+        // Binds tighter than all other operators, see InfixProb in the grammar
+        Prob => PREC_SIMPLE,
+    }
+}
+
+fn infix_op(op: InfixOp) -> &'static str {
+    use InfixOp::*;
+    match op {
+        Add => "+",
+        Subtract => "-",
+        Eq => "==",
+        NotEq => "!=",
+        Gt => ">",
+        Lt => "<",
+        Gte => ">=",
+        Lte => "<=",
+        Prob => "@",
+        Multiply => "*",
+        Mod => "%",
+        Shl => "<<",
+        Shr => ">>",
+        BitAnd => "&",
+        BitOr => "|",
+        BitXor => "^",
+    }
+}
+
+/// Expressions accepted by the grammar as thresh() thresholds and probability weights (`SExpr`)
+fn is_sexpr(kind: &ExprKind) -> bool {
+    matches!(
+        kind,
+        ExprKind::Number(_)
+            | ExprKind::Ident(_)
+            | ExprKind::Bytes(_)
+            | ExprKind::String(_)
+            | ExprKind::InterpolatedStr(_)
+            | ExprKind::Call(_)
+            | ExprKind::Block(_)
+    ) && !is_pipe(kind)
+        || matches!(kind, ExprKind::Value(_) if !is_compound_value(kind))
+}
+
+/// Expressions accepted by the grammar on the left-hand side of an array access (`ArrayAccessLHS`)
+fn is_access_lhs(kind: &ExprKind) -> bool {
+    matches!(
+        kind,
+        ExprKind::Ident(_)
+            | ExprKind::Call(_)
+            | ExprKind::Array(_)
+            | ExprKind::Map(_)
+            | ExprKind::Block(_)
+            | ExprKind::Bytes(_)
+            | ExprKind::String(_)
+            | ExprKind::InterpolatedStr(_)
+            | ExprKind::ArrayAccess(_)
+    ) && !is_pipe(kind)
+        || matches!(kind, ExprKind::Value(_) if !is_compound_value(kind))
+}
+
+/// Expressions accepted by the grammar on the left-hand side of a slice (`SliceLHS`),
+/// other than parenthesized expressions
+fn is_slice_lhs(kind: &ExprKind) -> bool {
+    matches!(
+        kind,
+        ExprKind::Ident(_)
+            | ExprKind::Call(_)
+            | ExprKind::Array(_)
+            | ExprKind::Block(_)
+            | ExprKind::Bytes(_)
+            | ExprKind::ArrayAccess(_)
+            | ExprKind::Slice(_)
+    ) && !is_pipe(kind)
+        || matches!(kind, ExprKind::Value(value) if matches!(**value, Value::Array(_) | Value::Bytes(_)))
+}
+
+/// Values printed using a syntax that requires parenthesis where simple expressions are expected
+fn is_compound_value(kind: &ExprKind) -> bool {
+    match kind {
+        ExprKind::Value(value) => matches!(**value, Value::WithProb(..)),
+        _ => false,
+    }
+}
+
+/// Negative numbers, which are parsed back as a negation of the number
+fn is_negative(kind: &ExprKind) -> bool {
+    matches!(kind, ExprKind::Number(num) if *num < 0)
+}
+
+/// Calls using the pipeline operator, which binds looser than all other operators
+fn is_pipe(kind: &ExprKind) -> bool {
+    matches!(kind, ExprKind::Call(call) if call.piped)
+}
+
+/// Check whether the name can be used as a map key or field without quoting
+fn is_plain_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+        && name.len() <= 39
+        && !KEYWORDS.contains(&name)
+}
+
+fn map_key(key: &str) -> String {
+    iif!(is_plain_name(key), key.to_string(), quote(key))
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", escape_str(s))
+}
+
+/// Escape a string for use within a string literal (the inverse of ast::unescape_str)
+fn escape_str(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            // Empty `{}` are kept as-is for use as format() placeholders, other braces are escaped
+            '{' if chars.peek() == Some(&'}') => {
+                chars.next();
+                escaped.push_str("{}");
+            }
+            '{' => escaped.push_str("\\{"),
+            '}' => escaped.push_str("\\}"),
+            ch => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+fn fmt_duration(duration: &ast::Duration) -> String {
+    use ast::DurationPart::*;
+    let unit = |num: f64, unit: &str| {
+        iif!(
+            num == 1.0,
+            format!("1 {}", unit),
+            format!("{} {}s", num, unit)
+        )
+    };
+    match duration {
+        ast::Duration::BlockHeight(blocks) => unit(*blocks as f64, "block"),
+        ast::Duration::BlockTime { parts, heightwise } => {
+            let parts = parts.iter().map(|part| match *part {
+                Years(n) => unit(n, "year"),
+                Months(n) => unit(n, "month"),
+                Weeks(n) => unit(n, "week"),
+                Days(n) => unit(n, "day"),
+                Hours(n) => unit(n, "hour"),
+                Minutes(n) => unit(n, "minute"),
+                Seconds(n) => unit(n, "second"),
+            });
+            let parts = parts.collect::<Vec<_>>().join(" ");
+            iif!(*heightwise, format!("heightwise {}", parts), parts)
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut printer = Printer::new(None);
+        printer.expr(self);
+        f.write_str(&printer.out)
+    }
+}
+
+impl fmt::Display for Stmt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut printer = Printer::new(None);
+        printer.stmt(self);
+        f.write_str(&printer.out)
+    }
+}
+
+/// Displayed as the parameters list of a function definition, without the surrounding parentheses
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut printer = Printer::new(None);
+        printer.signature(self, PREC_LOWEST);
+        f.write_str(&printer.out)
+    }
+}
+
+impl fmt::Display for ast::Library {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut printer = Printer::new(None);
+        printer.program(&self.stmts, None);
+        f.write_str(&printer.out)
+    }
+}
@@ -1,29 +1,33 @@
 use std::borrow::Borrow;
+use std::collections::BTreeMap;
 use std::convert::{TryFrom, TryInto};
 use std::fmt;
 use std::str::FromStr;
 
 use bitcoin::blockdata::script::Builder as ScriptBuilder;
 use bitcoin::hashes::{self, hex::ToHex, sha256, Hash};
-use bitcoin::util::bip32::DerivationPath;
+use bitcoin::util::bip32::{ChildNumber, DerivationPath};
 use bitcoin::{Address, Network, Script};
 use miniscript::bitcoin;
-use miniscript::descriptor::DescriptorPublicKey;
+use miniscript::descriptor::{DescriptorPublicKey, DescriptorSecretKey, DescriptorSinglePriv};
 
-use crate::ast::{self, Expr, Stmt};
+use crate::ast::{self, Expr, ExprKind, SourceId, Stmt, StmtKind};
 use crate::function::{Call, Function};
-use crate::util::{self, DeriveExt, DescriptorExt, MiniscriptExt, EC};
-use crate::{stdlib, time, Descriptor, Error, Miniscript, Policy, Result, Scope};
+use crate::util::{self, DeriveExt, DescriptorExt, MiniscriptExt, EC, EC_SIGN};
+use crate::{stdlib, time, Descriptor, Error, Miniscript, Policy, Result, Scope, Type};
 
 /// A runtime value. This is what gets passed around as function arguments, returned from functions,
 /// and assigned to variables.
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     PubKey(DescriptorPublicKey),
+    SecKey(SecKey),
     Bytes(Vec<u8>),
+    String(String),
     Number(i64),
     Bool(bool),
     Network(Network),
+    Null,
 
     Policy(Policy),
     WithProb(usize, Box<Value>),
@@ -35,16 +39,20 @@ pub enum Value {
 
     Function(Function),
     Array(Vec<Value>),
+    Map(BTreeMap<String, Value>),
 }
 
 impl_from_variant!(Policy, Value);
 impl_from_variant!(Miniscript, Value);
 impl_from_variant!(Descriptor, Value);
 impl_from_variant!(DescriptorPublicKey, Value, PubKey);
+impl_from_variant!(SecKey, Value);
 impl_from_variant!(Script, Value);
 impl_from_variant!(Address, Value);
 impl_from_variant!(Vec<Value>, Value, Array);
+impl_from_variant!(BTreeMap<String, Value>, Value, Map);
 impl_from_variant!(Vec<u8>, Value, Bytes);
+impl_from_variant!(String, Value);
 impl_from_variant!(Network, Value);
 impl_from_variant!(i64, Value, Number);
 impl_from_variant!(bool, Value, Bool);
@@ -53,6 +61,79 @@ impl From<usize> for Value {
         Value::Number(num.try_into().unwrap())
     }
 }
+/// A private key (xprv/tprv or WIF), converted to its public counterpart wherever a PubKey is expected.
+/// Its Display and Debug never reveal the private key, use `revealSecKey()` to print it.
+pub struct SecKey(pub DescriptorSecretKey);
+
+impl SecKey {
+    /// Parse a private key, rejecting mainnet keys unless the mainnet network was explicitly requested
+    pub fn parse(s: &str, network: Option<Network>) -> Result<Self> {
+        let seckey = SecKey(s.parse()?);
+        match (seckey.network(), network) {
+            (Network::Bitcoin, Some(Network::Bitcoin)) => Ok(seckey),
+            (Network::Bitcoin, _) => bail!(Error::MainnetSecKey),
+            (_, Some(Network::Bitcoin)) => bail!(Error::SecKeyNetworkMismatch(
+                seckey.network(),
+                Network::Bitcoin
+            )),
+            // Testnet, signet and regtest share the same private key encoding
+            (_, _) => Ok(seckey),
+        }
+    }
+
+    pub fn network(&self) -> Network {
+        match &self.0 {
+            DescriptorSecretKey::SinglePriv(sk) => sk.key.network,
+            DescriptorSecretKey::XPrv(xprv) => xprv.xkey.network,
+        }
+    }
+
+    pub fn to_public(&self) -> Result<DescriptorPublicKey> {
+        Ok(self.0.as_public(&EC_SIGN)?)
+    }
+
+    /// Get the encoded private key. This is the only way to get it out.
+    pub fn reveal(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+impl Clone for SecKey {
+    fn clone(&self) -> Self {
+        SecKey(match &self.0 {
+            DescriptorSecretKey::SinglePriv(sk) => {
+                DescriptorSecretKey::SinglePriv(DescriptorSinglePriv {
+                    origin: sk.origin.clone(),
+                    key: sk.key,
+                })
+            }
+            DescriptorSecretKey::XPrv(xprv) => DescriptorSecretKey::XPrv(xprv.clone()),
+        })
+    }
+}
+
+impl PartialEq for SecKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.reveal() == other.reveal()
+    }
+}
+
+// Displayed using the public counterpart, with the private key redacted
+impl fmt::Display for SecKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.to_public() {
+            Ok(pubkey) => write!(f, "SecKey({})", pubkey),
+            Err(_) => write!(f, "SecKey(<redacted>)"),
+        }
+    }
+}
+
+impl fmt::Debug for SecKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
 impl<T: Into<Function>> From<T> for Value {
     fn from(f: T) -> Self {
         Value::Function(f.into())
@@ -73,31 +154,146 @@ impl Execute for ast::Assign {
     fn exec(&self, scope: &mut Scope) -> Result<()> {
         for assignment in &self.0 {
             let value = assignment.rhs.eval(scope)?;
-            scope.set(assignment.lhs.clone(), value)?;
+            if let Some(ty) = assignment.ty {
+                ty.ensure(&value)
+                    .map_err(|e| e.with_span(assignment.rhs.span))?;
+            }
+            assignment.lhs.bind(value, scope)?;
         }
         Ok(())
     }
 }
 
+impl ast::Pattern {
+    /// Bind the value to the pattern's identifiers in the given scope, destructuring arrays as needed
+    pub fn bind(&self, value: Value, scope: &mut Scope) -> Result<()> {
+        match self {
+            ast::Pattern::Ident(ident) => scope.set(ident.clone(), value),
+            ast::Pattern::Array { elements, rest } => {
+                let mut values = value.into_array()?;
+                let has_rest = rest.is_some();
+                ensure!(
+                    values.len() == elements.len() || (has_rest && values.len() >= elements.len()),
+                    Error::PatternMismatch(elements.len(), has_rest, values.len())
+                );
+                let rest_values = values.split_off(elements.len());
+                for (pattern, value) in elements.iter().zip(values) {
+                    pattern.bind(value, scope)?;
+                }
+                if let Some(rest) = rest {
+                    scope.set(rest.clone(), rest_values)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ast::MatchPattern {
+    /// Check whether the value matches the pattern, binding its identifiers in the given scope if it does.
+    /// Literals are evaluated in the scope.
+    pub fn matches(&self, value: &Value, scope: &mut Scope) -> Result<bool> {
+        Ok(match self {
+            ast::MatchPattern::Wildcard => true,
+            ast::MatchPattern::Bind(ident) => {
+                scope.set(ident.clone(), value.clone())?;
+                true
+            }
+            ast::MatchPattern::Literal(literal) => literal.eval(scope)? == *value,
+            ast::MatchPattern::Type(ty, inner) => {
+                ty.contains(Type::of(value)) && inner.matches(value, scope)?
+            }
+            ast::MatchPattern::Array { elements, rest } => {
+                let values = match value {
+                    Value::Array(values) => values,
+                    _ => return Ok(false),
+                };
+                let len_matches = match rest {
+                    Some(_) => values.len() >= elements.len(),
+                    None => values.len() == elements.len(),
+                };
+                if !len_matches {
+                    return Ok(false);
+                }
+                for (pattern, value) in elements.iter().zip(values) {
+                    if !pattern.matches(value, scope)? {
+                        return Ok(false);
+                    }
+                }
+                match rest {
+                    Some(rest) if rest.0 != "_" => {
+                        let rest_values = values[elements.len()..].to_vec();
+                        scope.alloc(rest_values.len())?;
+                        scope.set(rest.clone(), rest_values)?;
+                    }
+                    _ => (),
+                }
+                true
+            }
+        })
+    }
+}
+
 impl Execute for ast::FnDef {
     fn exec(&self, scope: &mut Scope) -> Result<()> {
-        let func = Function::from(self.clone());
+        let func = Function::from_def(self.clone(), scope);
         scope.set(self.ident.clone(), func)
     }
 }
 
 impl Execute for Stmt {
     fn exec(&self, scope: &mut Scope) -> Result<()> {
-        match self {
-            Stmt::FnDef(x) => x.exec(scope),
-            Stmt::Assign(x) => x.exec(scope),
+        match &self.kind {
+            StmtKind::FnDef(x) => x.exec(scope),
+            StmtKind::Assign(x) => x.exec(scope),
+            StmtKind::Import(x) => exec_import(x, self.span.source, scope),
+            StmtKind::ExprStmt(ast::ExprStmt(x)) => x.eval(scope).map(|_| ()),
+            // Tests are only run by the test runner
+            StmtKind::Test(_) => Ok(()),
         }
+        .map_err(|e| e.with_span(self.span))
     }
 }
 
+/// Import the library and bring its definitions into scope, namespaced under the alias if one is given.
+/// Relative paths are resolved against the file containing the import statement, identified by its `source`.
+fn exec_import(import: &ast::Import, source: SourceId, scope: &mut Scope) -> Result<()> {
+    let importer = scope.importer().ok_or(Error::ImportUnavailable)?.clone();
+    // Libraries can only see the built-ins, but are evaluated under the importing program's budget and output
+    let root = scope.top().child_for_caller(scope);
+    let module = importer
+        .import(&import.path, source, &root)
+        .map_err(|e| Error::ImportError(import.path.clone(), e.into()))?;
+
+    for (ident, value) in module.iter() {
+        let ident = match &import.alias {
+            Some(alias) => ast::Ident(format!("{}::{}", alias, ident)),
+            None => ident.clone(),
+        };
+        scope.set(ident, value.clone())?;
+    }
+    Ok(())
+}
+
 impl Evaluate for ast::Call {
     fn eval(&self, scope: &Scope) -> Result<Value> {
-        call_exprs(scope, &self.ident, &self.args)
+        if self.named_args.is_empty() {
+            return call_exprs(scope, &self.ident, &self.args);
+        }
+
+        let args = eval_exprs(scope, &self.args)?;
+        let named_args = self
+            .named_args
+            .iter()
+            .map(|(name, expr)| Ok((name.clone(), expr.eval(scope)?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let func = scope
+            .get(&self.ident)
+            .ok_or_else(|| Error::FnNotFound(self.ident.clone()))?;
+        func.into_fn()
+            .and_then(|func| func.call_named(args, named_args, scope))
+            .map_err(|e| Error::CallError(self.ident.clone(), e.into()))
     }
 }
 
@@ -125,7 +321,11 @@ fn eval_andor(
     let first_operand = operands[0].eval(scope)?;
     match &first_operand {
         Value::Bool(_) => eval_bool_andor(first_operand, &operands[1..], scope, bool_stop_on),
-        Value::Policy(_) | Value::WithProb(_, _) | Value::PubKey(_) | Value::Array(_) => {
+        Value::Policy(_)
+        | Value::WithProb(_, _)
+        | Value::PubKey(_)
+        | Value::SecKey(_)
+        | Value::Array(_) => {
             eval_policy_andor(desc_op, desc_thresh_n, first_operand, &operands[1..], scope)
         }
         _ => Err(Error::InvalidArguments),
@@ -181,22 +381,44 @@ impl Evaluate for ast::Ident {
     fn eval(&self, scope: &Scope) -> Result<Value> {
         scope
             .get(&self)
-            .cloned()
             .ok_or_else(|| Error::VarNotFound(self.clone()))
     }
 }
 
 impl Evaluate for ast::Array {
     fn eval(&self, scope: &Scope) -> Result<Value> {
+        scope.alloc(self.0.len())?;
         let elements = eval_exprs(scope, &self.0)?;
         Ok(elements.into())
     }
 }
 
+impl Evaluate for ast::Map {
+    fn eval(&self, scope: &Scope) -> Result<Value> {
+        scope.alloc(self.0.len())?;
+        let mut map = BTreeMap::new();
+        for (key, expr) in &self.0 {
+            ensure!(!map.contains_key(key), Error::MapKeyExists(key.clone()));
+            map.insert(key.clone(), expr.eval(scope)?);
+        }
+        Ok(map.into())
+    }
+}
+
 impl Evaluate for ast::ArrayAccess {
     fn eval(&self, scope: &Scope) -> Result<Value> {
         let value = self.array.eval(scope)?;
-        let index = self.index.eval(scope)?.into_usize()?;
+        let index = self.index.eval(scope)?;
+
+        // Maps are indexed by their String keys, arrays and bytes by a Number
+        if let Value::Map(mut map) = value {
+            let key = index.into_string()?;
+            return map.remove(&key).ok_or(Error::MapKeyNotFound(key));
+        }
+        // Field names (`$arr.name`) are parsed into String indexes, which only apply to maps
+        ensure!(!matches!(index, Value::String(_)), Error::NotMap(value));
+
+        let index = index.into_usize()?;
         Ok(match value {
             Value::Array(mut elements) => {
                 ensure!(index < elements.len(), Error::ArrayIndexOutOfRange);
@@ -211,42 +433,115 @@ impl Evaluate for ast::ArrayAccess {
     }
 }
 
+impl Evaluate for ast::Slice {
+    fn eval(&self, scope: &Scope) -> Result<Value> {
+        let value = self.value.eval(scope)?;
+        let eval_bound = |bound: &Option<Box<Expr>>| match bound {
+            Some(bound) => bound.eval(scope)?.into_i64().map(Some),
+            None => Ok(None),
+        };
+        let (start, end) = (eval_bound(&self.start)?, eval_bound(&self.end)?);
+
+        Ok(match value {
+            Value::Array(mut elements) => {
+                let range = slice_range(start, end, elements.len())?;
+                scope.alloc(range.len())?;
+                elements.truncate(range.end);
+                elements.split_off(range.start).into()
+            }
+            Value::Bytes(bytes) => {
+                let range = slice_range(start, end, bytes.len())?;
+                scope.alloc(range.len())?;
+                bytes[range].to_vec().into()
+            }
+            other => bail!(Error::NotArray(other)),
+        })
+    }
+}
+
+/// Resolve the slice bounds into a range of indexes, with negative bounds counted back from the end
+fn slice_range(start: Option<i64>, end: Option<i64>, len: usize) -> Result<std::ops::Range<usize>> {
+    let resolve = |bound: i64| match bound {
+        bound if bound < 0 => len.checked_sub(usize::try_from(bound.checked_neg()?).ok()?),
+        bound => usize::try_from(bound).ok(),
+    };
+    match (
+        start.map_or(Some(0), resolve),
+        end.map_or(Some(len), resolve),
+    ) {
+        (Some(from), Some(to)) if from <= to && to <= len => Ok(from..to),
+        _ => Err(Error::SliceOutOfRange(start, end, len)),
+    }
+}
+
+impl Evaluate for ast::Range {
+    fn eval(&self, scope: &Scope) -> Result<Value> {
+        let start = self.start.eval(scope)?.into_i64()?;
+        let end = self.end.eval(scope)?.into_i64()?;
+        stdlib::array::exclusive_range(start, end, scope)
+    }
+}
+
 impl Evaluate for ast::ChildDerive {
     fn eval(&self, scope: &Scope) -> Result<Value> {
         let parent = self.parent.eval(scope)?;
 
+        // Numeric division, evaluated left-to-right
+        if let Value::Number(mut num) = parent {
+            ensure!(
+                self.wildcard == ast::Wildcard::None && !self.path.iter().any(|s| s.hardened),
+                Error::InvalidArguments
+            );
+            for step in &self.path {
+                let divisor = step.child.eval(scope)?.into_i64()?;
+                ensure!(divisor != 0, Error::DivideByZero);
+                num = num.checked_div(divisor).ok_or(Error::Overflow)?;
+            }
+            return Ok(num.into());
+        }
+
         let mut path = DerivationPath::master();
 
+        let child_number = |index: u32, hardened: bool| -> Result<ChildNumber> {
+            Ok(iif!(
+                hardened,
+                ChildNumber::from_hardened_idx(index)?,
+                ChildNumber::from_normal_idx(index)?
+            ))
+        };
+
         // Support deriving with a hash as the derivation path, using the hash_to_child_vec() conversion
-        if self.path.len() == 1 {
-            let child = self.path[0].eval(scope)?;
+        if self.path.len() == 1 && !self.path[0].hardened {
+            let child = self.path[0].child.eval(scope)?;
             if child.is_bytes() {
                 let hash: sha256::Hash = child.try_into()?;
                 path = util::hash_to_child_vec(hash).into();
             } else {
-                path = path.into_child(child.into_u32()?.into());
+                path = path.into_child(child_number(child.into_u32()?, false)?);
             }
         } else {
-            for child in &self.path {
-                let child = child.eval(scope)?.into_u32()?;
-                path = path.into_child(child.into());
+            for step in &self.path {
+                let index = step.child.eval(scope)?.into_u32()?;
+                path = path.into_child(child_number(index, step.hardened)?);
             }
         }
 
-        parent.derive_path(path, self.is_wildcard)
+        parent.derive_path(path, self.wildcard)
     }
 }
 
 impl Evaluate for ast::FnExpr {
-    fn eval(&self, _scope: &Scope) -> Result<Value> {
-        Ok(Function::from(self.clone()).into())
+    fn eval(&self, scope: &Scope) -> Result<Value> {
+        Ok(Function::from_expr(self.clone(), scope).into())
     }
 }
 
 impl Evaluate for ast::ScriptFrag {
     fn eval(&self, scope: &Scope) -> Result<Value> {
         let frags = eval_exprs(scope, &self.fragments)?;
-        Ok(script_frag(Value::Array(frags))?.into())
+        let script = script_frag(Value::Array(frags))?;
+        scope.alloc(script.len())?;
+        Ok(script.into())
     }
 }
 
@@ -261,10 +556,14 @@ fn script_frag(value: Value) -> Result<Script> {
         Value::Number(n) => ScriptBuilder::new().push_int(n).into_script(),
         Value::Bool(val) => ScriptBuilder::new().push_int(val as i64).into_script(),
         Value::Bytes(bytes) => ScriptBuilder::new().push_slice(&bytes).into_script(),
+        Value::String(string) => ScriptBuilder::new()
+            .push_slice(string.as_bytes())
+            .into_script(),
         Value::PubKey(desc_pubkey) => {
             let pubkey = desc_pubkey.derive_public_key(&EC)?;
             ScriptBuilder::new().push_key(&pubkey).into_script()
         }
+        Value::SecKey(seckey) => script_frag(seckey.to_public()?.into())?,
 
         // Flatten arrays
         Value::Array(elements) => {
@@ -288,11 +587,97 @@ impl Evaluate for ast::Not {
     }
 }
 
+impl Evaluate for ast::Coalesce {
+    fn eval(&self, scope: &Scope) -> Result<Value> {
+        match self.lhs.eval(scope)? {
+            Value::Null => self.rhs.eval(scope),
+            value => Ok(value),
+        }
+    }
+}
+
+impl Evaluate for ast::Neg {
+    fn eval(&self, scope: &Scope) -> Result<Value> {
+        let num = self.0.eval(scope)?.into_i64()?;
+        Ok(num.checked_neg().ok_or(Error::Overflow)?.into())
+    }
+}
+
+impl Evaluate for ast::IfExpr {
+    fn eval(&self, scope: &Scope) -> Result<Value> {
+        if self.condition.eval(scope)?.into_bool()? {
+            self.then_val.eval(scope)
+        } else {
+            self.else_val.eval(scope)
+        }
+    }
+}
+
+impl Evaluate for ast::Match {
+    fn eval(&self, scope: &Scope) -> Result<Value> {
+        let value = self.value.eval(scope)?;
+        for arm in &self.arms {
+            // Each arm is tried in its own child scope, holding the bindings of its pattern
+            let mut arm_scope = scope.child();
+            if !arm.pattern.matches(&value, &mut arm_scope)? {
+                continue;
+            }
+            if let Some(guard) = &arm.guard {
+                if !guard.eval(&arm_scope)?.into_bool()? {
+                    continue;
+                }
+            }
+            return arm.body.eval(&arm_scope);
+        }
+        Err(Error::NoMatchingArm(value))
+    }
+}
+
+impl Evaluate for ast::Try {
+    fn eval(&self, scope: &Scope) -> Result<Value> {
+        match self.body.eval(scope) {
+            Err(err) if err.is_catchable() => {
+                // The handler is evaluated in a child scope holding the caught error
+                let mut handler_scope = scope.child();
+                if let Some(ident) = &self.error {
+                    handler_scope.set(ident.clone(), err.into_value())?;
+                }
+                self.handler.eval(&handler_scope)
+            }
+            result => result,
+        }
+    }
+}
+
+impl Evaluate for ast::InterpolatedStr {
+    fn eval(&self, scope: &Scope) -> Result<Value> {
+        let mut string = String::new();
+        for part in &self.0 {
+            match part {
+                ast::StrPart::Str(s) => string.push_str(s),
+                ast::StrPart::Expr(expr) => string.push_str(&expr.eval(scope)?.to_string()),
+            }
+        }
+        scope.alloc(string.len())?;
+        Ok(string.into())
+    }
+}
+
 impl Evaluate for ast::Infix {
     fn eval(&self, scope: &Scope) -> Result<Value> {
-        self.op
+        let result = self
+            .op
             .apply(self.lhs.eval(scope)?, self.rhs.eval(scope)?)
-            .map_err(|e| Error::OpError(self.op, e.into()))
+            .map_err(|e| Error::OpError(self.op, e.into()))?;
+
+        // Account for the arrays, bytes and strings constructed by concatenation
+        match &result {
+            Value::Array(array) => scope.alloc(array.len())?,
+            Value::Bytes(bytes) => scope.alloc(bytes.len())?,
+            Value::String(string) => scope.alloc(string.len())?,
+            _ => (),
+        }
+        Ok(result)
     }
 }
 
@@ -305,22 +690,50 @@ impl ast::InfixOp {
             // == != for all types
             (Eq, a, b) => (a == b).into(),
             (NotEq, a, b) => (a != b).into(),
-            // < > <= >= for numbers only
+            // < > <= >= for numbers
             (Gt, Number(a), Number(b)) => (a > b).into(),
             (Lt, Number(a), Number(b)) => (a < b).into(),
             (Gte, Number(a), Number(b)) => (a >= b).into(),
             (Lte, Number(a), Number(b)) => (a <= b).into(),
-            // + - for numbers
+            // < > <= >= for bytes and strings, compared lexicographically (i.e. for sorting keys)
+            (Gt, Bytes(a), Bytes(b)) => (a > b).into(),
+            (Lt, Bytes(a), Bytes(b)) => (a < b).into(),
+            (Gte, Bytes(a), Bytes(b)) => (a >= b).into(),
+            (Lte, Bytes(a), Bytes(b)) => (a <= b).into(),
+            (Gt, String(a), String(b)) => (a > b).into(),
+            (Lt, String(a), String(b)) => (a < b).into(),
+            (Gte, String(a), String(b)) => (a >= b).into(),
+            (Lte, String(a), String(b)) => (a <= b).into(),
+            // + - * % for numbers (division is handled by ChildDerive)
             (Add, Number(a), Number(b)) => a.checked_add(b).ok_or(Error::Overflow)?.into(),
             (Subtract, Number(a), Number(b)) => a.checked_sub(b).ok_or(Error::Overflow)?.into(),
+            (Multiply, Number(a), Number(b)) => a.checked_mul(b).ok_or(Error::Overflow)?.into(),
+            (Mod, Number(_), Number(0)) => bail!(Error::DivideByZero),
+            (Mod, Number(a), Number(b)) => a.checked_rem(b).ok_or(Error::Overflow)?.into(),
+            // << >> & | ^ for numbers
+            (Shl, Number(a), Number(b)) => {
+                let shifted = a.checked_shl(b.try_into()?).ok_or(Error::Overflow)?;
+                // Shifting out any of the value bits is considered an overflow
+                ensure!(shifted >> b == a, Error::Overflow);
+                shifted.into()
+            }
+            (Shr, Number(a), Number(b)) => {
+                a.checked_shr(b.try_into()?).ok_or(Error::Overflow)?.into()
+            }
+            (BitAnd, Number(a), Number(b)) => (a & b).into(),
+            (BitOr, Number(a), Number(b)) => (a | b).into(),
+            (BitXor, Number(a), Number(b)) => (a ^ b).into(),
             // + for arrays
             (Add, Array(a), Array(b)) => [a, b].concat().into(),
             // + for bytes
             (Add, Bytes(a), Bytes(b)) => [a, b].concat().into(),
+            // + for strings
+            (Add, String(a), String(b)) => (a + &b).into(),
             // @ to assign execution probability
             (Prob, Number(prob), value) => WithProb(prob.try_into()?, value.into()),
             // + for tap tweak (internal_key+script_tree)
             (Add, k @ PubKey(_), s)
+            | (Add, k @ SecKey(_), s)
             | (
                 Add,
                 k @ Bytes(_),
@@ -337,7 +750,6 @@ impl Evaluate for ast::Duration {
         let block_interval = scope
             .get(&"BLOCK_INTERVAL".into())
             .expect("built-in var")
-            .clone()
             .into_usize()? as f64;
         let seq_num = time::duration_to_seq(self, block_interval)?;
         Ok(Value::Number(seq_num as i64))
@@ -361,7 +773,26 @@ impl Evaluate for ast::BtcAmount {
 impl Evaluate for ast::Block {
     // Execute the block in a new child scope, with no visible side-effects.
     fn eval(&self, scope: &Scope) -> Result<Value> {
-        let mut scope = scope.child();
+        let scope = scope.child();
+        let result = self.eval_in(&scope);
+        // Unless the result holds functions that may have captured the block's scope, nothing outside
+        // of the block refers to it anymore. Its definitions are cleared to break the reference cycles
+        // they may form (i.e. a closure returned from a function call and stored in the scope that
+        // the function was defined in), which would otherwise keep the scope alive.
+        let escapes = match &result {
+            Ok(value) => value.holds_user_fn(),
+            Err(err) => matches!(err.root(), Error::Thrown(value) if value.holds_user_fn()),
+        };
+        if !escapes {
+            scope.clear_locals();
+        }
+        result
+    }
+}
+
+impl ast::Block {
+    fn eval_in(&self, scope: &Scope) -> Result<Value> {
+        let mut scope = scope.clone();
         for stmt in &self.stmts {
             stmt.exec(&mut scope)?;
         }
@@ -379,38 +810,63 @@ impl Evaluate for ast::Block {
 }
 
 impl Execute for ast::Library {
-    // Execute the library in the given scope, producing visible side-effects
+    // Execute the library in the given scope, producing visible side-effects.
+    // Its tests are skipped, they're only run by the test runner.
     fn exec(&self, scope: &mut Scope) -> Result<()> {
         for stmt in &self.stmts {
-            stmt.exec(scope)?;
+            if !matches!(stmt.kind, StmtKind::Test(_)) {
+                stmt.exec(scope)?;
+            }
         }
         Ok(())
     }
 }
 
 impl Evaluate for Expr {
+    // Evaluate the expression, attaching its source span to errors that don't have one yet
+    fn eval(&self, scope: &Scope) -> Result<Value> {
+        scope
+            .step()
+            .and_then(|_| self.kind.eval(scope))
+            .map_err(|e| e.with_span(self.span))
+    }
+}
+
+impl Evaluate for ExprKind {
     fn eval(&self, scope: &Scope) -> Result<Value> {
         Ok(match self {
-            Expr::Ident(x) => x.eval(scope)?,
-            Expr::Call(x) => x.eval(scope)?,
-            Expr::Or(x) => x.eval(scope)?,
-            Expr::And(x) => x.eval(scope)?,
-            Expr::Thresh(x) => x.eval(scope)?,
-            Expr::Block(x) => x.eval(scope)?,
-            Expr::Array(x) => x.eval(scope)?,
-            Expr::ArrayAccess(x) => x.eval(scope)?,
-            Expr::ChildDerive(x) => x.eval(scope)?,
-            Expr::ScriptFrag(x) => x.eval(scope)?,
-            Expr::FnExpr(x) => x.eval(scope)?,
-            Expr::Infix(x) => x.eval(scope)?,
-            Expr::Not(x) => x.eval(scope)?,
-
-            Expr::Duration(x) => x.eval(scope)?,
-            Expr::DateTime(x) => x.eval(scope)?,
-            Expr::BtcAmount(x) => x.eval(scope)?,
-            Expr::PubKey(x) => Value::PubKey(x.parse()?),
-            Expr::Bytes(x) => Value::Bytes(x.clone()),
-            Expr::Number(x) => Value::Number(*x),
+            ExprKind::Ident(x) => x.eval(scope)?,
+            ExprKind::Call(x) => x.eval(scope)?,
+            ExprKind::Or(x) => x.eval(scope)?,
+            ExprKind::And(x) => x.eval(scope)?,
+            ExprKind::Thresh(x) => x.eval(scope)?,
+            ExprKind::Block(x) => x.eval(scope)?,
+            ExprKind::Array(x) => x.eval(scope)?,
+            ExprKind::ArrayAccess(x) => x.eval(scope)?,
+            ExprKind::Slice(x) => x.eval(scope)?,
+            ExprKind::Range(x) => x.eval(scope)?,
+            ExprKind::Map(x) => x.eval(scope)?,
+            ExprKind::ChildDerive(x) => x.eval(scope)?,
+            ExprKind::ScriptFrag(x) => x.eval(scope)?,
+            ExprKind::FnExpr(x) => x.eval(scope)?,
+            ExprKind::Infix(x) => x.eval(scope)?,
+            ExprKind::Not(x) => x.eval(scope)?,
+            ExprKind::Neg(x) => x.eval(scope)?,
+            ExprKind::IfExpr(x) => x.eval(scope)?,
+            ExprKind::Match(x) => x.eval(scope)?,
+            ExprKind::Try(x) => x.eval(scope)?,
+            ExprKind::Coalesce(x) => x.eval(scope)?,
+            ExprKind::InterpolatedStr(x) => x.eval(scope)?,
+
+            ExprKind::Duration(x) => x.eval(scope)?,
+            ExprKind::DateTime(x) => x.eval(scope)?,
+            ExprKind::BtcAmount(x) => x.eval(scope)?,
+            ExprKind::PubKey(x) => Value::PubKey(x.parse()?),
+            ExprKind::SecKey(x) => Value::SecKey(SecKey::parse(x, None)?),
+            ExprKind::Bytes(x) => Value::Bytes(x.clone()),
+            ExprKind::String(x) => Value::String(x.clone()),
+            ExprKind::Number(x) => Value::Number(*x),
+            ExprKind::Value(x) => (**x).clone(),
         })
     }
 }
@@ -441,6 +897,7 @@ impl TryFrom<Value> for Policy {
         match value {
             Value::Policy(policy) => Ok(policy),
             Value::PubKey(pubkey) => Ok(Policy::Key(pubkey)),
+            Value::SecKey(seckey) => Ok(Policy::Key(seckey.to_public()?)),
             arr @ Value::Array(_) => stdlib::miniscript::fns::all_(arr)?.try_into(),
             v => Err(Error::NotPolicyLike(v)),
         }
@@ -494,6 +951,7 @@ impl TryFrom<Value> for DescriptorPublicKey {
         use miniscript::descriptor::{DescriptorSinglePub, SinglePubKey};
         match value {
             Value::PubKey(x) => Ok(x),
+            Value::SecKey(x) => x.to_public(),
             Value::Bytes(bytes) => {
                 let key = match bytes.len() {
                     33 => SinglePubKey::FullKey(PublicKey::from_slice(&bytes)?),
@@ -518,6 +976,7 @@ impl TryFrom<Value> for Descriptor {
             Value::Miniscript(x) => Ok(Descriptor::new_wsh(x)?),
             Value::Policy(x) => Ok(Descriptor::new_wsh(x.compile()?)?),
             Value::PubKey(x) => Ok(Descriptor::new_wpkh(x)?),
+            Value::SecKey(x) => Ok(Descriptor::new_wpkh(x.to_public()?)?),
             v => Err(Error::NotDescriptorLike(v)),
         }
     }
@@ -538,6 +997,7 @@ impl TryFrom<Value> for Vec<u8> {
     fn try_from(value: Value) -> Result<Self> {
         Ok(match value {
             Value::Bytes(bytes) => bytes,
+            Value::String(string) => string.into_bytes(),
             Value::Script(script) => script.into_bytes(),
             v => bail!(Error::NotBytes(v)),
         })
@@ -554,6 +1014,26 @@ impl TryFrom<Value> for Vec<Value> {
     }
 }
 
+impl TryFrom<Value> for BTreeMap<String, Value> {
+    type Error = Error;
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::Map(map) => Ok(map),
+            v => Err(Error::NotMap(v)),
+        }
+    }
+}
+
+impl TryFrom<Value> for SecKey {
+    type Error = Error;
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::SecKey(seckey) => Ok(seckey),
+            v => Err(Error::NotSecKey(v)),
+        }
+    }
+}
+
 impl TryFrom<Value> for Function {
     type Error = Error;
     fn try_from(value: Value) -> Result<Self> {
@@ -591,7 +1071,12 @@ impl TryFrom<Value> for Script {
 impl TryFrom<Value> for String {
     type Error = Error;
     fn try_from(value: Value) -> Result<Self> {
-        Ok(String::from_utf8(value.into_bytes()?)?)
+        match value {
+            Value::String(string) => Ok(string),
+            // Bytes are accepted for compatibility, as long as they're valid UTF-8
+            Value::Bytes(bytes) => Ok(String::from_utf8(bytes)?),
+            v => Err(Error::NotString(v)),
+        }
     }
 }
 
@@ -622,12 +1107,25 @@ impl Value {
     pub fn is_array(&self) -> bool {
         matches!(self, Value::Array(_))
     }
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+    /// Convert into an Option, with Null as None. Used for optional function arguments.
+    pub fn into_option(self) -> Option<Value> {
+        iif!(self.is_null(), None, Some(self))
+    }
+    pub fn is_map(&self) -> bool {
+        matches!(self, Value::Map(_))
+    }
     pub fn is_bool(&self) -> bool {
         matches!(self, Value::Bool(_))
     }
     pub fn is_bytes(&self) -> bool {
         matches!(self, Value::Bytes(_))
     }
+    pub fn is_string(&self) -> bool {
+        matches!(self, Value::String(_))
+    }
     pub fn into_policy(self) -> Result<Policy> {
         self.try_into()
     }
@@ -661,6 +1159,12 @@ impl Value {
     pub fn into_array(self) -> Result<Vec<Value>> {
         self.try_into()
     }
+    pub fn into_map(self) -> Result<BTreeMap<String, Value>> {
+        self.try_into()
+    }
+    pub fn into_seckey(self) -> Result<SecKey> {
+        self.try_into()
+    }
     pub fn into_spk(self) -> Result<Script> {
         if self.is_desc_like() {
             self.into_desc()?.to_script_pubkey()
@@ -674,20 +1178,35 @@ impl Value {
         matches!(self, Value::Script(_) | Value::Bytes(_)) || self.is_miniscript_like()
     }
     pub fn is_desc_like(&self) -> bool {
-        matches!(self, Value::Descriptor(_) | Value::PubKey(_)) || self.is_miniscript_like()
+        matches!(
+            self,
+            Value::Descriptor(_) | Value::PubKey(_) | Value::SecKey(_)
+        ) || self.is_miniscript_like()
     }
     pub fn is_miniscript_like(&self) -> bool {
         matches!(self, Value::Miniscript(_) | Value::Policy(_))
     }
+    /// Whether the value is or contains a user function, which may refer to the scope it captured
+    pub fn holds_user_fn(&self) -> bool {
+        match self {
+            Value::Function(Function::User(_)) => true,
+            Value::Array(elements) => elements.iter().any(Value::holds_user_fn),
+            Value::Map(map) => map.values().any(Value::holds_user_fn),
+            Value::WithProb(_, value) => value.holds_user_fn(),
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Value::PubKey(x) => write!(f, "{}", x),
+            Value::SecKey(x) => write!(f, "{}", x),
             Value::Number(x) => write!(f, "{}", x),
             Value::Bool(x) => write!(f, "{}", x),
             Value::Bytes(x) => write!(f, "0x{}", x.to_hex()),
+            Value::String(x) => write!(f, "{}", x),
             Value::Policy(x) => write!(f, "{}", x),
             Value::WithProb(p, x) => write!(f, "{}@{}", p, x),
             Value::Miniscript(x) => write!(f, "{}", x),
@@ -696,16 +1215,39 @@ impl fmt::Display for Value {
             Value::Script(x) => write!(f, "{:?}", x),
             Value::Function(x) => write!(f, "{:?}", x),
             Value::Network(x) => write!(f, "{}", x),
+            Value::Null => write!(f, "null"),
             Value::Array(elements) => {
                 write!(f, "[ ")?;
                 for (i, element) in elements.iter().enumerate() {
                     if i > 0 {
                         write!(f, ", ")?;
                     }
-                    write!(f, "{}", element)?;
+                    fmt_element(f, element)?;
                 }
                 write!(f, " ]")
             }
+            // Displayed in a JSON-like format, with the keys in sorted order
+            Value::Map(map) if map.is_empty() => write!(f, "{{}}"),
+            Value::Map(map) => {
+                write!(f, "{{ ")?;
+                for (i, (key, value)) in map.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{:?}: ", key)?;
+                    fmt_element(f, value)?;
+                }
+                write!(f, " }}")
+            }
         }
     }
 }
+
+// Display a value nested within an array or map
+fn fmt_element(f: &mut fmt::Formatter, value: &Value) -> fmt::Result {
+    match value {
+        // Quote strings, so their boundaries are visible
+        Value::String(x) => write!(f, "{:?}", x),
+        _ => write!(f, "{}", value),
+    }
+}
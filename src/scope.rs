@@ -1,39 +1,65 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
+use std::rc::{Rc, Weak};
 
 use crate::ast::Ident;
+use crate::budget::Budget;
 use crate::error::{Error, Result};
-use crate::function::NativeFunctionPt;
+use crate::function::{Function, NativeFunction, NativeFunctionPt};
+use crate::import::Importer;
+use crate::output::Output;
 use crate::runtime::Value;
 use crate::stdlib::attach_stdlib;
 
-#[derive(Default, Debug)]
-pub struct Scope<'a> {
-    parent: Option<&'a Scope<'a>>,
-    local: HashMap<Ident, Value>,
+/// A reference-counted handle to a lexical scope.
+///
+/// Cloning the handle is cheap and refers to the same underlying scope. This allows user functions
+/// to capture the environment they were defined in and keep it alive after the evaluation that
+/// created it returns. Functions stored in the same scope they capture (like `fn` definitions)
+/// only hold a WeakScope while stored there, to avoid a reference cycle that would keep the scope
+/// alive forever. They get a strong reference back when read out of the scope.
+#[derive(Clone, Default)]
+pub struct Scope(Rc<ScopeInner>);
+
+/// A non-owning handle to a scope, which does not keep it alive
+#[derive(Clone)]
+pub struct WeakScope(Weak<ScopeInner>);
+
+#[derive(Default)]
+struct ScopeInner {
+    parent: Option<Scope>,
+    local: RefCell<HashMap<Ident, Value>>,
+    // Used to resolve import statements, inherited by child scopes
+    importer: Option<Rc<Importer>>,
+    // Limits the resources used by the evaluation, inherited by child scopes
+    budget: Option<Rc<Budget>>,
+    // Receives the debugging output, inherited by child scopes. Discarded when there's none.
+    output: Option<Rc<dyn Output>>,
 }
 
-impl<'a> Scope<'a> {
+impl Scope {
     pub fn root() -> Self {
         let mut scope = Self::default();
         attach_stdlib(&mut scope);
         scope
     }
 
-    pub fn get(&self, key: &Ident) -> Option<&Value> {
-        self.local
-            .get(key)
-            .or_else(|| self.parent.as_ref().and_then(|p| p.get(key)))
+    pub fn get(&self, key: &Ident) -> Option<Value> {
+        let local = self.0.local.borrow().get(key).cloned().map(load);
+        local.or_else(|| self.0.parent.as_ref().and_then(|p| p.get(key)))
     }
 
     pub fn set<K: Into<Ident>, V: Into<Value>>(&mut self, key: K, value: V) -> Result<()> {
         let key = key.into();
+        let mut local = self.0.local.borrow_mut();
 
         #[allow(clippy::map_entry)]
-        if self.local.contains_key(&key) {
+        if local.contains_key(&key) {
             // cannot be set if already exists in this scope, but could shadow over a definition from a parent scope
             Err(Error::AssignedVariableExists(key))
         } else {
-            self.local.insert(key, value.into());
+            local.insert(key, store(value.into(), self));
             Ok(())
         }
     }
@@ -44,10 +70,202 @@ impl<'a> Scope<'a> {
         self.set(key, f)
     }
 
-    pub fn child(&'a self) -> Self {
-        Scope {
-            parent: Some(&self),
-            local: HashMap::new(),
+    /// Set a native function with its declared type signature (i.e. `Number, Bytes? -> Policy`),
+    /// checked by the type checker before evaluation
+    pub fn set_fn_typed<K: Into<Ident>>(
+        &mut self,
+        key: K,
+        f: NativeFunctionPt,
+        sig: &str,
+    ) -> Result<()> {
+        self.set_fn_with_params(key, f, &[], sig)
+    }
+
+    /// Set a native function with its declared parameter names and type signature,
+    /// allowing it to be called with named arguments
+    pub fn set_fn_with_params<K: Into<Ident>>(
+        &mut self,
+        key: K,
+        f: NativeFunctionPt,
+        params: &'static [&'static str],
+        sig: &str,
+    ) -> Result<()> {
+        let native = NativeFunction {
+            pt: f,
+            params,
+            sig: Some(Rc::new(sig.parse()?)),
+            doc: None,
+        };
+        self.set(key, Function::Native(native))
+    }
+
+    /// Attach a description to a native function previously set in this scope,
+    /// shown in the documentation generated by `minsc doc`
+    pub fn set_fn_doc<K: Into<Ident>>(&mut self, key: K, doc: &'static str) -> Result<()> {
+        let key = key.into();
+        let mut local = self.0.local.borrow_mut();
+        match local.get_mut(&key) {
+            Some(Value::Function(Function::Native(native))) => {
+                native.doc = Some(doc);
+                Ok(())
+            }
+            Some(value) => Err(Error::NotFn(value.clone())),
+            None => Err(Error::VarNotFound(key)),
         }
     }
+
+    pub fn child(&self) -> Self {
+        self.new_child(
+            self.0.importer.clone(),
+            self.0.budget.clone(),
+            self.0.output.clone(),
+        )
+    }
+
+    /// Create a child scope that resolves import statements using the given importer
+    pub fn child_with_importer(&self, importer: Rc<Importer>) -> Self {
+        self.new_child(Some(importer), self.0.budget.clone(), self.0.output.clone())
+    }
+
+    /// Create a child scope that evaluates under the given budget (or without one, if None)
+    pub fn child_with_budget(&self, budget: Option<Rc<Budget>>) -> Self {
+        self.new_child(self.0.importer.clone(), budget, self.0.output.clone())
+    }
+
+    /// Create a child scope that writes the output of `print()`, `trace()` and `dbg()` to the given output
+    pub fn child_with_output(&self, output: Rc<dyn Output>) -> Self {
+        self.new_child(self.0.importer.clone(), self.0.budget.clone(), Some(output))
+    }
+
+    /// Create a child scope that evaluates under the budget and output of the `caller` scope,
+    /// while keeping this scope's variables and importer (i.e. for the body of a called function)
+    pub fn child_for_caller(&self, caller: &Scope) -> Self {
+        self.new_child(
+            self.0.importer.clone(),
+            caller.0.budget.clone(),
+            caller.0.output.clone(),
+        )
+    }
+
+    fn new_child(
+        &self,
+        importer: Option<Rc<Importer>>,
+        budget: Option<Rc<Budget>>,
+        output: Option<Rc<dyn Output>>,
+    ) -> Self {
+        Scope(Rc::new(ScopeInner {
+            parent: Some(self.clone()),
+            local: RefCell::new(HashMap::new()),
+            importer,
+            budget,
+            output,
+        }))
+    }
+
+    pub fn importer(&self) -> Option<&Rc<Importer>> {
+        self.0.importer.as_ref()
+    }
+
+    pub fn budget(&self) -> Option<&Rc<Budget>> {
+        self.0.budget.as_ref()
+    }
+
+    /// Account for an evaluation step, if the scope has a budget
+    pub fn step(&self) -> Result<()> {
+        self.budget().map_or(Ok(()), |budget| budget.step())
+    }
+
+    /// Account for allocating `size` array elements, map entries or bytes, if the scope has a budget
+    pub fn alloc(&self, size: usize) -> Result<()> {
+        self.budget().map_or(Ok(()), |budget| budget.alloc(size))
+    }
+
+    /// Write a line of output, if the scope has an output attached. The line is accounted
+    /// for as allocated bytes, since the output may keep it in memory (like LogOutput does).
+    pub fn write_output(&self, line: &str) -> Result<()> {
+        match &self.0.output {
+            Some(output) => {
+                self.alloc(line.len())?;
+                output.write(line)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Get the top-level scope, with the built-in definitions
+    pub fn top(&self) -> &Scope {
+        self.0.parent.as_ref().map_or(self, |parent| parent.top())
+    }
+
+    /// Get the variables defined directly in this scope, excluding its parents
+    pub fn locals(&self) -> Vec<(Ident, Value)> {
+        let local = self.0.local.borrow();
+        local
+            .iter()
+            .map(|(k, v)| (k.clone(), load(v.clone())))
+            .collect()
+    }
+
+    /// Remove the variables defined directly in this scope
+    pub(crate) fn clear_locals(&self) {
+        // Taken out before being dropped, since dropping the values may drop scopes referring to this one
+        let locals = self.0.local.take();
+        drop(locals);
+    }
+
+    pub fn downgrade(&self) -> WeakScope {
+        WeakScope(Rc::downgrade(&self.0))
+    }
+
+    pub fn ptr_eq(&self, other: &Scope) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl WeakScope {
+    /// Get the scope, unless it was already dropped
+    pub fn upgrade(&self) -> Option<Scope> {
+        self.0.upgrade().map(Scope)
+    }
+}
+
+// Prepare the value for storing in the scope, weakening the functions that capture it,
+// including those nested within arrays, maps and probabilities
+fn store(value: Value, scope: &Scope) -> Value {
+    match value {
+        Value::Function(Function::User(func)) => Function::User(func.weaken_for(scope)).into(),
+        Value::Array(elements) => {
+            Value::Array(elements.into_iter().map(|v| store(v, scope)).collect())
+        }
+        Value::Map(entries) => Value::Map(
+            entries
+                .into_iter()
+                .map(|(k, v)| (k, store(v, scope)))
+                .collect(),
+        ),
+        Value::WithProb(prob, value) => Value::WithProb(prob, store(*value, scope).into()),
+        value => value,
+    }
+}
+
+// Restore the value read from a scope, with strong references for its functions
+fn load(value: Value) -> Value {
+    match value {
+        Value::Function(Function::User(func)) => Function::User(func.strengthen()).into(),
+        Value::Array(elements) => Value::Array(elements.into_iter().map(load).collect()),
+        Value::Map(entries) => Value::Map(entries.into_iter().map(|(k, v)| (k, load(v))).collect()),
+        Value::WithProb(prob, value) => Value::WithProb(prob, load(*value).into()),
+        value => value,
+    }
+}
+
+// Only the local variable names are printed. Scopes captured by functions may be self-referencing,
+// so printing the values (or the parent chain) could recurse infinitely.
+impl fmt::Debug for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Scope")
+            .field("local", &self.0.local.borrow().keys().collect::<Vec<_>>())
+            .field("has_parent", &self.0.parent.is_some())
+            .finish()
+    }
 }
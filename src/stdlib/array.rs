@@ -0,0 +1,366 @@
+use std::cmp::Ordering;
+use std::convert::TryInto;
+
+use crate::function::Call;
+use crate::{Error, Result, Scope, Type, Value};
+
+pub fn attach_stdlib(scope: &mut Scope) {
+    scope
+        .set_fn_with_params(
+            "map",
+            fns::map,
+            &["array", "callback"],
+            "Array, Function -> Array",
+        )
+        .unwrap();
+    scope
+        .set_fn_with_params(
+            "filter",
+            fns::filter,
+            &["array", "predicate"],
+            "Array, Function -> Array",
+        )
+        .unwrap();
+    scope
+        .set_fn_with_params(
+            "reduce",
+            fns::reduce,
+            &["array", "callback"],
+            "Array, Function -> Any",
+        )
+        .unwrap();
+    scope
+        .set_fn_with_params(
+            "fold",
+            fns::fold,
+            &["array", "initial", "callback"],
+            "Array, Any, Function -> Any",
+        )
+        .unwrap();
+    scope
+        .set_fn_typed("zip", fns::zip, "Array, Array... -> Array")
+        .unwrap();
+    scope
+        .set_fn_typed("flatten", fns::flatten, "Array -> Array")
+        .unwrap();
+    scope
+        .set_fn_typed("sort", fns::sort, "Array -> Array")
+        .unwrap();
+    scope
+        .set_fn_with_params(
+            "sortBy",
+            fns::sortBy,
+            &["array", "key"],
+            "Array, Function -> Array",
+        )
+        .unwrap();
+    scope
+        .set_fn_typed("unique", fns::unique, "Array -> Array")
+        .unwrap();
+    scope
+        .set_fn_with_params(
+            "contains",
+            fns::contains,
+            &["array", "value"],
+            "Array, Any -> Bool",
+        )
+        .unwrap();
+    scope
+        .set_fn_with_params(
+            "indexOf",
+            fns::indexOf,
+            &["array", "value"],
+            "Array, Any -> Number|Null",
+        )
+        .unwrap();
+    scope
+        .set_fn_typed("enumerate", fns::enumerate, "Array -> Array")
+        .unwrap();
+    scope
+        .set_fn_with_params(
+            "range",
+            fns::range,
+            &["start", "end"],
+            "Number, Number -> Array",
+        )
+        .unwrap();
+    scope
+        .set_fn_with_params(
+            "slice",
+            fns::slice,
+            &["array", "start", "len"],
+            "Array, Number, Number -> Array",
+        )
+        .unwrap();
+
+    // Descriptions for the generated documentation (`minsc doc`)
+    for (name, doc) in &[
+        ("map", "Transform each element using the callback"),
+        ("filter", "Keep the elements matching the predicate"),
+        (
+            "reduce",
+            "Combine the elements using the `callback($acc, $element)`",
+        ),
+        (
+            "fold",
+            "Combine the elements into the initial value using `callback($acc, $element)`",
+        ),
+        (
+            "zip",
+            "Combine the arrays into tuples, up to the shortest array's length",
+        ),
+        ("flatten", "Flatten an array of arrays by one level"),
+        ("sort", "Sort an array of numbers, strings or bytes"),
+        (
+            "sortBy",
+            "Sort the elements by the number, string or bytes returned for them by `key`",
+        ),
+        (
+            "unique",
+            "Remove duplicate elements, keeping the first occurrence",
+        ),
+        ("contains", "Check whether the array contains the value"),
+        (
+            "indexOf",
+            "Get the index of the first element equal to the value, or null",
+        ),
+        (
+            "enumerate",
+            "Pair each element with its index, as `[ $index, $element ]`",
+        ),
+        (
+            "range",
+            "Create an array of the numbers from `start` to `end` (inclusive)",
+        ),
+        ("slice", "Get `len` elements starting at the `start` index"),
+    ] {
+        scope.set_fn_doc(*name, doc).unwrap();
+    }
+}
+
+/// Check whether all (or any) of the array's elements match the predicate.
+/// Used by all()/any() when called with a predicate, instead of constructing a policy.
+pub fn match_predicate(mut args: Vec<Value>, scope: &Scope, all: bool) -> Result<Value> {
+    let array = args.remove(0).into_array()?;
+    let predicate = args.remove(0);
+    for element in array {
+        if predicate.call(vec![element], scope)?.into_bool()? != all {
+            return Ok((!all).into());
+        }
+    }
+    Ok(all.into())
+}
+
+/// Create an array of the numbers from `start` up to (excluding) `end`, or an empty array if `end`
+/// is not greater than `start`. Used for range literals (`0..$n`) and by range().
+pub fn exclusive_range(start: i64, end: i64, scope: &Scope) -> Result<Value> {
+    if end <= start {
+        return Ok(Value::Array(vec![]));
+    }
+    let len: usize = end.checked_sub(start).ok_or(Error::Overflow)?.try_into()?;
+    scope.alloc(len)?;
+    // Reserve upfront, so that huge ranges error out rather than abort when unbounded by a Budget
+    let mut array = Vec::new();
+    array.try_reserve_exact(len)?;
+    array.extend((start..end).map(Value::from));
+    Ok(Value::Array(array))
+}
+
+/// Compare values for sorting. Numbers, strings and bytes are supported, but not mixed together.
+fn compare(a: &Value, b: &Value) -> Result<Ordering> {
+    Ok(match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.cmp(b),
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Bytes(a), Value::Bytes(b)) => a.cmp(b),
+        (a, b) => bail!(Error::InvalidOperands(Type::of(a), Type::of(b))),
+    })
+}
+
+/// Sort the elements by their keys, ensuring they can all be compared first
+fn sort_by_keys(elements: Vec<Value>, keys: Vec<Value>) -> Result<Vec<Value>> {
+    if let Some(first) = keys.first() {
+        for key in &keys[1..] {
+            compare(first, key)?;
+        }
+    }
+    let mut keyed: Vec<(Value, Value)> = keys.into_iter().zip(elements).collect();
+    keyed.sort_by(|(a, _), (b, _)| compare(a, b).expect("checked above"));
+    Ok(keyed.into_iter().map(|(_, element)| element).collect())
+}
+
+#[allow(non_snake_case)]
+pub mod fns {
+    use super::*;
+
+    /// map(Array, Function callback) -> Array
+    pub fn map(mut args: Vec<Value>, scope: &Scope) -> Result<Value> {
+        ensure!(args.len() == 2, Error::InvalidArguments);
+        let array = args.remove(0).into_array()?;
+        let callback = args.remove(0);
+        scope.alloc(array.len())?;
+        Ok(Value::Array(
+            array
+                .into_iter()
+                .map(|element| callback.call(vec![element], scope))
+                .collect::<Result<_>>()?,
+        ))
+    }
+
+    /// filter(Array, Function predicate) -> Array
+    pub fn filter(mut args: Vec<Value>, scope: &Scope) -> Result<Value> {
+        ensure!(args.len() == 2, Error::InvalidArguments);
+        let array = args.remove(0).into_array()?;
+        let predicate = args.remove(0);
+        let mut filtered = vec![];
+        for element in array {
+            if predicate.call(vec![element.clone()], scope)?.into_bool()? {
+                filtered.push(element);
+            }
+        }
+        scope.alloc(filtered.len())?;
+        Ok(filtered.into())
+    }
+
+    /// reduce(Array, Function callback) -> Value
+    ///
+    /// Like fold(), using the first element as the initial value. Fails for empty arrays.
+    pub fn reduce(mut args: Vec<Value>, scope: &Scope) -> Result<Value> {
+        ensure!(args.len() == 2, Error::InvalidArguments);
+        let mut array = args.remove(0).into_array()?.into_iter();
+        let callback = args.remove(0);
+        let initial = array.next().ok_or(Error::ArrayIndexOutOfRange)?;
+        array.try_fold(initial, |acc, element| {
+            callback.call(vec![acc, element], scope)
+        })
+    }
+
+    /// fold(Array, Value initial, Function callback) -> Value
+    pub fn fold(mut args: Vec<Value>, scope: &Scope) -> Result<Value> {
+        ensure!(args.len() == 3, Error::InvalidArguments);
+        let array = args.remove(0).into_array()?;
+        let initial = args.remove(0);
+        let callback = args.remove(0);
+        array.into_iter().try_fold(initial, |acc, element| {
+            callback.call(vec![acc, element], scope)
+        })
+    }
+
+    /// zip(Array, ...Array) -> Array<Array>
+    pub fn zip(args: Vec<Value>, scope: &Scope) -> Result<Value> {
+        ensure!(!args.is_empty(), Error::InvalidArguments);
+        let mut arrays = args
+            .into_iter()
+            .map(|array| Ok(array.into_array()?.into_iter()))
+            .collect::<Result<Vec<_>>>()?;
+        let len = arrays.iter().map(ExactSizeIterator::len).min().unwrap_or(0);
+        scope.alloc(len * (arrays.len() + 1))?;
+        let zipped = (0..len)
+            .map(|_| {
+                let tuple = arrays.iter_mut().map(|array| array.next().unwrap());
+                Value::Array(tuple.collect())
+            })
+            .collect::<Vec<_>>();
+        Ok(zipped.into())
+    }
+
+    /// flatten(Array<Array|Value>) -> Array
+    pub fn flatten(mut args: Vec<Value>, scope: &Scope) -> Result<Value> {
+        ensure!(args.len() == 1, Error::InvalidArguments);
+        let mut flattened = vec![];
+        for element in args.remove(0).into_array()? {
+            match element {
+                Value::Array(inner) => flattened.extend(inner),
+                other => flattened.push(other),
+            }
+        }
+        scope.alloc(flattened.len())?;
+        Ok(flattened.into())
+    }
+
+    /// sort(Array<Number>|Array<String>|Array<Bytes>) -> Array
+    pub fn sort(mut args: Vec<Value>, scope: &Scope) -> Result<Value> {
+        ensure!(args.len() == 1, Error::InvalidArguments);
+        let array = args.remove(0).into_array()?;
+        scope.alloc(array.len())?;
+        Ok(sort_by_keys(array.clone(), array)?.into())
+    }
+
+    /// sortBy(Array, Function key) -> Array
+    pub fn sortBy(mut args: Vec<Value>, scope: &Scope) -> Result<Value> {
+        ensure!(args.len() == 2, Error::InvalidArguments);
+        let array = args.remove(0).into_array()?;
+        let key_fn = args.remove(0);
+        scope.alloc(array.len())?;
+        let keys = array
+            .iter()
+            .map(|element| key_fn.call(vec![element.clone()], scope))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(sort_by_keys(array, keys)?.into())
+    }
+
+    /// unique(Array) -> Array
+    pub fn unique(mut args: Vec<Value>, scope: &Scope) -> Result<Value> {
+        ensure!(args.len() == 1, Error::InvalidArguments);
+        let array = args.remove(0).into_array()?;
+        scope.alloc(array.len())?;
+        // Values are not hashable, so this is quadratic in the number of unique elements
+        let mut unique = Vec::<Value>::new();
+        for element in array {
+            if !unique.contains(&element) {
+                unique.push(element);
+            }
+        }
+        Ok(unique.into())
+    }
+
+    /// contains(Array, Value) -> Bool
+    pub fn contains(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
+        ensure!(args.len() == 2, Error::InvalidArguments);
+        let array = args.remove(0).into_array()?;
+        Ok(array.contains(&args[0]).into())
+    }
+
+    /// indexOf(Array, Value) -> Number|Null
+    pub fn indexOf(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
+        ensure!(args.len() == 2, Error::InvalidArguments);
+        let array = args.remove(0).into_array()?;
+        Ok(match array.iter().position(|element| *element == args[0]) {
+            Some(index) => index.into(),
+            None => Value::Null,
+        })
+    }
+
+    /// enumerate(Array) -> Array<[Number, Value]>
+    pub fn enumerate(mut args: Vec<Value>, scope: &Scope) -> Result<Value> {
+        ensure!(args.len() == 1, Error::InvalidArguments);
+        let array = args.remove(0).into_array()?;
+        scope.alloc(array.len() * 3)?;
+        let enumerated = array
+            .into_iter()
+            .enumerate()
+            .map(|(index, element)| Value::Array(vec![index.into(), element]))
+            .collect::<Vec<_>>();
+        Ok(enumerated.into())
+    }
+
+    /// range(Number start, Number end) -> Array<Number>
+    pub fn range(mut args: Vec<Value>, scope: &Scope) -> Result<Value> {
+        ensure!(args.len() == 2, Error::InvalidArguments);
+        let start = args.remove(0).into_i64()?;
+        let end = args.remove(0).into_i64()?;
+        exclusive_range(start, end.checked_add(1).ok_or(Error::Overflow)?, scope)
+    }
+
+    /// slice(Array, Number start, Number len) -> Array
+    pub fn slice(mut args: Vec<Value>, scope: &Scope) -> Result<Value> {
+        ensure!(args.len() == 3, Error::InvalidArguments);
+        let mut array = args.remove(0).into_array()?;
+        let start = args.remove(0).into_usize()?;
+        let len = args.remove(0).into_usize()?;
+        let end = start.checked_add(len).ok_or(Error::Overflow)?;
+        ensure!(end <= array.len(), Error::ArrayIndexOutOfRange);
+        scope.alloc(len)?;
+        array.truncate(end);
+        Ok(array.split_off(start).into())
+    }
+}
@@ -3,44 +3,61 @@ use bitcoin::{Transaction, TxIn, TxOut};
 use miniscript::bitcoin;
 
 use crate::runtime::Execute;
-use crate::{ast, parse_lib, Error, Result, Scope, Value};
-
-lazy_static! {
-    static ref MINSC_CTV_LIB: ast::Library = parse_lib(
-        r#"
-        OP_CHECKTEMPLATEVERIFY = rawscript(0xb3);
-        OP_CTV = OP_CHECKTEMPLATEVERIFY;
-
-        fn ctv($tx) = `ctvHash($tx) OP_CHECKTEMPLATEVERIFY OP_DROP`;
-
-        // Utility functions for tagged arrays with a more DSL-y syntax
-        fn txVersion($version) = [ "version", $version ];
-        fn txLocktime($locktime) = [ "locktime", $locktime ];
-        fn txInSeq($seq) = [ "input", $seq ];
-        fn txIn() = [ "input" ];
-        fn txOut($spk, $amount) = [ "output", $spk, $amount ];
-        "#
-    )
-    .unwrap();
+use crate::stdlib::CTV_LIB_SOURCE;
+use crate::{ast, parse_lib_source, Error, Result, Scope, Value};
+
+pub const MINSC_CTV_LIB_CODE: &str = r#"
+    OP_CHECKTEMPLATEVERIFY = rawscript(0xb3);
+    OP_CTV = OP_CHECKTEMPLATEVERIFY;
+
+    fn ctv($tx) = `ctvHash($tx) OP_CHECKTEMPLATEVERIFY OP_DROP`;
+
+    // Utility functions for tagged arrays with a more DSL-y syntax
+    fn txVersion($version) = [ "version", $version ];
+    fn txLocktime($locktime) = [ "locktime", $locktime ];
+    fn txInSeq($seq) = [ "input", $seq ];
+    fn txIn() = [ "input" ];
+    fn txOut($spk, $amount) = [ "output", $spk, $amount ];
+"#;
+
+thread_local! {
+    static MINSC_CTV_LIB: ast::Library =
+        parse_lib_source(MINSC_CTV_LIB_CODE, CTV_LIB_SOURCE).unwrap();
 }
 
 pub fn attach_stdlib(scope: &mut Scope) {
-    scope.set_fn("ctvHash", fns::ctvHash).unwrap();
+    scope
+        .set_fn_with_params(
+            "ctvHash",
+            fns::ctvHash,
+            &["tx", "index"],
+            "Array, Number|Null? -> Bytes",
+        )
+        .unwrap();
+    scope
+        .set_fn_doc(
+            "ctvHash",
+            "Compute the CheckTemplateVerify hash of the transaction for the input index",
+        )
+        .unwrap();
 
-    MINSC_CTV_LIB.exec(scope).unwrap();
+    MINSC_CTV_LIB.with(|lib| lib.exec(scope)).unwrap();
 }
 
 #[allow(non_snake_case)]
 pub mod fns {
     use super::*;
 
-    /// ctvHash(Array tx_instructions, Number index=0) -> Hash
+    /// ctvHash(Array tx_instructions, Number index=0|Null) -> Hash
     ///
     /// Example: ctvHash([ txVersion(1), txOut($bob_pk, 10000), txOut($alice_pk, 25000) ])
     pub fn ctvHash(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
         ensure!(matches!(args.len(), 1 | 2), Error::InvalidArguments);
         let tx_instructions = args.remove(0).into_array()?;
-        let input_index = args.pop().map_or(Ok(0), |v| v.into_u32())?;
+        let input_index = args
+            .pop()
+            .and_then(Value::into_option)
+            .map_or(Ok(0), |v| v.into_u32())?;
 
         let tx = build_tx(tx_instructions)?;
         let hash = get_ctv_hash(&tx, input_index);
@@ -59,7 +76,7 @@ fn build_tx(mut instructions: Vec<Value>) -> Result<Transaction> {
     };
 
     // Support short form with just a single output instruction (i.e. ctvHash(txOut($bob_pk)))
-    if let Some(Value::Bytes(_)) = instructions.get(0) {
+    if let Some(Value::String(_) | Value::Bytes(_)) = instructions.get(0) {
         instructions = vec![Value::Array(instructions)];
     }
 
@@ -75,7 +92,11 @@ fn build_tx(mut instructions: Vec<Value>) -> Result<Transaction> {
                 previous_output: Default::default(),
                 script_sig: Default::default(),
                 witness: Default::default(),
-                sequence: inst.pop().map_or(Ok(u32::MAX), |v| v.into_u32())?,
+                // A Null sequence uses the default (final) u32::MAX
+                sequence: inst
+                    .pop()
+                    .and_then(Value::into_option)
+                    .map_or(Ok(u32::MAX), |v| v.into_u32())?,
             }),
             ("output", 2) => tx.output.push(TxOut {
                 script_pubkey: inst.remove(0).into_spk()?,
@@ -1,6 +1,7 @@
 use std::convert::TryInto;
 
 use crate::runtime::Value;
+use crate::stdlib::array::match_predicate;
 use crate::util::DescriptorExt;
 use crate::{Descriptor, Policy, Result, Scope};
 
@@ -8,34 +9,155 @@ const LIKELY_PROB: usize = 10;
 
 pub fn attach_stdlib(scope: &mut Scope) {
     // Miniscript Policy functions exposed in the Minsc runtime
-    scope.set_fn("or", fns::or).unwrap();
-    scope.set_fn("and", fns::and).unwrap();
-    scope.set_fn("thresh", fns::thresh).unwrap();
-    scope.set_fn("older", fns::older).unwrap();
-    scope.set_fn("after", fns::after).unwrap();
-    scope.set_fn("pk", fns::pk).unwrap();
-    scope.set_fn("sha256", fns::sha256).unwrap();
-    scope.set_fn("hash256", fns::hash256).unwrap();
-    scope.set_fn("ripemd160", fns::ripemd160).unwrap();
-    scope.set_fn("hash160", fns::hash160).unwrap();
+    scope
+        .set_fn_typed("or", fns::or, "PolicyLike|WithProb... -> Policy")
+        .unwrap();
+    scope
+        .set_fn_typed("and", fns::and, "PolicyLike... -> Policy")
+        .unwrap();
+    scope
+        .set_fn_typed("thresh", fns::thresh, "Number, PolicyLike... -> Policy")
+        .unwrap();
+    scope
+        .set_fn_typed("older", fns::older, "Number -> Policy")
+        .unwrap();
+    scope
+        .set_fn_typed("after", fns::after, "Number -> Policy")
+        .unwrap();
+    scope
+        .set_fn_typed("pk", fns::pk, "KeyLike -> Policy")
+        .unwrap();
+    scope
+        .set_fn_typed("sha256", fns::sha256, "Bytes -> Policy")
+        .unwrap();
+    scope
+        .set_fn_typed("hash256", fns::hash256, "Bytes -> Policy")
+        .unwrap();
+    scope
+        .set_fn_typed("ripemd160", fns::ripemd160, "Bytes -> Policy")
+        .unwrap();
+    scope
+        .set_fn_typed("hash160", fns::hash160, "Bytes -> Policy")
+        .unwrap();
 
     // Descriptor functions
-    scope.set_fn("wpkh", fns::wpkh).unwrap();
-    scope.set_fn("wsh", fns::wsh).unwrap();
-    scope.set_fn("sh", fns::sh).unwrap();
+    scope
+        .set_fn_typed("wpkh", fns::wpkh, "KeyLike -> Descriptor")
+        .unwrap();
+    scope
+        .set_fn_typed("wsh", fns::wsh, "ScriptLike -> Descriptor|Script")
+        .unwrap();
+    scope
+        .set_fn_typed("sh", fns::sh, "Descriptor -> Descriptor")
+        .unwrap();
 
     // Minsc policy functions
-    scope.set_fn("all", fns::all).unwrap();
-    scope.set_fn("any", fns::any).unwrap();
+    scope
+        .set_fn_with_params(
+            "all",
+            fns::all,
+            &["array", "predicate"],
+            "Array, Function? -> Policy|Bool",
+        )
+        .unwrap();
+    scope
+        .set_fn_with_params(
+            "any",
+            fns::any,
+            &["array", "predicate"],
+            "Array, Function? -> Policy|Bool",
+        )
+        .unwrap();
 
     // Compile policy to miniscript
-    scope.set_fn("miniscript", fns::miniscript).unwrap();
+    scope
+        .set_fn_typed(
+            "miniscript",
+            fns::miniscript,
+            "MiniscriptLike -> Miniscript",
+        )
+        .unwrap();
 
     // Compile descriptor/miniscript to script
-    scope.set_fn("script_pubkey", fns::script_pubkey).unwrap();
     scope
-        .set_fn("explicit_script", fns::explicit_script)
+        .set_fn_typed(
+            "script_pubkey",
+            fns::script_pubkey,
+            "DescriptorLike -> Script",
+        )
         .unwrap();
+    scope
+        .set_fn_typed(
+            "explicit_script",
+            fns::explicit_script,
+            "DescriptorLike -> Script",
+        )
+        .unwrap();
+
+    // Descriptions for the generated documentation (`minsc doc`)
+    for (name, doc) in &[
+        (
+            "or",
+            "A policy satisfied by any of the sub-policies, with optional `@` probabilities",
+        ),
+        ("and", "A policy satisfied by all of the sub-policies"),
+        (
+            "thresh",
+            "A policy satisfied by at least `thresh` of the sub-policies",
+        ),
+        (
+            "older",
+            "A relative timelock policy, for a number of blocks or a time duration",
+        ),
+        (
+            "after",
+            "An absolute timelock policy, for a block height or a unix timestamp",
+        ),
+        ("pk", "A policy requiring a signature by the key"),
+        (
+            "sha256",
+            "A policy requiring the SHA256 preimage of the hash",
+        ),
+        (
+            "hash256",
+            "A policy requiring the double-SHA256 preimage of the hash",
+        ),
+        (
+            "ripemd160",
+            "A policy requiring the RIPEMD160 preimage of the hash",
+        ),
+        (
+            "hash160",
+            "A policy requiring the HASH160 preimage of the hash",
+        ),
+        ("wpkh", "A P2WPKH descriptor for the key"),
+        (
+            "wsh",
+            "A P2WSH descriptor for the policy, or the P2WSH scriptPubKey for a script",
+        ),
+        ("sh", "Wrap the descriptor in P2SH"),
+        (
+            "all",
+            "A policy requiring all of the array's sub-policies (`A && B && C`), or check \
+             whether all of the elements match the predicate",
+        ),
+        (
+            "any",
+            "A policy requiring any of the array's sub-policies (`A || B || C`), or check \
+             whether any of the elements match the predicate",
+        ),
+        ("miniscript", "Compile the policy to miniscript"),
+        (
+            "script_pubkey",
+            "Get the scriptPubKey of the descriptor, policy, miniscript or key",
+        ),
+        (
+            "explicit_script",
+            "Get the witness script of the descriptor, policy or miniscript",
+        ),
+    ] {
+        scope.set_fn_doc(*name, doc).unwrap();
+    }
 
     // `likely` as an alias for 10 (i.e. `likely@pk(A) || pk(B)`)
     scope.set("likely", LIKELY_PROB).unwrap();
@@ -168,9 +290,13 @@ pub mod fns {
         Ok(descriptor.to_explicit_script()?.into())
     }
 
-    // Turn `[A,B,C]` array into an `A && B && C` policy
-    pub fn all(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
-        ensure!(args.len() == 1, Error::InvalidArguments);
+    // Turn `[A,B,C]` array into an `A && B && C` policy,
+    // or check whether all of the elements match the predicate when one is given
+    pub fn all(mut args: Vec<Value>, scope: &Scope) -> Result<Value> {
+        ensure!(matches!(args.len(), 1 | 2), Error::InvalidArguments);
+        if args.len() == 2 {
+            return match_predicate(args, scope, true);
+        }
         all_(args.remove(0))
     }
 
@@ -179,9 +305,13 @@ pub mod fns {
         Ok(Policy::Threshold(policies.len(), policies).into())
     }
 
-    // Turn `[A,B,C]` array into an `A || B || C` policy
-    pub fn any(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
-        ensure!(args.len() == 1, Error::InvalidArguments);
+    // Turn `[A,B,C]` array into an `A || B || C` policy,
+    // or check whether any of the elements match the predicate when one is given
+    pub fn any(mut args: Vec<Value>, scope: &Scope) -> Result<Value> {
+        ensure!(matches!(args.len(), 1 | 2), Error::InvalidArguments);
+        if args.len() == 2 {
+            return match_predicate(args, scope, false);
+        }
         let policies = map_policy_array(args.remove(0))?;
         Ok(Policy::Threshold(1, policies).into())
     }
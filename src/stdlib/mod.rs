@@ -1,18 +1,41 @@
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 
 use ::miniscript::bitcoin::{self, Address, Network, Script};
 use bitcoin::hashes::{sha256, Hash};
 
-use crate::runtime::{Execute, Value};
-use crate::{ast, parse_lib, time, Result, Scope};
+use crate::runtime::{Execute, SecKey, Value};
+use crate::{ast, parse_lib_source, time, Result, Scope, SourceId};
 
+pub mod array;
 pub mod ctv;
 pub mod miniscript;
 pub mod taproot;
 
-lazy_static! {
-    static ref MINSC_STDLIB: ast::Library = parse_lib(include_str!("stdlib.minsc")).unwrap();
-    static ref ELEMENTS_STDLIB: ast::Library = parse_lib(include_str!("elements.minsc")).unwrap();
+const MINSC_STDLIB_CODE: &str = include_str!("stdlib.minsc");
+const ELEMENTS_STDLIB_CODE: &str = include_str!("elements.minsc");
+
+// Source ids for the built-in libraries, used to resolve error locations within them
+pub const MINSC_STDLIB_SOURCE: SourceId = SourceId(1);
+pub const ELEMENTS_STDLIB_SOURCE: SourceId = SourceId(2);
+pub const CTV_LIB_SOURCE: SourceId = SourceId(3);
+
+// Thread-local rather than global statics, since the AST may hold (non-Sync) pre-evaluated values
+thread_local! {
+    static MINSC_STDLIB: ast::Library =
+        parse_lib_source(MINSC_STDLIB_CODE, MINSC_STDLIB_SOURCE).unwrap();
+    static ELEMENTS_STDLIB: ast::Library =
+        parse_lib_source(ELEMENTS_STDLIB_CODE, ELEMENTS_STDLIB_SOURCE).unwrap();
+}
+
+/// Get the name and source code of a built-in library
+pub fn builtin_source(source: SourceId) -> Option<(&'static str, &'static str)> {
+    Some(match source {
+        MINSC_STDLIB_SOURCE => ("<stdlib.minsc>", MINSC_STDLIB_CODE),
+        ELEMENTS_STDLIB_SOURCE => ("<elements.minsc>", ELEMENTS_STDLIB_CODE),
+        CTV_LIB_SOURCE => ("<ctv.minsc>", ctv::MINSC_CTV_LIB_CODE),
+        _ => return None,
+    })
 }
 
 /// Attach built-in functions and variables to the Minsc runtime environment
@@ -20,6 +43,7 @@ pub fn attach_stdlib(scope: &mut Scope) {
     // Boolean types
     scope.set("true", true).unwrap();
     scope.set("false", false).unwrap();
+    scope.set("null", Value::Null).unwrap();
 
     // Network types
     scope.set("signet", Network::Signet).unwrap();
@@ -30,20 +54,196 @@ pub fn attach_stdlib(scope: &mut Scope) {
         .unwrap();
 
     // Functions
-    scope.set_fn("len", fns::len).unwrap();
-    scope.set_fn("rawscript", fns::rawscript).unwrap();
-    scope.set_fn("bytes", fns::bytes).unwrap();
-    scope.set_fn("address", fns::address).unwrap();
-    scope.set_fn("repeat", fns::repeat).unwrap();
-    scope.set_fn("iif", fns::iif).unwrap();
-    scope.set_fn("le64", fns::le64).unwrap();
-    scope.set_fn("SHA256", fns::SHA256).unwrap();
+    scope
+        .set_fn_typed("len", fns::len, "Array|Bytes|String|Script|Map -> Number")
+        .unwrap();
+    scope
+        .set_fn_typed("rawscript", fns::rawscript, "BytesLike -> Script")
+        .unwrap();
+    scope
+        .set_fn_typed("bytes", fns::bytes, "BytesLike -> Bytes")
+        .unwrap();
+    scope
+        .set_fn_with_params(
+            "address",
+            fns::address,
+            &["script", "network"],
+            "DescriptorLike|ScriptLike, Network|Null? -> Address",
+        )
+        .unwrap();
+    scope
+        .set_fn_with_params(
+            "repeat",
+            fns::repeat,
+            &["count", "producer"],
+            "Number, Any -> Array",
+        )
+        .unwrap();
+    scope
+        .set_fn_with_params(
+            "iif",
+            fns::iif,
+            &["condition", "then", "else"],
+            "Bool, Any, Any -> Any",
+        )
+        .unwrap();
+    scope
+        .set_fn_typed("le64", fns::le64, "Number -> Bytes")
+        .unwrap();
+    scope
+        .set_fn_typed("SHA256", fns::SHA256, "BytesLike -> Bytes")
+        .unwrap();
+    scope
+        .set_fn_typed("format", fns::format, "String|Bytes, Any... -> String")
+        .unwrap();
+    scope
+        .set_fn_typed("isNull", fns::isNull, "Any -> Bool")
+        .unwrap();
+    scope
+        .set_fn_typed("typeof", fns::r#typeof, "Any -> String")
+        .unwrap();
+    scope
+        .set_fn_with_params(
+            "assert",
+            fns::assert,
+            &["condition", "message"],
+            "Bool, String? -> Bool",
+        )
+        .unwrap();
+    scope
+        .set_fn_typed("error", fns::error, "String -> Any")
+        .unwrap();
+    scope
+        .set_fn_typed("throw", fns::throw, "Any -> Any")
+        .unwrap();
+    scope
+        .set_fn_typed("print", fns::print, "Any... -> Null")
+        .unwrap();
+    scope
+        .set_fn_typed("trace", fns::trace, "String, Any -> Any")
+        .unwrap();
+    scope.set_fn_typed("dbg", fns::dbg, "Any -> Any").unwrap();
+    scope
+        .set_fn_typed("keys", fns::keys, "Map|Array -> Array")
+        .unwrap();
+    scope
+        .set_fn_typed("values", fns::values, "Map -> Array")
+        .unwrap();
+    scope
+        .set_fn_with_params(
+            "has",
+            fns::has,
+            &["map", "key"],
+            "Map, String|Bytes -> Bool",
+        )
+        .unwrap();
+    scope
+        .set_fn_typed("merge", fns::merge, "Map, Map... -> Map")
+        .unwrap();
+    scope
+        .set_fn_with_params(
+            "secKey",
+            fns::secKey,
+            &["key", "network"],
+            "String|Bytes, Network -> SecKey",
+        )
+        .unwrap();
+    scope
+        .set_fn_typed("revealSecKey", fns::revealSecKey, "SecKey -> String")
+        .unwrap();
+    scope
+        .set_fn_typed("pubKey", fns::pubKey, "KeyLike -> PubKey")
+        .unwrap();
+
+    // Descriptions for the generated documentation (`minsc doc`)
+    for (name, doc) in &[
+        ("len", "Get the number of elements, bytes or characters"),
+        (
+            "rawscript",
+            "Construct a script from its raw bytes encoding",
+        ),
+        ("bytes", "Get the raw bytes encoding of a script"),
+        (
+            "address",
+            "Generate the address of a descriptor or script (for signet by default)",
+        ),
+        (
+            "repeat",
+            "Create an array of `count` elements, produced by a function of the index",
+        ),
+        (
+            "iif",
+            "Return `then` or `else`, calling it if it's a (lazily evaluated) function",
+        ),
+        ("le64", "Encode the number as 64-bit little-endian bytes"),
+        (
+            "SHA256",
+            "Hash the data with SHA256 (unlike the `sha256()` policy)",
+        ),
+        (
+            "format",
+            "Replace each `{}` placeholder in the format string with the next argument",
+        ),
+        ("isNull", "Check whether the value is `null`"),
+        (
+            "typeof",
+            "Get the name of the value's runtime type, like `Policy` or `Script`",
+        ),
+        (
+            "assert",
+            "Fail with the message if the condition is false, or return true otherwise",
+        ),
+        (
+            "error",
+            "Fail with the message, as a `UserError` that can be caught by `try`/`catch`",
+        ),
+        (
+            "throw",
+            "Fail with the value, available as the `value` of the error caught by `try`/`catch`",
+        ),
+        (
+            "print",
+            "Write the values to the debugging output, separated by spaces",
+        ),
+        (
+            "trace",
+            "Write the value to the debugging output with the label, and return it",
+        ),
+        (
+            "dbg",
+            "Write the value's internal representation to the debugging output, and return it",
+        ),
+        ("keys", "Get the keys of a map, or the indexes of an array"),
+        (
+            "values",
+            "Get the values of a map, in the order of their keys",
+        ),
+        ("has", "Check whether the map has the key"),
+        (
+            "merge",
+            "Combine the maps, with keys from later maps overriding earlier ones",
+        ),
+        ("secKey", "Parse a private key for the network"),
+        (
+            "revealSecKey",
+            "Get the encoded private key, as WIF or as an xprv with its derivation path",
+        ),
+        (
+            "pubKey",
+            "Get the public key of a private key, or parse the public key bytes",
+        ),
+    ] {
+        scope.set_fn_doc(*name, doc).unwrap();
+    }
 
     // Constants
     scope.set("BLOCK_INTERVAL", time::BLOCK_INTERVAL).unwrap();
     scope.set("MAX_NUMBER", i64::MAX).unwrap();
     scope.set("MIN_NUMBER", i64::MIN).unwrap();
 
+    // Array functions
+    self::array::attach_stdlib(scope);
+
     // Miniscript related functions
     self::miniscript::attach_stdlib(scope);
 
@@ -54,27 +254,177 @@ pub fn attach_stdlib(scope: &mut Scope) {
     self::ctv::attach_stdlib(scope);
 
     // Standard library implemented in Minsc
-    MINSC_STDLIB.exec(scope).unwrap();
-    ELEMENTS_STDLIB.exec(scope).unwrap();
+    MINSC_STDLIB.with(|lib| lib.exec(scope)).unwrap();
+    ELEMENTS_STDLIB.with(|lib| lib.exec(scope)).unwrap();
 }
 
 pub mod fns {
     use super::*;
     use crate::function::Call;
+    use crate::types::Type;
     use crate::Error;
 
-    // len(Array|Bytes|Script) -> Number
+    // len(Array|Bytes|String|Script|Map) -> Number
     pub fn len(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
         ensure!(args.len() == 1, Error::InvalidArguments);
         Ok(match args.remove(0) {
             Value::Array(elements) => elements.len(),
             Value::Bytes(bytes) => bytes.len(),
+            Value::String(string) => string.chars().count(),
             Value::Script(script) => script.into_bytes().len(),
+            Value::Map(map) => map.len(),
             _ => bail!(Error::InvalidArguments),
         }
         .into())
     }
 
+    /// format(String, ...Value) -> String
+    ///
+    /// Replace each `{}` placeholder in the format string with the next argument
+    pub fn format(mut args: Vec<Value>, scope: &Scope) -> Result<Value> {
+        ensure!(!args.is_empty(), Error::InvalidArguments);
+        let format_str = args.remove(0).into_string()?;
+
+        let parts: Vec<&str> = format_str.split("{}").collect();
+        ensure!(
+            parts.len() - 1 == args.len(),
+            Error::FormatArgsMismatch(parts.len() - 1, args.len())
+        );
+
+        let mut string = parts[0].to_string();
+        for (arg, part) in args.into_iter().zip(&parts[1..]) {
+            string.push_str(&arg.to_string());
+            string.push_str(part);
+        }
+        scope.alloc(string.len())?;
+        Ok(string.into())
+    }
+
+    /// isNull(Value) -> Bool
+    #[allow(non_snake_case)]
+    pub fn isNull(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
+        ensure!(args.len() == 1, Error::InvalidArguments);
+        Ok(args.remove(0).is_null().into())
+    }
+
+    /// typeof(Value) -> String
+    pub fn r#typeof(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
+        ensure!(args.len() == 1, Error::InvalidArguments);
+        Ok(Type::of(&args.remove(0)).to_string().into())
+    }
+
+    /// assert(Bool condition, String message=None) -> Bool
+    ///
+    /// Fail with the message if the condition is false, or return true otherwise
+    pub fn assert(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
+        ensure!(args.len() == 1 || args.len() == 2, Error::InvalidArguments);
+        let message = args.get(1).cloned().map(Value::into_string).transpose()?;
+        ensure!(args.remove(0).into_bool()?, Error::AssertionFailed(message));
+        Ok(true.into())
+    }
+
+    /// error(String message) -> Never
+    pub fn error(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
+        ensure!(args.len() == 1, Error::InvalidArguments);
+        Err(Error::UserError(args.remove(0).into_string()?))
+    }
+
+    /// throw(Value) -> Never
+    pub fn throw(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
+        ensure!(args.len() == 1, Error::InvalidArguments);
+        Err(Error::Thrown(args.remove(0)))
+    }
+
+    /// print(Value...) -> Null
+    pub fn print(args: Vec<Value>, scope: &Scope) -> Result<Value> {
+        let values: Vec<String> = args.iter().map(Value::to_string).collect();
+        scope.write_output(&values.join(" "))?;
+        Ok(Value::Null)
+    }
+
+    /// trace(String label, Value) -> Value
+    pub fn trace(mut args: Vec<Value>, scope: &Scope) -> Result<Value> {
+        ensure!(args.len() == 2, Error::InvalidArguments);
+        let label = args.remove(0).into_string()?;
+        let value = args.remove(0);
+        scope.write_output(&format!("{}: {}", label, value))?;
+        Ok(value)
+    }
+
+    /// dbg(Value) -> Value
+    pub fn dbg(mut args: Vec<Value>, scope: &Scope) -> Result<Value> {
+        ensure!(args.len() == 1, Error::InvalidArguments);
+        let value = args.remove(0);
+        scope.write_output(&format!("{:?}", value))?;
+        Ok(value)
+    }
+
+    /// secKey(String, Network) -> SecKey
+    ///
+    /// Parse a private key for the given network. This is the only way to use mainnet private keys,
+    /// by passing the `_$$_RECKLESSLY_RISK_MY_BITCOINS_$$_` network.
+    #[allow(non_snake_case)]
+    pub fn secKey(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
+        ensure!(args.len() == 2, Error::InvalidArguments);
+        let key = args.remove(0).into_string()?;
+        let network = args.remove(0).try_into()?;
+        Ok(SecKey::parse(&key, Some(network))?.into())
+    }
+
+    /// revealSecKey(SecKey) -> String
+    #[allow(non_snake_case)]
+    pub fn revealSecKey(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
+        ensure!(args.len() == 1, Error::InvalidArguments);
+        Ok(args.remove(0).into_seckey()?.reveal().into())
+    }
+
+    /// pubKey(SecKey|PubKey|Bytes) -> PubKey
+    #[allow(non_snake_case)]
+    pub fn pubKey(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
+        ensure!(args.len() == 1, Error::InvalidArguments);
+        Ok(args.remove(0).into_key()?.into())
+    }
+
+    /// keys(Map) -> Array<String>
+    /// keys(Array) -> Array<Number>
+    pub fn keys(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
+        ensure!(args.len() == 1, Error::InvalidArguments);
+        Ok(match args.remove(0) {
+            Value::Map(map) => map.into_keys().map(Value::from).collect::<Vec<_>>(),
+            Value::Array(elements) => (0..elements.len()).map(Value::from).collect(),
+            v => bail!(Error::NotMap(v)),
+        }
+        .into())
+    }
+
+    /// values(Map) -> Array
+    pub fn values(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
+        ensure!(args.len() == 1, Error::InvalidArguments);
+        let map = args.remove(0).into_map()?;
+        Ok(map.into_values().collect::<Vec<_>>().into())
+    }
+
+    /// has(Map, String key) -> Bool
+    pub fn has(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
+        ensure!(args.len() == 2, Error::InvalidArguments);
+        let map = args.remove(0).into_map()?;
+        let key = args.remove(0).into_string()?;
+        Ok(map.contains_key(&key).into())
+    }
+
+    /// merge(Map, ...Map) -> Map
+    ///
+    /// Combine the maps, with keys from later maps overriding earlier ones
+    pub fn merge(args: Vec<Value>, scope: &Scope) -> Result<Value> {
+        ensure!(!args.is_empty(), Error::InvalidArguments);
+        let mut merged = BTreeMap::new();
+        for map in args {
+            merged.extend(map.into_map()?);
+        }
+        scope.alloc(merged.len())?;
+        Ok(merged.into())
+    }
+
     // rawscript(Bytes) -> Script
     pub fn rawscript(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
         ensure!(args.len() == 1, Error::InvalidArguments);
@@ -95,7 +445,10 @@ pub mod fns {
         ensure!(args.len() == 1 || args.len() == 2, Error::InvalidArguments);
 
         let spk = args.remove(0).into_spk()?;
-        let network = args.pop().map_or(Ok(Network::Signet), TryInto::try_into)?;
+        let network = args
+            .pop()
+            .and_then(Value::into_option)
+            .map_or(Ok(Network::Signet), TryInto::try_into)?;
 
         Ok(Address::from_script(&spk, network)
             .ok_or_else(|| Error::NotAddressable(spk))?
@@ -105,6 +458,7 @@ pub mod fns {
     pub fn repeat(mut args: Vec<Value>, scope: &Scope) -> Result<Value> {
         ensure!(args.len() == 2, Error::InvalidArguments);
         let num = args.remove(0).into_usize()?;
+        scope.alloc(num)?;
         let producer = args.remove(0);
         Ok(Value::Array(
             (0..num)
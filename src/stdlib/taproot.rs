@@ -12,32 +12,82 @@ use crate::util::EC;
 use crate::{Error, Result, Scope, Value};
 
 pub fn attach_stdlib(scope: &mut Scope) {
-    scope.set_fn("tapLeaf", fns::tapLeaf).unwrap();
-    scope.set_fn("tapBranch", fns::tapBranch).unwrap();
-    scope.set_fn("tapTweak", fns::tapTweak).unwrap();
-    scope.set_fn("tapTreeRoot", fns::tapTreeRoot).unwrap();
+    scope
+        .set_fn_with_params(
+            "tapLeaf",
+            fns::tapLeaf,
+            &["script", "version"],
+            "ScriptLike, Number|Bytes|Null? -> Bytes",
+        )
+        .unwrap();
+    scope
+        .set_fn_with_params(
+            "tapBranch",
+            fns::tapBranch,
+            &["node_a", "node_b"],
+            "Bytes, Bytes -> Bytes",
+        )
+        .unwrap();
+    scope
+        .set_fn_with_params(
+            "tapTweak",
+            fns::tapTweak,
+            &["internal_key", "script_tree"],
+            "KeyLike, ScriptLike|Array|Null? -> Script",
+        )
+        .unwrap();
+    scope
+        .set_fn_typed(
+            "tapTreeRoot",
+            fns::tapTreeRoot,
+            "ScriptLike|Array|Null -> Bytes|Null",
+        )
+        .unwrap();
+
+    // Descriptions for the generated documentation (`minsc doc`)
+    for (name, doc) in &[
+        (
+            "tapLeaf",
+            "Compute the leaf hash of the script (with leaf version 0xc0 by default)",
+        ),
+        (
+            "tapBranch",
+            "Combine two nodes to create a new TapBranch parent",
+        ),
+        (
+            "tapTweak",
+            "Tweak the internal key with the script tree and return the v1 scriptPubKey",
+        ),
+        (
+            "tapTreeRoot",
+            "Compute the merkle root hash of the script tree",
+        ),
+    ] {
+        scope.set_fn_doc(*name, doc).unwrap();
+    }
 }
 
 #[allow(non_snake_case)]
 pub mod fns {
     use super::*;
 
-    /// tapLeaf(Script, version=0xc0) -> Hash
+    /// tapLeaf(Script, version=0xc0|Null) -> Hash
     ///
     /// Compute the leaf hash of the given script
     pub fn tapLeaf(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
         ensure!(matches!(args.len(), 1 | 2), Error::InvalidArguments);
         let script = args.remove(0).into_script()?;
-        let leaf_ver = args
-            .pop()
-            .map_or(Ok(LeafVersion::TapScript), |v| -> Result<_> {
+        let leaf_ver = args.pop().and_then(Value::into_option).map_or(
+            Ok(LeafVersion::TapScript),
+            |v| -> Result<_> {
                 let leaf_ver = match v {
                     Value::Number(num) => num.try_into()?,
                     Value::Bytes(bytes) if bytes.len() == 1 => bytes[0],
                     _ => bail!(Error::InvalidArguments),
                 };
                 Ok(LeafVersion::from_consensus(leaf_ver)?)
-            })?;
+            },
+        )?;
         let leaf_hash = TapLeafHash::from_script(&script, leaf_ver);
         Ok(Value::Bytes(leaf_hash.into_inner().to_vec()))
     }
@@ -56,7 +106,7 @@ pub mod fns {
         Ok(Value::Bytes(branch.into_inner().to_vec()))
     }
 
-    /// tapTweak(PubKey internal_key, Mixed) -> Script
+    /// tapTweak(PubKey internal_key, Mixed|Null) -> Script
     ///
     /// Tweak the internal key with the given script tree and return the v1 output SPK
     /// The second argument can be a 32 bytes hash or anything accepted by tree_root()
@@ -70,7 +120,7 @@ pub mod fns {
         Ok(output_spk.into())
     }
 
-    /// tapTreeRoot(Script|Array) -> Hash merkle_root
+    /// tapTreeRoot(Script|Array|Null) -> Hash merkle_root|Null
     ///
     /// Compute the merkle root hash for the given script tree
     pub fn tapTreeRoot(mut args: Vec<Value>, _: &Scope) -> Result<Value> {
@@ -78,10 +128,8 @@ pub mod fns {
 
         let merkle_root = tree_root(args.remove(0))?;
 
-        Ok(Value::Bytes(
-            // Return empty tree as an empty Bytes vector (Minsc doesn't yet have Null/None)
-            merkle_root.map_or_else(Vec::new, |r| r.into_inner().to_vec()),
-        ))
+        // Return an empty tree as Null
+        Ok(merkle_root.map_or(Value::Null, |r| r.into_inner().to_vec().into()))
     }
 }
 
@@ -90,7 +138,8 @@ pub fn tap_tweak(internal_key: Value, script_tree: Option<Value>) -> Result<Scri
     let internal_key = internal_key.into_key()?.derive_public_key(&EC)?;
     let internal_key: XOnlyPublicKey = internal_key.inner.into();
 
-    // When there's no script tree, the second argument can be omitted entirely or provided as en empty byte vector (`0x`)
+    // When there's no script tree, the second argument can be omitted entirely or provided as Null
+    // (or as an empty byte vector `0x`, for compatibility)
     // The second argument can be anything accepted
     let merkle_root = script_tree.map_or(Ok(None), tree_root)?;
 
@@ -104,7 +153,9 @@ pub fn tap_tweak(internal_key: Value, script_tree: Option<Value>) -> Result<Scri
 
 fn tree_root(root: Value) -> Result<Option<TapBranchHash>> {
     Ok(match root {
-        // Empty arrays and empty bytes are treated as an empty script tree (key-path only)
+        // Null is an empty script tree (key-path only). Empty arrays and empty bytes are also
+        // accepted as an empty tree, for compatibility.
+        Value::Null => None,
         Value::Array(nodes) if nodes.len() == 0 => None,
         Value::Bytes(bytes) if bytes.len() == 0 => None,
 
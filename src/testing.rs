@@ -0,0 +1,67 @@
+//! The test runner for `test "name" { ... }` declarations.
+//!
+//! The program's definitions are executed once, then each test is run in its own child scope of them,
+//! with its own resource budget. A test passes if it completes without an error (like a failed `assert()`)
+//! and its return value, if it has one, is not `false`.
+
+use std::rc::Rc;
+
+use crate::ast::{self, Expr, ExprKind, Span, StmtKind};
+use crate::runtime::Execute;
+use crate::{types, Budget, Error, Evaluate, Limits, Result, Scope, Value};
+
+/// The outcome of a single test
+#[derive(Debug)]
+pub struct TestResult {
+    pub name: String,
+    pub span: Span,
+    pub result: Result<()>,
+}
+
+/// Run the tests declared at the top-level of the program, with the built-ins resolved from `scope`.
+///
+/// Fails if the program itself fails to type-check or if its (non-test) statements fail to execute.
+/// Its return value is not evaluated.
+pub fn run_tests(program: &Expr, scope: &Scope) -> Result<Vec<TestResult>> {
+    types::check(program, scope)?;
+
+    let stmts = match &program.kind {
+        ExprKind::Block(block) => &block.stmts,
+        _ => return Ok(vec![]),
+    };
+    let mut defs = scope.child();
+    for stmt in stmts {
+        stmt.exec(&mut defs)?;
+    }
+
+    Ok(stmts
+        .iter()
+        .filter_map(|stmt| match &stmt.kind {
+            StmtKind::Test(test) => Some(TestResult {
+                name: test.name.clone(),
+                span: stmt.span,
+                result: run_test(test, &defs),
+            }),
+            _ => None,
+        })
+        .collect())
+}
+
+fn run_test(test: &ast::Test, defs: &Scope) -> Result<()> {
+    let budget = Rc::new(Budget::new(Limits::default()));
+    let mut scope = defs.child_with_budget(Some(budget));
+    let body = match &test.body.kind {
+        ExprKind::Block(block) => block,
+        _ => unreachable!("test bodies are always parsed as a Block"),
+    };
+    for stmt in &body.stmts {
+        stmt.exec(&mut scope)?;
+    }
+    if let Some(return_value) = &body.return_value {
+        if let Value::Bool(false) = return_value.eval(&scope)? {
+            let err = Error::AssertionFailed(Some("test returned false".into()));
+            bail!(err.with_span(return_value.span));
+        }
+    }
+    Ok(())
+}
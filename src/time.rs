@@ -76,3 +76,13 @@ pub fn parse_datetime(s: &str) -> Result<u32> {
     );
     Ok(ts as u32)
 }
+
+/// Check whether the relative `older()` sequence number is time-based (rather than block height based)
+pub fn is_time_based_seq(seq: i64) -> bool {
+    seq & SEQUENCE_LOCKTIME_TYPE_FLAG as i64 != 0
+}
+
+/// Check whether the absolute `after()` locktime is a unix timestamp (rather than a block height)
+pub fn is_timestamp_locktime(locktime: i64) -> bool {
+    locktime >= LOCKTIME_THRESHOLD as i64
+}
@@ -0,0 +1,719 @@
+//! Static type inference and checking, performed over the AST before evaluation.
+//!
+//! Types are sets of the possible `Value` kinds that an expression may evaluate into. The checker
+//! only reports definite mismatches, where none of the possible kinds are acceptable. Anything it
+//! cannot infer (like unannotated function parameters) is treated as `Any` and left for the runtime.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use crate::ast::{self, Expr, ExprKind, Ident, InfixOp, Pattern, Stmt, StmtKind};
+use crate::function::Function;
+use crate::{Error, Result, Scope, Value};
+
+/// A set of `Value` kinds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Type(u32);
+
+impl Type {
+    pub const PUBKEY: Type = Type(1 << 0);
+    pub const SECKEY: Type = Type(1 << 1);
+    pub const BYTES: Type = Type(1 << 2);
+    pub const STRING: Type = Type(1 << 3);
+    pub const NUMBER: Type = Type(1 << 4);
+    pub const BOOL: Type = Type(1 << 5);
+    pub const NETWORK: Type = Type(1 << 6);
+    pub const NULL: Type = Type(1 << 7);
+    pub const POLICY: Type = Type(1 << 8);
+    pub const WITH_PROB: Type = Type(1 << 9);
+    pub const MINISCRIPT: Type = Type(1 << 10);
+    pub const DESCRIPTOR: Type = Type(1 << 11);
+    pub const SCRIPT: Type = Type(1 << 12);
+    pub const ADDRESS: Type = Type(1 << 13);
+    pub const FUNCTION: Type = Type(1 << 14);
+    pub const ARRAY: Type = Type(1 << 15);
+    pub const MAP: Type = Type(1 << 16);
+
+    pub const NEVER: Type = Type(0);
+    pub const ANY: Type = Type((1 << 17) - 1);
+
+    // The kinds accepted by the Value conversions (i.e. `into_key()` or `into_policy()`)
+    pub const KEY_LIKE: Type = Type(Self::PUBKEY.0 | Self::SECKEY.0 | Self::BYTES.0);
+    pub const BYTES_LIKE: Type = Type(Self::BYTES.0 | Self::STRING.0 | Self::SCRIPT.0);
+    pub const POLICY_LIKE: Type =
+        Type(Self::POLICY.0 | Self::PUBKEY.0 | Self::SECKEY.0 | Self::ARRAY.0);
+    pub const MINISCRIPT_LIKE: Type = Type(Self::MINISCRIPT.0 | Self::POLICY.0);
+    pub const DESCRIPTOR_LIKE: Type =
+        Type(Self::DESCRIPTOR.0 | Self::MINISCRIPT_LIKE.0 | Self::PUBKEY.0 | Self::SECKEY.0);
+    pub const SCRIPT_LIKE: Type = Type(Self::SCRIPT.0 | Self::BYTES.0 | Self::MINISCRIPT_LIKE.0);
+
+    const KINDS: [(Type, &'static str); 17] = [
+        (Self::PUBKEY, "PubKey"),
+        (Self::SECKEY, "SecKey"),
+        (Self::BYTES, "Bytes"),
+        (Self::STRING, "String"),
+        (Self::NUMBER, "Number"),
+        (Self::BOOL, "Bool"),
+        (Self::NETWORK, "Network"),
+        (Self::NULL, "Null"),
+        (Self::POLICY, "Policy"),
+        (Self::WITH_PROB, "WithProb"),
+        (Self::MINISCRIPT, "Miniscript"),
+        (Self::DESCRIPTOR, "Descriptor"),
+        (Self::SCRIPT, "Script"),
+        (Self::ADDRESS, "Address"),
+        (Self::FUNCTION, "Function"),
+        (Self::ARRAY, "Array"),
+        (Self::MAP, "Map"),
+    ];
+
+    const ALIASES: [(Type, &'static str); 7] = [
+        (Self::ANY, "Any"),
+        (Self::KEY_LIKE, "KeyLike"),
+        (Self::BYTES_LIKE, "BytesLike"),
+        (Self::POLICY_LIKE, "PolicyLike"),
+        (Self::MINISCRIPT_LIKE, "MiniscriptLike"),
+        (Self::DESCRIPTOR_LIKE, "DescriptorLike"),
+        (Self::SCRIPT_LIKE, "ScriptLike"),
+    ];
+
+    /// The type of a runtime value
+    pub fn of(value: &Value) -> Type {
+        match value {
+            Value::PubKey(_) => Self::PUBKEY,
+            Value::SecKey(_) => Self::SECKEY,
+            Value::Bytes(_) => Self::BYTES,
+            Value::String(_) => Self::STRING,
+            Value::Number(_) => Self::NUMBER,
+            Value::Bool(_) => Self::BOOL,
+            Value::Network(_) => Self::NETWORK,
+            Value::Null => Self::NULL,
+            Value::Policy(_) => Self::POLICY,
+            Value::WithProb(..) => Self::WITH_PROB,
+            Value::Miniscript(_) => Self::MINISCRIPT,
+            Value::Descriptor(_) => Self::DESCRIPTOR,
+            Value::Script(_) => Self::SCRIPT,
+            Value::Address(_) => Self::ADDRESS,
+            Value::Function(_) => Self::FUNCTION,
+            Value::Array(_) => Self::ARRAY,
+            Value::Map(_) => Self::MAP,
+        }
+    }
+
+    pub fn union(self, other: Type) -> Type {
+        Type(self.0 | other.0)
+    }
+
+    pub fn intersect(self, other: Type) -> Type {
+        Type(self.0 & other.0)
+    }
+
+    pub fn without(self, other: Type) -> Type {
+        Type(self.0 & !other.0)
+    }
+
+    /// Whether any of the kinds is shared with the other type
+    pub fn intersects(self, other: Type) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// Whether all of the other type's kinds are included in this one
+    pub fn contains(self, other: Type) -> bool {
+        other.0 & !self.0 == 0
+    }
+
+    pub fn is_never(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Ensure that the runtime value matches this type, used for annotated bindings
+    pub fn ensure(self, value: &Value) -> Result<()> {
+        let value_type = Type::of(value);
+        ensure!(
+            self.contains(value_type),
+            Error::TypeMismatch(self, value_type)
+        );
+        Ok(())
+    }
+
+    /// Iterate over the individual kinds in this type
+    fn kinds(self) -> impl Iterator<Item = Type> {
+        Self::KINDS
+            .iter()
+            .map(|(kind, _)| *kind)
+            .filter(move |kind| self.intersects(*kind))
+    }
+}
+
+impl std::ops::BitOr for Type {
+    type Output = Type;
+    fn bitor(self, other: Type) -> Type {
+        self.union(other)
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let named = Self::ALIASES.iter().chain(&Self::KINDS);
+        if let Some((_, name)) = named.clone().find(|(ty, _)| ty == self) {
+            return f.write_str(name);
+        }
+        if self.is_never() {
+            return f.write_str("Never");
+        }
+        let names: Vec<&str> = Self::KINDS
+            .iter()
+            .filter(|(kind, _)| self.intersects(*kind))
+            .map(|(_, name)| *name)
+            .collect();
+        f.write_str(&names.join("|"))
+    }
+}
+
+/// Parse a type name or a `|`-separated union of them (`PubKey|Bytes`)
+impl FromStr for Type {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        s.split('|').try_fold(Type::NEVER, |ty, name| {
+            let name = name.trim();
+            let (kind, _) = Self::ALIASES
+                .iter()
+                .chain(&Self::KINDS)
+                .find(|(_, kind_name)| *kind_name == name)
+                .ok_or_else(|| Error::InvalidType(name.into()))?;
+            Ok(ty.union(*kind))
+        })
+    }
+}
+
+/// The type signature of a function
+#[derive(Debug, Clone)]
+pub struct FnType {
+    pub params: Vec<Type>,
+    /// The number of required parameters, the ones following it are optional
+    pub required: usize,
+    /// The type of the variadic arguments, if the function accepts them
+    pub rest: Option<Type>,
+    pub ret: Type,
+}
+
+impl FnType {
+    /// The type of a user function, using the annotated types for its parameters
+    pub fn from_signature(signature: &ast::Signature, ret: Type) -> Self {
+        FnType {
+            params: signature.params.iter().map(param_type).collect(),
+            required: signature.min_args(),
+            rest: signature.rest.as_ref().map(|_| Type::ANY),
+            ret,
+        }
+    }
+
+    pub fn max_args(&self) -> Option<usize> {
+        iif!(self.rest.is_none(), Some(self.params.len()), None)
+    }
+}
+
+fn param_type(param: &ast::Param) -> Type {
+    match (param.ty, &param.pattern) {
+        (Some(ty), _) => ty,
+        (None, Pattern::Array { .. }) => Type::ARRAY,
+        (None, Pattern::Ident(_)) => Type::ANY,
+    }
+}
+
+/// Parse a native function signature, like `ScriptLike, Number|Null? -> Bytes`.
+/// Optional parameters are suffixed with `?`, and a final variadic parameter with `...`.
+impl FromStr for FnType {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (params, ret) = s
+            .split_once("->")
+            .ok_or_else(|| Error::InvalidType(s.into()))?;
+        let mut fn_type = FnType {
+            params: vec![],
+            required: 0,
+            rest: None,
+            ret: ret.parse()?,
+        };
+        for param in params.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            if let Some(rest) = param.strip_suffix("...") {
+                fn_type.rest = Some(rest.parse()?);
+            } else if let Some(optional) = param.strip_suffix('?') {
+                fn_type.params.push(optional.parse()?);
+            } else {
+                fn_type.params.push(param.parse()?);
+                fn_type.required = fn_type.params.len();
+            }
+        }
+        Ok(fn_type)
+    }
+}
+
+/// Check the program for type errors before evaluating it in the given scope.
+/// Returns the first error found.
+pub fn check(expr: &Expr, scope: &Scope) -> Result<()> {
+    Checker::new(scope).infer(expr)?;
+    Ok(())
+}
+
+struct Checker<'a> {
+    /// The runtime scope the program will be evaluated in, used to look up the built-ins
+    scope: &'a Scope,
+    frames: Vec<Frame>,
+}
+
+#[derive(Default)]
+struct Frame {
+    vars: HashMap<Ident, Binding>,
+    /// Set following an import statement, which may bring any definitions into the scope
+    opaque: bool,
+}
+
+#[derive(Clone)]
+struct Binding {
+    ty: Type,
+    func: Option<Rc<FnType>>,
+}
+
+impl Binding {
+    fn of(ty: Type) -> Self {
+        Binding { ty, func: None }
+    }
+
+    fn of_value(value: &Value) -> Self {
+        let func = match value {
+            Value::Function(Function::Native(native)) => native.sig.clone(),
+            Value::Function(Function::User(user)) => {
+                Some(Rc::new(FnType::from_signature(&user.signature, Type::ANY)))
+            }
+            _ => None,
+        };
+        Binding {
+            ty: Type::of(value),
+            func,
+        }
+    }
+}
+
+impl<'a> Checker<'a> {
+    fn new(scope: &'a Scope) -> Self {
+        Checker {
+            scope,
+            frames: vec![],
+        }
+    }
+
+    fn lookup(&self, ident: &Ident) -> Binding {
+        for frame in self.frames.iter().rev() {
+            if let Some(binding) = frame.vars.get(ident) {
+                return binding.clone();
+            } else if frame.opaque {
+                return Binding::of(Type::ANY);
+            }
+        }
+        self.scope
+            .get(ident)
+            .map_or(Binding::of(Type::ANY), |value| Binding::of_value(&value))
+    }
+
+    fn define(&mut self, ident: &Ident, binding: Binding) {
+        let frame = self.frames.last_mut().expect("within a frame");
+        frame.vars.insert(ident.clone(), binding);
+    }
+
+    fn define_pattern(&mut self, pattern: &Pattern, ty: Type) -> Result<()> {
+        match pattern {
+            Pattern::Ident(ident) => self.define(ident, Binding::of(ty)),
+            Pattern::Array { elements, rest } => {
+                ensure!(
+                    ty.intersects(Type::ARRAY),
+                    Error::TypeMismatch(Type::ARRAY, ty)
+                );
+                for element in elements {
+                    self.define_pattern(element, Type::ANY)?;
+                }
+                if let Some(rest) = rest {
+                    self.define(rest, Binding::of(Type::ARRAY));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn infer(&mut self, expr: &Expr) -> Result<Type> {
+        self.infer_kind(&expr.kind)
+            .map_err(|e| e.with_span(expr.span))
+    }
+
+    /// Infer the expression type and ensure it matches the expected one, narrowing it down
+    fn infer_expect(&mut self, expr: &Expr, expected: Type) -> Result<Type> {
+        let ty = self.infer(expr)?;
+        ensure!(
+            ty.intersects(expected),
+            Error::TypeMismatch(expected, ty).with_span(expr.span)
+        );
+        Ok(ty.intersect(expected))
+    }
+
+    fn infer_kind(&mut self, kind: &ExprKind) -> Result<Type> {
+        Ok(match kind {
+            ExprKind::Ident(ident) => self.lookup(ident).ty,
+            ExprKind::Call(call) => self.call(call)?,
+            ExprKind::Or(ast::Or(operands)) | ExprKind::And(ast::And(operands)) => {
+                self.andor(operands)?
+            }
+            ExprKind::Thresh(x) => {
+                self.infer_expect(&x.thresh, Type::NUMBER)?;
+                self.infer_expect(&x.policies, Type::POLICY_LIKE)?;
+                Type::POLICY
+            }
+            ExprKind::Block(block) => self.block(block)?,
+            ExprKind::Array(ast::Array(elements)) => {
+                for element in elements {
+                    self.infer(element)?;
+                }
+                Type::ARRAY
+            }
+            ExprKind::Map(ast::Map(entries)) => {
+                for (_, value) in entries {
+                    self.infer(value)?;
+                }
+                Type::MAP
+            }
+            ExprKind::ArrayAccess(x) => {
+                let array = self.infer_expect(&x.array, Type::ARRAY | Type::BYTES | Type::MAP)?;
+                self.infer_expect(&x.index, Type::NUMBER | Type::STRING | Type::BYTES)?;
+                // Bytes are accessed as their Number values
+                iif!(array == Type::BYTES, Type::NUMBER, Type::ANY)
+            }
+            ExprKind::Slice(x) => {
+                let value = self.infer_expect(&x.value, Type::ARRAY | Type::BYTES)?;
+                for bound in x.start.iter().chain(&x.end) {
+                    self.infer_expect(bound, Type::NUMBER)?;
+                }
+                value
+            }
+            ExprKind::Range(x) => {
+                self.infer_expect(&x.start, Type::NUMBER)?;
+                self.infer_expect(&x.end, Type::NUMBER)?;
+                Type::ARRAY
+            }
+            ExprKind::ChildDerive(x) => {
+                // Numbers are divided, everything else is derived
+                let derivable = Type::NUMBER
+                    | Type::PUBKEY
+                    | Type::SECKEY
+                    | Type::DESCRIPTOR
+                    | Type::MINISCRIPT
+                    | Type::POLICY
+                    | Type::ARRAY;
+                let parent = self.infer_expect(&x.parent, derivable)?;
+                for step in &x.path {
+                    self.infer_expect(&step.child, Type::NUMBER | Type::BYTES)?;
+                }
+                parent
+            }
+            ExprKind::ScriptFrag(x) => {
+                let fragment = Type::SCRIPT_LIKE
+                    | Type::NUMBER
+                    | Type::BOOL
+                    | Type::STRING
+                    | Type::PUBKEY
+                    | Type::SECKEY
+                    | Type::ARRAY;
+                for part in &x.fragments {
+                    self.infer_expect(part, fragment)?;
+                }
+                Type::SCRIPT
+            }
+            ExprKind::FnExpr(x) => {
+                self.function(&x.signature, &x.body)?;
+                Type::FUNCTION
+            }
+            ExprKind::Infix(x) => {
+                let (lhs, rhs) = (self.infer(&x.lhs)?, self.infer(&x.rhs)?);
+                let result = lhs.kinds().fold(Type::NEVER, |result, a| {
+                    rhs.kinds()
+                        .filter_map(|b| infix_type(x.op, a, b))
+                        .fold(result, Type::union)
+                });
+                ensure!(
+                    !result.is_never(),
+                    Error::OpError(x.op, Error::InvalidOperands(lhs, rhs).into())
+                );
+                result
+            }
+            ExprKind::Not(ast::Not(x)) => {
+                self.infer_expect(x, Type::BOOL)?;
+                Type::BOOL
+            }
+            ExprKind::Neg(ast::Neg(x)) => {
+                self.infer_expect(x, Type::NUMBER)?;
+                Type::NUMBER
+            }
+            ExprKind::IfExpr(x) => {
+                self.infer_expect(&x.condition, Type::BOOL)?;
+                self.infer(&x.then_val)? | self.infer(&x.else_val)?
+            }
+            ExprKind::Match(x) => {
+                let value = self.infer(&x.value)?;
+                let mut result = Type::NEVER;
+                for arm in &x.arms {
+                    self.frames.push(Frame::default());
+                    let arm_type = self.match_arm(arm, value);
+                    self.frames.pop();
+                    result = result | arm_type?;
+                }
+                result
+            }
+            ExprKind::Try(x) => {
+                let body = self.infer(&x.body)?;
+                self.frames.push(Frame::default());
+                if let Some(error) = &x.error {
+                    self.define(error, Binding::of(Type::MAP));
+                }
+                let handler = self.infer(&x.handler);
+                self.frames.pop();
+                body | handler?
+            }
+            ExprKind::Coalesce(x) => {
+                let (lhs, rhs) = (self.infer(&x.lhs)?, self.infer(&x.rhs)?);
+                // The fallback is only used if the lhs may be Null
+                lhs.without(Type::NULL) | iif!(lhs.intersects(Type::NULL), rhs, Type::NEVER)
+            }
+            ExprKind::InterpolatedStr(ast::InterpolatedStr(parts)) => {
+                for part in parts {
+                    if let ast::StrPart::Expr(expr) = part {
+                        self.infer(expr)?;
+                    }
+                }
+                Type::STRING
+            }
+
+            ExprKind::PubKey(_) => Type::PUBKEY,
+            ExprKind::SecKey(_) => Type::SECKEY,
+            ExprKind::Bytes(_) => Type::BYTES,
+            ExprKind::String(_) => Type::STRING,
+            ExprKind::Number(_)
+            | ExprKind::Duration(_)
+            | ExprKind::DateTime(_)
+            | ExprKind::BtcAmount(_) => Type::NUMBER,
+            ExprKind::Value(value) => Type::of(value),
+        })
+    }
+
+    fn call(&mut self, call: &ast::Call) -> Result<Type> {
+        let mut args = Vec::with_capacity(call.args.len());
+        for arg in &call.args {
+            args.push(self.infer(arg)?);
+        }
+        for (_, arg) in &call.named_args {
+            self.infer(arg)?;
+        }
+
+        let func = match self.lookup(&call.ident).func {
+            Some(func) => func,
+            None => return Ok(Type::ANY),
+        };
+
+        // The number of arguments cannot be known with named arguments, which may be bound to optional parameters
+        let max_args = func.max_args();
+        if call.named_args.is_empty()
+            && (args.len() < func.required || matches!(max_args, Some(max) if args.len() > max))
+        {
+            bail!(Error::ArgumentMismatch(
+                call.ident.clone(),
+                func.required,
+                max_args,
+                args.len()
+            ));
+        }
+
+        for (i, (ty, arg)) in args.iter().zip(&call.args).enumerate() {
+            if let Some(expected) = func.params.get(i).copied().or(func.rest) {
+                ensure!(
+                    ty.intersects(expected),
+                    Error::CallError(
+                        call.ident.clone(),
+                        Error::TypeMismatch(expected, *ty)
+                            .with_span(arg.span)
+                            .into()
+                    )
+                );
+            }
+        }
+        Ok(func.ret)
+    }
+
+    // && and || operate on either booleans or policies
+    fn andor(&mut self, operands: &[Expr]) -> Result<Type> {
+        let policy_like = Type::POLICY_LIKE | Type::WITH_PROB;
+        let first = self.infer_expect(&operands[0], Type::BOOL | policy_like)?;
+        for operand in &operands[1..] {
+            self.infer_expect(operand, Type::BOOL | policy_like)?;
+        }
+        Ok(first.intersect(Type::BOOL)
+            | iif!(first.intersects(policy_like), Type::POLICY, Type::NEVER))
+    }
+
+    fn block(&mut self, block: &ast::Block) -> Result<Type> {
+        self.frames.push(Frame::default());
+        let result = self.block_frame(block);
+        self.frames.pop();
+        result
+    }
+
+    fn block_frame(&mut self, block: &ast::Block) -> Result<Type> {
+        // Definitions are visible throughout the block (i.e. to functions defined before them) and
+        // shadow the outer ones, so they're declared upfront and refined as their statements are checked
+        for stmt in &block.stmts {
+            match &stmt.kind {
+                StmtKind::FnDef(def) => {
+                    let fn_type = FnType::from_signature(&def.signature, Type::ANY);
+                    self.define(
+                        &def.ident,
+                        Binding {
+                            ty: Type::FUNCTION,
+                            func: Some(fn_type.into()),
+                        },
+                    );
+                }
+                StmtKind::Assign(assign) => {
+                    for assignment in &assign.0 {
+                        self.define_pattern(&assignment.lhs, Type::ANY)?;
+                    }
+                }
+                StmtKind::Import(_) | StmtKind::ExprStmt(_) | StmtKind::Test(_) => (),
+            }
+        }
+        for stmt in &block.stmts {
+            self.stmt(stmt).map_err(|e| e.with_span(stmt.span))?;
+        }
+        Ok(match &block.return_value {
+            Some(return_value) => self.infer(return_value)?,
+            None => self
+                .lookup(&"main".into())
+                .func
+                .map_or(Type::ANY, |main| main.ret),
+        })
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> Result<()> {
+        match &stmt.kind {
+            StmtKind::FnDef(def) => {
+                let ret = self.function(&def.signature, &def.body)?;
+                let fn_type = FnType::from_signature(&def.signature, ret);
+                self.define(
+                    &def.ident,
+                    Binding {
+                        ty: Type::FUNCTION,
+                        func: Some(fn_type.into()),
+                    },
+                );
+            }
+            StmtKind::Assign(assign) => {
+                for assignment in &assign.0 {
+                    let mut ty = self.infer(&assignment.rhs)?;
+                    if let Some(annotated) = assignment.ty {
+                        ensure!(
+                            ty.intersects(annotated),
+                            Error::TypeMismatch(annotated, ty).with_span(assignment.rhs.span)
+                        );
+                        ty = ty.intersect(annotated);
+                    }
+                    self.define_pattern(&assignment.lhs, ty)
+                        .map_err(|e| e.with_span(assignment.rhs.span))?;
+                }
+            }
+            StmtKind::Import(_) => self.frames.last_mut().expect("within a frame").opaque = true,
+            StmtKind::ExprStmt(ast::ExprStmt(expr)) => {
+                self.infer(expr)?;
+            }
+            StmtKind::Test(test) => {
+                self.infer(&test.body)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the function body with its parameters in scope, returning its return type
+    fn match_arm(&mut self, arm: &ast::MatchArm, value: Type) -> Result<Type> {
+        self.define_match_pattern(&arm.pattern, value)?;
+        if let Some(guard) = &arm.guard {
+            self.infer_expect(guard, Type::BOOL)?;
+        }
+        self.infer(&arm.body)
+    }
+
+    /// Define the pattern's bindings, with the value type narrowed down by the type patterns
+    fn define_match_pattern(&mut self, pattern: &ast::MatchPattern, ty: Type) -> Result<()> {
+        match pattern {
+            ast::MatchPattern::Wildcard => (),
+            ast::MatchPattern::Bind(ident) => self.define(ident, Binding::of(ty)),
+            ast::MatchPattern::Literal(literal) => {
+                self.infer(literal)?;
+            }
+            ast::MatchPattern::Type(pattern_ty, inner) => {
+                let narrowed = ty.intersect(*pattern_ty);
+                let narrowed = iif!(narrowed.is_never(), *pattern_ty, narrowed);
+                self.define_match_pattern(inner, narrowed)?;
+            }
+            ast::MatchPattern::Array { elements, rest } => {
+                for element in elements {
+                    self.define_match_pattern(element, Type::ANY)?;
+                }
+                if let Some(rest) = rest {
+                    self.define(rest, Binding::of(Type::ARRAY));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn function(&mut self, signature: &ast::Signature, body: &Expr) -> Result<Type> {
+        self.frames.push(Frame::default());
+        let result = self.function_frame(signature, body);
+        self.frames.pop();
+        result
+    }
+
+    fn function_frame(&mut self, signature: &ast::Signature, body: &Expr) -> Result<Type> {
+        for param in &signature.params {
+            let ty = param_type(param);
+            if let Some(default) = &param.default {
+                self.infer_expect(default, ty)?;
+            }
+            self.define_pattern(&param.pattern, ty)?;
+        }
+        if let Some(rest) = &signature.rest {
+            self.define(rest, Binding::of(Type::ARRAY));
+        }
+        self.infer(body)
+    }
+}
+
+/// The result type of applying the infix operator to the individual kinds, mirroring `InfixOp::apply()`
+fn infix_type(op: InfixOp, lhs: Type, rhs: Type) -> Option<Type> {
+    use InfixOp::*;
+    let comparable = Type::NUMBER | Type::BYTES | Type::STRING;
+    let concatable = Type::NUMBER | Type::ARRAY | Type::BYTES | Type::STRING;
+    let tweak_tree = Type::SCRIPT | Type::MINISCRIPT | Type::POLICY | Type::ARRAY;
+
+    Some(match op {
+        Eq | NotEq => Type::BOOL,
+        Gt | Lt | Gte | Lte if lhs == rhs && comparable.contains(lhs) => Type::BOOL,
+        Subtract | Multiply | Mod | Shl | Shr | BitAnd | BitOr | BitXor
+            if lhs == Type::NUMBER && rhs == Type::NUMBER =>
+        {
+            Type::NUMBER
+        }
+        Add if lhs == rhs && concatable.contains(lhs) => lhs,
+        // Tap tweak (internal_key+script_tree)
+        Add if lhs == Type::PUBKEY || lhs == Type::SECKEY => Type::SCRIPT,
+        Add if lhs == Type::BYTES && tweak_tree.contains(rhs) => Type::SCRIPT,
+        Prob if lhs == Type::NUMBER => Type::WITH_PROB,
+        _ => return None,
+    })
+}
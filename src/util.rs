@@ -4,14 +4,17 @@ use std::str::FromStr;
 use bitcoin::hashes::{sha256, Hash};
 use bitcoin::util::bip32::{ChildNumber, IntoDerivationPath};
 use bitcoin::{secp256k1, PublicKey};
-use miniscript::descriptor::{DescriptorPublicKey, DescriptorTrait, Wildcard};
+use miniscript::descriptor::{DescriptorPublicKey, DescriptorSecretKey, DescriptorTrait, Wildcard};
 use miniscript::{bitcoin, ForEachKey, TranslatePk2};
 
+use crate::runtime::SecKey;
 use crate::{Error, Result, Value};
 
 lazy_static! {
     pub static ref EC: secp256k1::Secp256k1<secp256k1::VerifyOnly> =
         secp256k1::Secp256k1::verification_only();
+    pub static ref EC_SIGN: secp256k1::Secp256k1<secp256k1::SignOnly> =
+        secp256k1::Secp256k1::signing_only();
 }
 
 pub trait MiniscriptExt<T: miniscript::ScriptContext> {
@@ -48,7 +51,7 @@ impl DescriptorExt for crate::Descriptor {
 }
 
 pub trait DeriveExt {
-    fn derive_path<P: DerivePath>(&self, path: P, is_wildcard: bool) -> Result<Self>
+    fn derive_path<P: DerivePath>(&self, path: P, wildcard: Wildcard) -> Result<Self>
     where
         Self: Sized;
     fn is_deriveable(&self) -> bool;
@@ -59,14 +62,20 @@ pub trait DerivePath: IntoDerivationPath + Clone {}
 impl<T: IntoDerivationPath + Clone> DerivePath for T {}
 
 impl DeriveExt for DescriptorPublicKey {
-    fn derive_path<P: DerivePath>(&self, path: P, is_wildcard: bool) -> Result<Self> {
+    fn derive_path<P: DerivePath>(&self, path: P, wildcard: Wildcard) -> Result<Self> {
         let mut xpub = match self {
             DescriptorPublicKey::XPub(xpub) => xpub.clone(),
             DescriptorPublicKey::SinglePub(_) => bail!(Error::NonDeriveableSingle),
         };
-        xpub.derivation_path = xpub.derivation_path.extend(path.into_derivation_path()?);
-        // XXX hardened derivation is currently unsupported
-        xpub.wildcard = iif!(is_wildcard, Wildcard::Unhardened, Wildcard::None);
+        let path = path.into_derivation_path()?;
+        // Hardened steps can only be derived from the private key. Any hardened steps in the key origin
+        // (i.e. `[fingerprint/84h/0h/0h]xpub...`) were already derived and are kept as-is.
+        ensure!(
+            wildcard != Wildcard::Hardened && !path.as_ref().iter().any(ChildNumber::is_hardened),
+            Error::HardenedDerivationOnPubKey
+        );
+        xpub.derivation_path = xpub.derivation_path.extend(path);
+        xpub.wildcard = wildcard;
         Ok(DescriptorPublicKey::XPub(xpub))
     }
     fn is_deriveable(&self) -> bool {
@@ -74,11 +83,26 @@ impl DeriveExt for DescriptorPublicKey {
         matches!(self, DescriptorPublicKey::XPub(_))
     }
 }
+impl DeriveExt for SecKey {
+    fn derive_path<P: DerivePath>(&self, path: P, wildcard: Wildcard) -> Result<Self> {
+        let mut xprv = match &self.0 {
+            DescriptorSecretKey::XPrv(xprv) => xprv.clone(),
+            DescriptorSecretKey::SinglePriv(_) => bail!(Error::NonDeriveableSingle),
+        };
+        // Unlike with public keys, hardened steps and wildcards are allowed
+        xprv.derivation_path = xprv.derivation_path.extend(path.into_derivation_path()?);
+        xprv.wildcard = wildcard;
+        Ok(SecKey(DescriptorSecretKey::XPrv(xprv)))
+    }
+    fn is_deriveable(&self) -> bool {
+        matches!(self.0, DescriptorSecretKey::XPrv(_))
+    }
+}
 impl DeriveExt for crate::Policy {
-    fn derive_path<P: DerivePath>(&self, path: P, is_wildcard: bool) -> Result<Self> {
+    fn derive_path<P: DerivePath>(&self, path: P, wildcard: Wildcard) -> Result<Self> {
         // ensure!(self.is_deriveable(), Error::NonDeriveableNoWildcard);
         let path = path.into_derivation_path()?;
-        self.translate_pk(|pk| pk.clone().derive_path(path.clone(), is_wildcard))
+        self.translate_pk(|pk| pk.clone().derive_path(path.clone(), wildcard))
     }
     fn is_deriveable(&self) -> bool {
         // TODO This fails with 'reached the recursion limit while instantiating'
@@ -87,20 +111,20 @@ impl DeriveExt for crate::Policy {
     }
 }
 impl DeriveExt for crate::Miniscript {
-    fn derive_path<P: DerivePath>(&self, path: P, is_wildcard: bool) -> Result<Self> {
+    fn derive_path<P: DerivePath>(&self, path: P, wildcard: Wildcard) -> Result<Self> {
         ensure!(self.is_deriveable(), Error::NonDeriveableNoWildcard);
         let path = path.into_derivation_path()?;
-        self.translate_pk2(|pk| pk.clone().derive_path(path.clone(), is_wildcard))
+        self.translate_pk2(|pk| pk.clone().derive_path(path.clone(), wildcard))
     }
     fn is_deriveable(&self) -> bool {
         self.for_any_key(|key| key.as_key().is_deriveable())
     }
 }
 impl DeriveExt for crate::Descriptor {
-    fn derive_path<P: DerivePath>(&self, path: P, is_wildcard: bool) -> Result<Self> {
+    fn derive_path<P: DerivePath>(&self, path: P, wildcard: Wildcard) -> Result<Self> {
         ensure!(self.is_deriveable(), Error::NonDeriveableNoWildcard);
         let path = path.into_derivation_path()?;
-        self.translate_pk2(|pk| pk.clone().derive_path(path.clone(), is_wildcard))
+        self.translate_pk2(|pk| pk.clone().derive_path(path.clone(), wildcard))
     }
     fn is_deriveable(&self) -> bool {
         // delegate to miniscript::Descriptor::is_derivable()
@@ -108,19 +132,21 @@ impl DeriveExt for crate::Descriptor {
     }
 }
 impl DeriveExt for Value {
-    fn derive_path<P: DerivePath>(&self, path: P, is_wildcard: bool) -> Result<Self> {
+    fn derive_path<P: DerivePath>(&self, path: P, wildcard: Wildcard) -> Result<Self> {
         Ok(match self {
-            Value::PubKey(key) => key.derive_path(path, is_wildcard)?.into(),
-            Value::Descriptor(desc) => desc.derive_path(path, is_wildcard)?.into(),
-            Value::Miniscript(ms) => ms.derive_path(path, is_wildcard)?.into(),
-            Value::Policy(policy) => policy.derive_path(path, is_wildcard)?.into(),
-            Value::Array(array) => array.derive_path(path, is_wildcard)?.into(),
+            Value::PubKey(key) => key.derive_path(path, wildcard)?.into(),
+            Value::SecKey(key) => key.derive_path(path, wildcard)?.into(),
+            Value::Descriptor(desc) => desc.derive_path(path, wildcard)?.into(),
+            Value::Miniscript(ms) => ms.derive_path(path, wildcard)?.into(),
+            Value::Policy(policy) => policy.derive_path(path, wildcard)?.into(),
+            Value::Array(array) => array.derive_path(path, wildcard)?.into(),
             _ => bail!(Error::NonDeriveableType),
         })
     }
     fn is_deriveable(&self) -> bool {
         match self {
             Value::PubKey(key) => key.is_deriveable(),
+            Value::SecKey(key) => key.is_deriveable(),
             Value::Descriptor(desc) => desc.is_deriveable(),
             Value::Miniscript(ms) => ms.is_deriveable(),
             Value::Policy(policy) => policy.is_deriveable(),
@@ -130,9 +156,9 @@ impl DeriveExt for Value {
     }
 }
 impl DeriveExt for Vec<Value> {
-    fn derive_path<P: DerivePath>(&self, path: P, is_wildcard: bool) -> Result<Self> {
+    fn derive_path<P: DerivePath>(&self, path: P, wildcard: Wildcard) -> Result<Self> {
         self.into_iter()
-            .map(|v| v.derive_path(path.clone(), is_wildcard))
+            .map(|v| v.derive_path(path.clone(), wildcard))
             .collect::<Result<_>>()
     }
     fn is_deriveable(&self) -> bool {
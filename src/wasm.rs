@@ -1,6 +1,11 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 
-use crate::{parse, Evaluate, Result, Scope, Value};
+use crate::{
+    parse, types, Budget, Evaluate, Importer, Limits, LogOutput, MemoryResolver, Result, Scope,
+    Value,
+};
 
 #[cfg(feature = "wee_alloc")]
 #[global_allocator]
@@ -8,14 +13,83 @@ static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
 #[wasm_bindgen(js_name = run)]
 pub fn js_run(code: &str) -> std::result::Result<JsValue, JsValue> {
-    let value = run(code).map_err(|e| e.to_string())?;
+    let importer = importer();
+    let value =
+        run(code, &importer).map_err(|e| e.render_with_importer("input", code, &importer))?;
     Ok(JsValue::from_str(&value.to_string()))
 }
 
-lazy_static! {
-    static ref ROOT_SCOPE: Scope<'static> = Scope::root();
+/// Make a file available to import statements, with the given path
+#[wasm_bindgen(js_name = addImportFile)]
+pub fn add_import_file(path: &str, code: &str) {
+    IMPORT_FILES.with(|files| files.borrow_mut().add_file(path, code))
 }
 
-fn run(code: &str) -> Result<Value> {
-    parse(code)?.eval(&ROOT_SCOPE)
+#[wasm_bindgen(js_name = clearImportFiles)]
+pub fn clear_import_files() {
+    IMPORT_FILES.with(|files| files.borrow_mut().clear())
+}
+
+/// Set the resource limits for evaluating untrusted code. Defaults to `Limits::default()`.
+#[wasm_bindgen(js_name = setLimits)]
+pub fn set_limits(max_depth: usize, max_steps: usize, max_alloc: usize) {
+    LIMITS.with(|limits| {
+        limits.set(Limits {
+            max_depth,
+            max_steps,
+            max_alloc,
+        })
+    })
+}
+
+/// Take the output of `print()`, `trace()` and `dbg()` written by the last evaluation.
+/// Available after both successful and failed evaluations.
+#[wasm_bindgen(js_name = takeLog)]
+pub fn take_log() -> Box<[JsValue]> {
+    let lines = LOG.with(|log| log.take());
+    lines.iter().map(|line| JsValue::from_str(line)).collect()
+}
+
+thread_local! {
+    static ROOT_SCOPE: Scope = Scope::root();
+
+    // The output written by the last evaluation, read with takeLog()
+    static LOG: Rc<LogOutput> = Rc::new(LogOutput::default());
+
+    // The files provided by the browser for import statements
+    static IMPORT_FILES: RefCell<MemoryResolver> = RefCell::new(MemoryResolver::default());
+
+    // The limits set with setLimits()
+    static LIMITS: Cell<Limits> = Cell::new(Limits::default());
+}
+
+/// Create a budget for a single evaluation, using the limits set with setLimits()
+pub(crate) fn budget() -> Rc<Budget> {
+    Rc::new(Budget::new(LIMITS.with(Cell::get)))
+}
+
+/// Get the output for a single evaluation, discarding the log left over from previous evaluations
+pub(crate) fn output() -> Rc<LogOutput> {
+    LOG.with(|log| {
+        log.take();
+        log.clone()
+    })
+}
+
+/// Create an importer for a single evaluation, resolving from the files added with addImportFile()
+pub(crate) fn importer() -> Rc<Importer> {
+    let files = IMPORT_FILES.with(|files| files.borrow().clone());
+    Rc::new(Importer::new(files, None))
+}
+
+fn run(code: &str, importer: &Rc<Importer>) -> Result<Value> {
+    let expr = parse(code)?;
+    ROOT_SCOPE.with(|scope| {
+        let scope = scope
+            .child_with_importer(importer.clone())
+            .child_with_budget(Some(budget()))
+            .child_with_output(output());
+        types::check(&expr, &scope)?;
+        expr.eval(&scope)
+    })
 }
@@ -1,4 +1,9 @@
-use minsc::run;
+use minsc::{
+    doc, fold, fold_lib, format_program, format_source, lint, parse, parse_lib, run, run_tests,
+    run_with_importer, run_with_limits, Budget, Error, Evaluate, Importer, Limits, Lint, LogOutput,
+    MemoryResolver, Scope, Value,
+};
+use std::rc::Rc;
 
 fn test(minsc: &str, expected_policy: &str) {
     let res = run(&replace_dummy(minsc)).unwrap();
@@ -74,6 +79,1021 @@ fn test_functions() {
     );
 }
 
+#[test]
+fn test_if_else() {
+    test(
+        "if 1 > 2 { pk(A) } else if true { pk(B) } else { pk(C) }",
+        "pk(B)",
+    );
+    // Only the chosen branch gets evaluated
+    test("if !false { pk(A) } else { $undefined }", "pk(A)");
+    assert!(run("if 1 { pk(A) } else { pk(B) }").is_err());
+}
+
+#[test]
+fn test_closures() {
+    // Functions returned from a function can access its locals after it returns
+    test(
+        r"
+        fn withKey($key) = |$delay| $key && older($delay);
+        $f = withKey(pk(A));
+        $key = pk(B);
+        $f(10)
+        ",
+        "and(pk(A),older(10))",
+    );
+    // Variables are resolved lexically and not from the caller's scope
+    test(
+        r"
+        $arr = [ pk(A), pk(B) ];
+        fn pick($n) = $arr.$n;
+        fn g($arr) = pick(1);
+        g([ pk(C), pk(D) ])
+        ",
+        "pk(B)",
+    );
+}
+
+#[test]
+fn test_destructuring() {
+    test(
+        r"
+        let [$key, [$delay, ...$rest]] = [ pk(A), [ 10, pk(B), pk(C) ] ];
+        fn f([$a, $b]) = $a && older($b);
+        $tail = |[$x, ...$xs]| $xs;
+        f([$key, $delay]) || 2 of ($rest + $tail([ pk(D), pk(E) ]))
+        ",
+        "or(1@and(pk(A),older(10)),1@thresh(2,pk(B),pk(C),pk(E)))",
+    );
+    let err = run("let [$a, $b] = [1, 2, 3]; $a").unwrap_err().to_string();
+    assert!(err.contains("expected 2 elements, not 3"));
+    let err = run("let [$a, $b, ...$c] = [1]; $a")
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("expected at least 2 elements, not 1"));
+    // The rest may be empty
+    assert_eq!(
+        run("let [$head, ...$rest] = [ 1 ]; $f = |[$a, ...$more]| $more; [ $head, $rest, $f([ 2 ]) ]")
+            .unwrap()
+            .to_string(),
+        "[ 1, [  ], [  ] ]"
+    );
+}
+
+#[test]
+fn test_strings() {
+    let eval = |code: &str| run(code).unwrap().to_string();
+    assert_eq!(eval(r#""abc" + "\{d\}""#), "abc{d}");
+    assert_eq!(
+        eval(r#"$n = 3; "n={$n}, n+1={ $m = $n + 1; $m }""#),
+        "n=3, n+1=4"
+    );
+    assert_eq!(
+        eval(r#"format("{} of {}", 2, [ "a", "b" ])"#),
+        r#"2 of [ "a", "b" ]"#
+    );
+    assert_eq!(eval(r#"len("₿tc")"#), "3");
+    // Strings are pushed as their UTF-8 bytes in script fragments
+    assert_eq!(eval(r#"`"abc"` == `0x616263`"#), "true");
+    assert!(run(r#"format("{}", 1, 2)"#).is_err());
+}
+
+#[test]
+fn test_operators() {
+    let eval = |code: &str| run(code).unwrap().to_string();
+    assert_eq!(eval("1 + 2 * 3 - 4 % 3"), "6");
+    assert_eq!(eval("1 - 2 - 3"), "-4");
+    assert_eq!(eval("100 / 10 / 2 * 3"), "15");
+    assert_eq!(eval("-(2 + 3) * 2"), "-10");
+    assert_eq!(
+        eval("$n = 5; [ 5-2, $n-1, $n - -1, -$n ]"),
+        "[ 3, 4, 6, -5 ]"
+    );
+    assert_eq!(
+        eval("match 1-2 { -1 => `-1`, _ => `0` } == `<(0 - 1)>`"),
+        "true"
+    );
+    assert_eq!(eval("1 << 4 | 6 & 3 ^ 1"), "19");
+    assert_eq!(eval("2 + 3 == 5 && 0x01 < 0x02 && \"a\" < \"b\""), "true");
+    assert!(run("1 / 0").is_err());
+    assert!(run("1 << 64").is_err());
+}
+
+#[test]
+fn test_maps() {
+    let eval = |code: &str| run(code).unwrap().to_string();
+    assert_eq!(
+        eval(r#"{ name: "alice", "the keys": [ 1, "b" ], empty: {} }"#),
+        r#"{ "empty": {}, "name": "alice", "the keys": [ 1, "b" ] }"#
+    );
+    assert_eq!(
+        eval(r#"$m = { a: 1, b: { c: 2 } }; $k = "a"; [ $m.a, $m.b.c, $m.$k, $m.("b").c ]"#),
+        "[ 1, 2, 1, 2 ]"
+    );
+    // Numeric access is unchanged
+    assert_eq!(
+        eval("$arr = [ 5, 6 ]; $n = 1; [ $arr.0, $arr.$n ]"),
+        "[ 5, 6 ]"
+    );
+    // Plain field names are always map keys, never variables. Other variables have to be parenthesized.
+    assert_eq!(
+        eval("CONST = 1; [ { CONST: 2 }.CONST, [ 5, 6 ].(CONST) ]"),
+        "[ 2, 6 ]"
+    );
+    assert!(matches!(
+        run("CONST = 1; [ 5, 6 ].CONST").unwrap_err().root(),
+        Error::NotMap(_)
+    ));
+    assert_eq!(
+        eval("$m = merge({ a: 1, b: 2 }, { b: 3 }); [ keys($m), values($m), has($m, \"a\"), has($m, \"c\") ]"),
+        r#"[ [ "a", "b" ], [ 1, 3 ], true, false ]"#
+    );
+    assert_eq!(eval("{ a: 1, b: 2 } == { b: 2, a: 1 }"), "true");
+    assert!(run("{ a: 1 }.b").is_err());
+    assert!(run("{ a: 1, a: 2 }").is_err());
+}
+
+#[test]
+fn test_array_functions() {
+    let eval = |code: &str| run(code).unwrap().to_string();
+    assert_eq!(
+        eval("$a = [ 3, 1, 2 ]; [ map($a, |$n| $n * 2), filter($a, |$n| $n > 1), reduce($a, |$x, $y| $x + $y), fold($a, 10, |$x, $y| $x + $y) ]"),
+        "[ [ 6, 2, 4 ], [ 3, 2 ], 6, 16 ]"
+    );
+    assert_eq!(
+        eval(
+            r#"[ zip([ 1, 2, 3 ], [ "a", "b" ]), flatten([ [ 1 ], 2, [ [ 3 ] ] ]), enumerate([ "a" ]) ]"#
+        ),
+        r#"[ [ [ 1, "a" ], [ 2, "b" ] ], [ 1, 2, [ 3 ] ], [ [ 0, "a" ] ] ]"#
+    );
+    assert_eq!(
+        eval(
+            r#"[ sort([ "b", "c", "a" ]), sortBy([ [ 2, "x" ], [ 1, "y" ] ], |$p| $p.0), unique([ 1, 2, 1, 3 ]) ]"#
+        ),
+        r#"[ [ "a", "b", "c" ], [ [ 1, "y" ], [ 2, "x" ] ], [ 1, 2, 3 ] ]"#
+    );
+    assert_eq!(
+        eval("[ contains([ 1, 2 ], 2), indexOf([ 1, 2 ], 2), indexOf([ 1, 2 ], 3), range(2, 4), range(2, 1), slice([ 1, 2, 3 ], 1, 2) ]"),
+        "[ true, 1, null, [ 2, 3, 4 ], [  ], [ 2, 3 ] ]"
+    );
+    // all() and any() check a predicate when given one, and construct a threshold policy otherwise
+    assert_eq!(
+        eval("[ all([ 1, 2 ], |$n| $n > 0), any([ 1, 2 ], |$n| $n > 1), any([], |$n| true) ]"),
+        "[ true, true, false ]"
+    );
+    test("all([ pk(A), pk(B) ])", "thresh(2,pk(A),pk(B))");
+    assert!(run("reduce([], |$x, $y| $x)").is_err());
+    assert!(run("sort([ 1, \"a\" ])").is_err());
+    // User functions are only equal to themselves
+    assert_eq!(
+        eval("$f = |$x| $x; $g = |$x| $x; [ len(unique([ $f, $f, $g ])), contains([ $f ], $f), contains([ $f ], $g), indexOf([ |$x| $x ], 1) ]"),
+        "[ 2, true, false, null ]"
+    );
+
+    // The pipeline operator passes its LHS as the first argument
+    assert_eq!(
+        eval("[ 3, 1, 2, 3 ] |> unique() |> sort() |> map(|$n| $n * 10) |> slice(1, 2)"),
+        "[ 20, 30 ]"
+    );
+    assert_eq!(eval("fn f($a, $b = 1) = $a - $b; 5 |> f(b: 2)"), "3");
+    assert_eq!(
+        format_source("$x = [ 1 ] |> map(|$n| $n + 1) |> len();\n$x ?? 0\n").unwrap(),
+        "$x = [ 1 ] |> map(|$n| $n + 1) |> len();\n$x ?? 0\n"
+    );
+}
+
+#[test]
+fn test_slices_and_ranges() {
+    let eval = |code: &str| run(code).unwrap().to_string();
+    assert_eq!(
+        eval(
+            "$a = [ 1, 2, 3, 4 ]; [ $a[1..3], $a[..2], $a[2..], $a[-3..-1], $a[..], $a[1..][..1] ]"
+        ),
+        "[ [ 2, 3 ], [ 1, 2 ], [ 3, 4 ], [ 2, 3 ], [ 1, 2, 3, 4 ], [ 2 ] ]"
+    );
+    assert_eq!(
+        eval("$b = 0x02aabbcc; [ $b[..1], $b[1..], $b[-1..], tail([ 1, 2 ]), initial([ 1, 2 ]) ]"),
+        "[ 0x02, 0xaabbcc, 0xcc, [ 2 ], [ 1 ] ]"
+    );
+    assert_eq!(
+        eval("$n = 2; [ 0..3, -1..$n, 3..3, (0..10)[..2] ]"),
+        "[ [ 0, 1, 2 ], [ -1, 0, 1 ], [  ], [ 0, 1 ] ]"
+    );
+    assert_eq!(eval("0..4 |> map(|$i| $i * 2)"), "[ 0, 2, 4, 6 ]");
+
+    let err = |code: &str| run(code).unwrap_err().to_string();
+    assert!(err("[ 1, 2, 3 ][1..5]").contains("Slice [1..5] out of range for length 3"));
+    assert!(err("0x0102[-3..]").contains("Slice [-3..] out of range for length 2"));
+    assert!(err("[ 1, 2 ][2..1]").contains("Slice [2..1] out of range for length 2"));
+    // Without a budget, huge ranges error rather than abort
+    assert!(err("0..9007199254740991").contains("Memory allocation failed"));
+    assert!(err("range(0, 9007199254740990)").contains("Memory allocation failed"));
+    let err = run_with_limits("0..1000000000", Limits::default()).unwrap_err();
+    assert!(err.to_string().contains("allocation size"));
+
+    assert_eq!(
+        format_source("$x = f( 1 )[ -2 .. ];\n$y = (1 || 2)..3;\n(0..2)[1..]\n").unwrap(),
+        "$x = f(1)[-2..];\n$y = 1 || 2..3;\n(0..2)[1..]\n"
+    );
+}
+
+#[test]
+fn test_match_and_typeof() {
+    let eval = |code: &str| run(code).unwrap().to_string();
+    assert_eq!(
+        eval(
+            r#"fn f($x) = match $x {
+                0 => "zero",
+                Number($n) if $n < 0 => "negative",
+                Number(_) => "positive",
+                [] => "empty",
+                [ $a, ...$rest ] if len($rest) > 1 => $a,
+                [ _, ..._ ] => "short",
+                Bytes($b) => len($b),
+                _ => typeof($x),
+            };
+            [ f(0), f(-3), f(7), f([  ]), f([ 1, 2, 3 ]), f([ 1 ]), f(0x0203), f(true) ]"#
+        ),
+        r#"[ "zero", "negative", "positive", "empty", 1, "short", 2, "Bool" ]"#
+    );
+    assert_eq!(eval("match [ 1, 2 ] { [ $a, $b ] => $a + $b }"), "3");
+    // Literals that evaluate into functions (by shadowing `true`) are compared without failing
+    assert_eq!(
+        eval(
+            r#"fn true() = 1; $f = |$x| $x; [ match true { true => 1, _ => 2 }, match $f { true => 1, _ => 2 } ]"#
+        ),
+        "[ 1, 2 ]"
+    );
+    assert_eq!(
+        eval("$a = 1; $b = match 2 { $a => $a }; [ $a, $b ]"),
+        "[ 1, 2 ]"
+    );
+    assert!(run("match 5 { 1 => 2 }")
+        .unwrap_err()
+        .to_string()
+        .contains("No match arm matched 5"));
+
+    assert_eq!(
+        eval(
+            "[ typeof(1), typeof(0x00), typeof(`1`), typeof([ 1 ]), typeof(signet), typeof(null) ]"
+        ),
+        r#"[ "Number", "Bytes", "Script", "Array", "Network", "Null" ]"#
+    );
+    assert_eq!(
+        eval("[ isNumber(1), isBytes(1), isArray([ 1 ]), isMap({}), isFunction(isMap) ]"),
+        "[ true, false, true, true, true ]"
+    );
+
+    assert_eq!(
+        format_source(
+            "match $x { 1=>2, _=>3 }
+"
+        )
+        .unwrap(),
+        "match $x { 1 => 2, _ => 3 }
+"
+    );
+    assert_eq!(
+        format_source("match $x {\n  // one\n  [1, ...$r] if $y=>$r,\n  Bytes($b)=>$b\n}\n")
+            .unwrap(),
+        "match $x {\n  // one\n  [1, ...$r] if $y => $r,\n  Bytes($b) => $b,\n}\n"
+    );
+}
+
+#[test]
+fn test_try_catch() {
+    let eval = |code: &str| run(code).unwrap().to_string();
+    assert_eq!(eval("try { 1 } catch $e { 2 }"), "1");
+    assert_eq!(eval(r#"try { [ 1 ].5 } catch { "default" }"#), "default");
+    assert_eq!(
+        eval("fn f($x) = g($x); fn g($x) = 1 / $x; try { f(0) } catch $e { $e }"),
+        r#"{ "calls": [ "f", "g" ], "kind": "DivideByZero", "message": "Division by zero" }"#
+    );
+    assert_eq!(
+        eval(r#"try { error("bad input") } catch $e { [ $e.kind, $e.message ] }"#),
+        r#"[ "UserError", "bad input" ]"#
+    );
+    assert_eq!(
+        eval(
+            r#"[ try { { "a": 1 }.b } catch $e { $e.kind }, try { [ 1 ].5 } catch $e { $e.kind } ]"#
+        ),
+        r#"[ "MapKeyNotFound", "ArrayIndexOutOfRange" ]"#
+    );
+    assert_eq!(
+        eval("try { throw([ 1, 2 ]) } catch $e { $e.value }"),
+        "[ 1, 2 ]"
+    );
+    assert_eq!(
+        eval(
+            r#"try { try { error("a") } catch $e { error($e.message + "b") } } catch $e { $e.message }"#
+        ),
+        "ab"
+    );
+
+    let err = |code: &str| run(code).unwrap_err().to_string();
+    assert!(err(r#"error("oops")"#).contains("oops"));
+    assert!(err("throw(5)").contains("Thrown value: 5"));
+    // Exceeding the limits cannot be caught
+    let limits = Limits {
+        max_depth: 20,
+        ..Limits::default()
+    };
+    let err = run_with_limits("fn f($n) = f($n + 1); try { f(0) } catch { 1 }", limits);
+    assert!(err.unwrap_err().to_string().contains("call depth of 20"));
+
+    assert_eq!(
+        format_source("$x = try {f()} catch $e {$e.kind};\n$x\n").unwrap(),
+        "$x = try { f() } catch $e { $e.kind };\n$x\n"
+    );
+}
+
+#[test]
+fn test_output() {
+    let code = r#"
+        fn f($x) = trace("f", $x * 2);
+        print("start", 1, [ "a" ]);
+        $y = dbg(f(3)) + 1;
+        $y
+    "#;
+    let log = Rc::new(LogOutput::default());
+    let scope = Scope::root().child_with_output(log.clone());
+    let res = parse(code).unwrap().eval(&scope).unwrap();
+    assert_eq!(res.to_string(), "7");
+    assert_eq!(log.take(), vec![r#"start 1 [ "a" ]"#, "f: 6", "Number(6)"]);
+
+    // Discarded when there's no output, like with run()
+    assert_eq!(run(code).unwrap().to_string(), "7");
+
+    // Output is deferred to runtime rather than folded away, including within try/catch
+    let code = r#"fn f() = trace("f", 1); $a = f(); $b = try { f() } catch { 2 }; $a + $b"#;
+    let folded = format_program(&fold(&parse(code).unwrap(), &Scope::root()));
+    assert!(folded.contains("$a = f();") && folded.contains("try { f() }"));
+    parse(&folded).unwrap().eval(&scope).unwrap();
+    assert_eq!(log.take(), vec!["f: 1", "f: 1"]);
+
+    // Charged to the budget, since the log keeps it in memory
+    let limits = Limits {
+        max_alloc: 1_000,
+        ..Limits::default()
+    };
+    let scope = Scope::root()
+        .child_with_budget(Some(Rc::new(Budget::new(limits))))
+        .child_with_output(log.clone());
+    let err = parse(r#"repeat(150, |$i| print("0123456789"))"#)
+        .unwrap()
+        .eval(&scope)
+        .unwrap_err();
+    assert!(
+        matches!(err.root(), Error::AllocLimitExceeded(1_000)),
+        "{:?}",
+        err
+    );
+    assert!(log.take().len() < 100);
+}
+
+#[test]
+fn test_limits() {
+    let limits = Limits {
+        max_depth: 20,
+        max_steps: 10_000,
+        max_alloc: 1_000,
+    };
+    let err = |code: &str| run_with_limits(code, limits).unwrap_err().to_string();
+    assert!(err("fn f($n) = f($n + 1); f(0)").contains("call depth of 20"));
+    assert!(
+        err("fn f($n) = if $n == 0 { 0 } else { f($n - 1) }; repeat(500, |$i| f(10))")
+            .contains("evaluation steps of 10000")
+    );
+    assert!(err("repeat(1000000000, 0)").contains("allocation size of 1000"));
+    assert!(err("$a = repeat(600, 0); $a + $a").contains("allocation size of 1000"));
+    // Stdlib functions are called under the caller's budget
+    assert!(err("map(repeat(600, 0), |$x| $x)").contains("allocation size of 1000"));
+    assert_eq!(
+        run_with_limits(
+            "fn f($n) = if $n == 0 { 0 } else { f($n - 1) }; f(15)",
+            limits
+        )
+        .unwrap()
+        .to_string(),
+        "0"
+    );
+}
+
+#[test]
+fn test_default_and_variadic_params() {
+    let eval = |code: &str| run(code).unwrap().to_string();
+    assert_eq!(
+        eval("fn f($a, $b = $a + 10) = [ $a, $b ]; [ f(1), f(1, 2) ]"),
+        "[ [ 1, 11 ], [ 1, 2 ] ]"
+    );
+    assert_eq!(
+        eval("fn f($first, ...$rest) = [ $first, $rest ]; [ f(1), f(1, 2, 3) ]"),
+        "[ [ 1, [  ] ], [ 1, [ 2, 3 ] ] ]"
+    );
+    assert_eq!(
+        eval("$f = |$a, $b = -1, ...$c| [ $a, $b, len($c) ]; $f(5)"),
+        "[ 5, -1, 0 ]"
+    );
+    // Defaults are evaluated in the function's scope at call time
+    assert_eq!(eval("fn f($a = $x) = $a; $x = 1; f()"), "1");
+    let err = run("fn f($a, $b = 1) = $a; f(1, 2, 3)")
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("expected 1 to 2 arguments, not 3"));
+    assert!(run("fn f($a = 1, $b) = $a; 1").is_err());
+}
+
+#[test]
+fn test_named_args() {
+    let eval = |code: &str| run(code).unwrap().to_string();
+    let f =
+        "fn f($vout, $amount, $asset = 0x01, $version = 1) = [ $vout, $amount, $asset, $version ];";
+    assert_eq!(
+        eval(&format!("{} f(amount: 10000, vout: 0)", f)),
+        "[ 0, 10000, 0x01, 1 ]"
+    );
+    assert_eq!(
+        eval(&format!("{} f(0, 10000, version: 2)", f)),
+        "[ 0, 10000, 0x01, 2 ]"
+    );
+    assert_eq!(eval("repeat(producer: 1, count: 2)"), "[ 1, 1 ]");
+    assert_eq!(
+        eval("tapLeaf(`OP_CHECKSIG`, version: 0xc0) == tapLeaf(`OP_CHECKSIG`)"),
+        "true"
+    );
+    // Optional trailing parameters of natives can be named
+    assert_eq!(
+        eval(r#"[ assert(1 < 2, message: "unreachable"), all([ 1, 2 ], predicate: |$n| $n > 0) ]"#),
+        "[ true, true ]"
+    );
+    assert!(run(r#"assert(message: "too small", condition: 1 > 2)"#)
+        .unwrap_err()
+        .to_string()
+        .contains("too small"));
+
+    let err = |code: &str| run(&format!("{} {}", f, code)).unwrap_err().to_string();
+    assert!(err("f(0, amount: 1, foo: 2)").contains("Unknown named argument foo"));
+    assert!(err("f(0, vout: 1, amount: 2)").contains("Argument vout was provided more than once"));
+    assert!(err("f(vout: 0, version: 2)").contains("Missing the required argument $amount"));
+    assert!(err("len(value: 0x00)").contains("Named arguments are not supported"));
+}
+
+#[test]
+fn test_null() {
+    let eval = |code: &str| run(code).unwrap().to_string();
+    assert_eq!(
+        eval("[ null, isNull(null), isNull(0), null == null ]"),
+        "[ null, true, false, true ]"
+    );
+    assert_eq!(
+        eval("[ null ?? 1, 2 ?? 3, null ?? null ?? 4 ]"),
+        "[ 1, 2, 4 ]"
+    );
+    // The fallback is only evaluated when needed
+    assert_eq!(eval("1 ?? $undefined"), "1");
+    assert_eq!(eval("{ a: null }.a ?? \"default\""), "default");
+    // Empty script trees are represented as Null, with the old sentinels still accepted
+    assert_eq!(eval("tapTreeRoot(null)"), "null");
+    assert_eq!(eval("tapTreeRoot([])"), "null");
+    let key = "029ffbe722b147f3035c87cb1c60b9a5947dd49c774cc31e94773478711a929ac0";
+    assert_eq!(
+        eval(&format!(
+            "$k = {}; tapTweak($k, null) == tapTweak($k, 0x) && tapTweak($k, null) == tapTweak($k)",
+            key
+        )),
+        "true"
+    );
+}
+
+#[test]
+fn test_hardened_derivation() {
+    let xpub = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+    let eval = |code: &str| run(&code.replace("XPUB", xpub)).map(|v| v.to_string());
+
+    // Hardened steps in the key origin are kept, with unhardened derivation on top of them
+    assert_eq!(
+        eval("[d34db33f/84h/0h/0h]XPUB/0/*").unwrap(),
+        format!("[d34db33f/84'/0'/0']{}/0/*", xpub)
+    );
+    // Hardened derivation fails for public keys, rather than producing the wrong key
+    for code in &[
+        "XPUB/84h/0",
+        "XPUB/0/84'",
+        "$n = 1; XPUB/$n'",
+        "XPUB/0/*h",
+        "XPUB/0/*'",
+    ] {
+        let err = eval(code).unwrap_err().to_string();
+        assert!(
+            err.contains("Hardened derivation requires a private key"),
+            "{}",
+            err
+        );
+    }
+    // Child numbers in the hardened range are not silently treated as hardened
+    assert!(eval("XPUB/2147483648").is_err());
+}
+
+#[test]
+fn test_seckeys() {
+    let tprv = "tprv8ZgxMBicQKsPeDgjzdC36fs6bMjGApWDNLR9erAXMs5skhMv36j9MV5ecvfavji5khqjWaWSFhN3YcCUUdiKH6isR4Pwy3U5y5egddBr16m";
+    let tpub = "tpubD6NzVbkrYhZ4XgiXtGrdW5XDAPFCL9h7we1vwNCpn8tGbBcgfVYjXyhWo4E1xkh56hjod1RhGjxbaTLV3X4FyWuejifB9jusQ46QzG87VKp";
+    let wif = "cMceqPhHedrhbcR9eXgzmfWy7kRqLyAxMYwFT6ABDWsiwUp9Nsq9";
+    let xprv = "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi";
+    let eval = |code: &str| {
+        let code = code
+            .replace("TPRV", tprv)
+            .replace("WIF", wif)
+            .replace("XPRV", xprv);
+        run(&code).map(|v| v.to_string())
+    };
+
+    // Converted to the public counterpart where a pubkey is expected
+    assert_eq!(eval("pk(TPRV)").unwrap(), format!("pk({})", tpub));
+    assert_eq!(eval("pk(TPRV) == pk(pubKey(TPRV))").unwrap(), "true");
+    assert_eq!(
+        eval("wpkh(WIF)").unwrap(),
+        "wpkh(031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f)"
+    );
+    assert_eq!(
+        eval("`WIF OP_CHECKSIG` == `pubKey(WIF) OP_CHECKSIG`").unwrap(),
+        "true"
+    );
+    // Hardened derivation is possible with private keys
+    assert!(eval("pk(TPRV/84h/0h/0h/0/*)")
+        .unwrap()
+        .contains("/84'/0'/0']tpub"));
+
+    // Never revealed unless explicitly asked for
+    for code in &["TPRV", "TPRV/0/*", "[TPRV, WIF]", "format(\"{}\", WIF)"] {
+        let printed = eval(code).unwrap();
+        assert!(
+            !printed.contains(tprv) && !printed.contains(wif),
+            "{}",
+            printed
+        );
+    }
+    assert_eq!(eval("revealSecKey(WIF)").unwrap(), wif);
+
+    // Mainnet keys require the reckless network
+    let err = eval("XPRV").unwrap_err().to_string();
+    assert!(err.contains("Mainnet private keys are rejected"), "{}", err);
+    assert!(eval("secKey(\"XPRV\", _$$_RECKLESSLY_RISK_MY_BITCOINS_$$_)").is_ok());
+    assert!(eval("secKey(\"TPRV\", _$$_RECKLESSLY_RISK_MY_BITCOINS_$$_)").is_err());
+
+    // Not folded into the printed program, where they'd be rejected as bare mainnet keys
+    let code = format!(
+        "$key = secKey(\"{}\", _$$_RECKLESSLY_RISK_MY_BITCOINS_$$_); [ $key, {} ]",
+        xprv, wif
+    );
+    let folded = format_program(&fold(&parse(&code).unwrap(), &Scope::root()));
+    assert_eq!(
+        folded,
+        format!(
+            "$key = secKey(\n  \"{}\",\n  _$$_RECKLESSLY_RISK_MY_BITCOINS_$$_\n);\n[ $key, {} ]\n",
+            xprv, wif
+        )
+    );
+    assert_eq!(run(&folded).unwrap(), run(&code).unwrap());
+}
+
+#[test]
+fn test_fmt() {
+    let code = r#"// Header
+
+fn   f( $a,$b=2,...$rest )=$a+$b*(2 - 1) ; // trailing
+let [$x , ...$y]=[1,2,3];
+$m = {a:1,"b c":2, // after b
+  // before d
+  d: 3 of [pk(A),pk(B),pk(C),pk(D)]};
+$s = "x={$x} {} \{lit\}";
+$d = older(heightwise 1 day 2 hours) && 10@pk(A) || -(1 + 2) * 3 ?? `OP_DUP
+  $x <($x + 1)> OP_CHECKSIG`;
+fn g($v) {
+
+  $w = $v/1h/*;
+  $w.0 // last
+}
+f(1, b: 3)
+"#;
+    let expected = r#"// Header
+
+fn f($a, $b = 2, ...$rest) = $a + $b * (2 - 1); // trailing
+let [$x, ...$y] = [ 1, 2, 3 ];
+$m = {
+  a: 1,
+  "b c": 2, // after b
+  // before d
+  d: 3 of [ pk(A), pk(B), pk(C), pk(D) ]
+};
+$s = "x={$x} {} \{lit\}";
+$d = older(heightwise 1 day 2 hours) && 10@pk(A) || -(1 + 2) * 3 ?? `
+  OP_DUP
+  $x <($x + 1)> OP_CHECKSIG
+`;
+fn g($v) {
+  $w = $v/1h/*;
+  $w.0 // last
+}
+f(1, b: 3)
+"#;
+    let formatted = format_source(code).unwrap();
+    assert_eq!(formatted, expected);
+    // Formatting is idempotent
+    assert_eq!(format_source(&formatted).unwrap(), formatted);
+
+    // Long expressions are broken over multiple lines
+    let formatted = format_source(&format!("[ {} ]", vec!["pk(A)"; 20].join(", "))).unwrap();
+    assert!(
+        formatted.starts_with("[\n  pk(A),\n  pk(A),\n"),
+        "{}",
+        formatted
+    );
+
+    // Comments are the trivia skipped by the parser's lexer, so `//` within strings is not one
+    let code = "$s = \"http://x \\\" // y\"; // comment\n$s\n";
+    assert_eq!(format_source(code).unwrap(), code);
+    let comments = minsc::pretty::comments(code);
+    assert_eq!(comments.len(), 1);
+    assert_eq!(comments[0].text, "// comment");
+
+    // The AST can be displayed back as source code
+    assert_eq!(
+        parse("{ a: 1 }.a + -$x ?? [1,2]").unwrap().to_string(),
+        "{ { a: 1 }.a + -$x ?? [ 1, 2 ] }"
+    );
+}
+
+#[test]
+fn test_type_check() {
+    let err = |code: &str| run(code).unwrap_err().to_string();
+    assert_eq!(err("pk(`OP_1`)"), "in pk(): Expected KeyLike, not Script");
+    assert_eq!(
+        err("older([ 1, 2 ])"),
+        "in older(): Expected Number, not Array"
+    );
+    assert_eq!(
+        err("older(1, 2)"),
+        "Function older expected 1 arguments, not 2"
+    );
+    assert_eq!(
+        err("1 + \"a\""),
+        "in Add: Unsupported operand types Number and String"
+    );
+    assert_eq!(err("if 1 { 2 } else { 3 }"), "Expected Bool, not Number");
+    // Reported before evaluation, which would've failed on the division first
+    assert_eq!(
+        err("$x = 1 / 0; sha256(older(10))"),
+        "in sha256(): Expected Bytes, not Policy"
+    );
+    // Return types are inferred for user functions
+    assert_eq!(
+        err("fn f($n) = older($n); wpkh(f(1))"),
+        "in wpkh(): Expected KeyLike, not Policy"
+    );
+
+    // Annotated parameters and let bindings
+    let eval = |code: &str| run(code).unwrap().to_string();
+    assert_eq!(
+        eval("fn f($a: Number, $b: Bytes|String = \"x\") = [ $a, $b ]; f(1)"),
+        "[ 1, \"x\" ]"
+    );
+    assert_eq!(
+        eval("let $x: Number = 2 * 3; $f = |$y: Number| $y + $x; $f(1)"),
+        "7"
+    );
+    assert_eq!(
+        err("fn f($a: PubKey|SecKey) = $a; f(0x00)"),
+        "in f(): Expected PubKey|SecKey, not Bytes"
+    );
+    assert_eq!(err("let $x: Policy = 1; $x"), "Expected Policy, not Number");
+    // Values the checker cannot infer are checked at runtime
+    assert_eq!(
+        err("fn f($a: Number) = $a; fn g($b) = f($b); g(\"a\")"),
+        "in g(): in f(): Expected Number, not String"
+    );
+    assert!(err("fn f($a: Foo) = $a; 1").contains("Invalid type: Foo"));
+}
+
+#[test]
+fn test_fold() {
+    // Constant definitions are pre-evaluated, leaving the parameters open
+    let lib = parse_lib(&replace_dummy(
+        "$delay = 2 * 3 + 1; $owner = pk(A); fn cov($user) = $owner && pk($user) && older($delay * 10);",
+    ))
+    .unwrap();
+    assert_eq!(
+        replace_dummy(&fold_lib(&lib, &Scope::root()).to_string()),
+        replace_dummy(
+            "$delay = 7;\n$owner = pk(A);\nfn cov($user) = pk(A)\n  && pk($user)\n  && older(70);\n"
+        )
+    );
+
+    let code = "$n = 2 * 5; if $n > 5 { [ $n, $x + $n, { $y = 3; $y * 2 } ] } else { $z }";
+    let folded = fold(&parse(code).unwrap(), &Scope::root());
+    assert_eq!(format_program(&folded), "$n = 10;\n[ 10, $x + 10, 6 ]\n");
+
+    // Evaluating the folded program for many parameter instances matches evaluating the original
+    let program = parse(&replace_dummy(
+        "fn cov($user) = pk(A) && pk($user) && older(3 * 144); cov($user_pk)",
+    ))
+    .unwrap();
+    let folded = fold(&program, &Scope::root());
+    for user in &["B", "C", "D"] {
+        let mut scope = Scope::root().child();
+        let user_pk = run(&replace_dummy(user)).unwrap();
+        scope.set("$user_pk", user_pk).unwrap();
+        assert_eq!(folded.eval(&scope).unwrap(), program.eval(&scope).unwrap());
+    }
+
+    // The folded function definitions don't keep the scopes used for folding alive
+    let scope = Scope::root();
+    let weak = scope.downgrade();
+    fold(
+        &parse("fn f($x) = $x + 1; fn g() = f(1); g()").unwrap(),
+        &scope,
+    );
+    drop(scope);
+    assert!(weak.upgrade().is_none());
+
+    // Values without a source form (descriptors, miniscript, addresses and functions) are kept as
+    // the expressions producing them, so that the folded program parses back
+    let code = replace_dummy(
+        "fn adder($n) = |$x| $x + $n; $d = wpkh(A); [ $d, wsh(pk(A)), miniscript(pk(A)), address($d), sha256, adder(1) ]",
+    );
+    let folded = format_program(&fold(&parse(&code).unwrap(), &Scope::root()));
+    assert_eq!(
+        folded,
+        replace_dummy("fn adder($n) = |$x| $x + $n;\n$d = wpkh(A);\n[\n  $d,\n  wsh(pk(A)),\n  miniscript(pk(A)),\n  address($d),\n  sha256,\n  adder(1)\n]\n")
+    );
+    match (run(&code).unwrap(), run(&folded).unwrap()) {
+        (Value::Array(original), Value::Array(refolded)) => {
+            assert_eq!(original[..5], refolded[..5]);
+            assert!(matches!(refolded[5], Value::Function(_)));
+        }
+        _ => panic!("expected arrays"),
+    }
+}
+
+#[test]
+fn test_lint() {
+    let lints = |code: &str| {
+        lint(&parse(code).unwrap(), &Scope::root())
+            .into_iter()
+            .map(|w| {
+                (
+                    w.lint,
+                    w.message,
+                    code[w.span.start..w.span.end].to_string(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        lints("fn g() { let $x = 1; $_y = 2; fn f($a) = 1; f(0) } g()"),
+        vec![(
+            Lint::UnusedBinding,
+            "$x is never used".into(),
+            "let $x = 1;".into()
+        )]
+    );
+    assert_eq!(
+        lints("fn sha256($x) = $x; sha256(1)"),
+        vec![(
+            Lint::ShadowedBuiltin,
+            "sha256 shadows the built-in function".into(),
+            "fn sha256($x) = $x;".into()
+        )]
+    );
+    assert_eq!(
+        lints("$a = older(1); $a || older(2) || $a"),
+        vec![(
+            Lint::IdenticalBranches,
+            "Identical branch $a repeated in ||".into(),
+            "$a".into()
+        )]
+    );
+    assert_eq!(
+        lints("$d = 1 day; older($d) && older(10)")[0].0,
+        Lint::MixedTimelocks
+    );
+    assert!(lints("older(1 day) && older(2 days) && after(2030-01-01)").is_empty());
+    assert_eq!(
+        lints("CONST = 1; $arr = [ 1, 2 ]; [ $arr.CONST, $arr.(CONST), { a: 1 }.a ]"),
+        vec![(
+            Lint::AmbiguousFieldName,
+            r#".CONST accesses the "CONST" field rather than the CONST variable, use .(CONST) to index by it"#.into(),
+            "CONST".into()
+        )]
+    );
+    // Top-level definitions may be used by the runtime (main()) or by importers
+    assert!(lints("fn main() = 1;").is_empty());
+    assert!(lints("fn helper() = 1; $exported = 2; let [$a, $b] = [1, 2];").is_empty());
+}
+
+#[test]
+fn test_assert_and_tests() {
+    assert_eq!(run("assert(1 < 2); 1").unwrap().to_string(), "1");
+    assert_eq!(
+        run("$x = 1; assert($x == 2, \"x is {$x}\"); $x")
+            .unwrap_err()
+            .to_string(),
+        "in assert(): Assertion failed: x is 1"
+    );
+
+    // Tests are skipped when the program is evaluated normally
+    let code = r#"
+        fn double($x) = $x * 2;
+        test "passes" {
+          assert(double(2) == 4);
+          $y = double(3);
+          $y == 6
+        }
+        test "fails" { assert(double(1) == 3, "oops"); }
+        test "returns false" { double(1) == 3 }
+        test "runs out of budget" { len(repeat(1000000000, 0)) > 0 }
+        double(5)
+    "#;
+    assert_eq!(run(code).unwrap().to_string(), "10");
+
+    let results = run_tests(&parse(code).unwrap(), &Scope::root()).unwrap();
+    let results: Vec<_> = results
+        .into_iter()
+        .map(|test| (test.name, test.result.map_err(|e| e.to_string())))
+        .collect();
+    assert_eq!(results[0], ("passes".into(), Ok(())));
+    assert_eq!(
+        results[1],
+        (
+            "fails".into(),
+            Err("in assert(): Assertion failed: oops".into())
+        )
+    );
+    assert_eq!(
+        results[2].1,
+        Err("Assertion failed: test returned false".into())
+    );
+    assert!(results[3]
+        .1
+        .as_ref()
+        .unwrap_err()
+        .contains("Maximum allocation size"));
+
+    // Tests can only be declared at the top level of the program
+    assert!(parse(r#"fn f() { test "nested" { true } 1 }"#).is_err());
+    assert!(parse(r#"$x = { test "nested" { true } 1 };"#).is_err());
+    assert!(parse(r#"test "outer" { test "nested" { true } true }"#).is_err());
+}
+
+#[test]
+fn test_doc_comments() {
+    let lib = parse_lib(
+        r#"
+        //// Section
+        /// Spendable by Alice after the delay
+        ///
+        /// Uses `older()`, for <n> blocks
+        fn vault($delay, $key = A) = pk($key) && older($delay);
+
+        // Not a doc comment
+        let $a = 1, [$b, ...$c] = [2];
+        /// Typed
+        let $d: Number = 4;
+        /// Not attached to imports
+        import "foo.minsc";
+        "#,
+    )
+    .unwrap();
+    let module = doc::library_docs("lib.minsc", &lib);
+    let items: Vec<_> = module
+        .items
+        .iter()
+        .map(|item| (&*item.name, &*item.signature, item.doc.as_deref()))
+        .collect();
+    assert_eq!(
+        items,
+        vec![
+            (
+                "vault",
+                "fn vault($delay, $key = A)",
+                Some("Spendable by Alice after the delay\n\nUses `older()`, for <n> blocks"),
+            ),
+            ("$a", "$a, $b, $c", None),
+            ("$d", "$d: Number", Some("Typed")),
+        ]
+    );
+
+    let modules = vec![module];
+    let markdown = doc::to_markdown(&modules);
+    assert!(markdown.contains(
+        "### `fn vault($delay, $key = A)`\n\nSpendable by Alice after the delay\n\nUses `older()`, for \\<n> blocks\n"
+    ));
+    let html = doc::to_html(&modules);
+    assert!(html.contains("<p>Uses <code>older()</code>, for &lt;n&gt; blocks</p>"));
+    assert!(html.contains("<a href=\"#lib-minsc-vault\">vault</a>"));
+
+    // The stdlib reference includes the descriptions of native functions and the built-in libraries' doc comments
+    let stdlib = doc::stdlib_docs();
+    let tap_leaf = stdlib[0].items.iter().find(|item| item.name == "tapLeaf");
+    assert_eq!(
+        tap_leaf.unwrap().signature,
+        "tapLeaf(script: ScriptLike, version: Bytes|Number|Null?) -> Bytes"
+    );
+    let switch = stdlib[1].items.iter().find(|item| item.name == "switch");
+    assert!(switch
+        .unwrap()
+        .doc
+        .as_ref()
+        .unwrap()
+        .starts_with("Match the item"));
+}
+
+#[test]
+fn test_scope_not_leaked() {
+    let scope = Scope::root();
+    let weak = scope.downgrade();
+    let code = r#"
+        fn even($n) = if $n == 0 { true } else { odd($n - 1) };
+        fn odd($n) = if $n == 0 { false } else { even($n - 1) };
+        $double = |$x| $x * 2;
+        // Functions outlive the scope they were defined in when they escape it
+        $fact = { fn fact($n) = if $n == 0 { 1 } else { $n * fact($n - 1) }; fact };
+        fn adder($n) = |$x| $x + $n;
+        $add2 = adder(2);
+        // Including functions nested in containers, stored in the (non-block) call scope
+        fn apply($x, $fns = [ |$x| $x + 1, { triple: |$x| $x * 3 } ]) {
+            $inc = $fns.0;
+            $triple = $fns.1.triple;
+            $triple($inc($x))
+        }
+        [ even(4), $double(2), $fact(4), $add2(1), apply(1) ]
+    "#;
+    let res = parse(code).unwrap().eval(&scope.child()).unwrap();
+    assert_eq!(res.to_string(), "[ true, 4, 24, 3, 6 ]");
+
+    // The definitions stored in the scopes (including the stdlib's) don't keep them alive
+    drop(scope);
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn test_imports() {
+    let mut files = MemoryResolver::default();
+    files.add_file(
+        "keys.minsc",
+        &replace_dummy("$alice = pk(A); $bob = pk(B);"),
+    );
+    files.add_file(
+        "lib/covenants.minsc",
+        r#"import "../keys.minsc" as keys;
+           fn vault($delay) = keys::$alice && older($delay);"#,
+    );
+    files.add_file(
+        "tested.minsc",
+        r#"fn double($x) = $x * 2;
+           test "doubles" { double(2) == 4 }
+           test "fails" { assert(false, "not run on import"); }"#,
+    );
+    files.add_file("cycle/a.minsc", r#"import "b.minsc";"#);
+    files.add_file("cycle/b.minsc", r#"import "./a.minsc";"#);
+
+    let run = |code: &str| {
+        let importer = Rc::new(Importer::new(files.clone(), Some("main.minsc".into())));
+        run_with_importer(code, &importer).map(|v| v.to_string())
+    };
+    assert_eq!(
+        run(r#"import "lib/covenants.minsc" as cov; import "keys.minsc"; cov::vault(10) || $bob"#)
+            .unwrap(),
+        replace_dummy("or(1@and(pk(A),older(10)),1@pk(B))")
+    );
+
+    // Tests declared in libraries are skipped when they're imported
+    assert_eq!(run(r#"import "tested.minsc"; double(3)"#).unwrap(), "6");
+
+    let err = run(r#"import "cycle/a.minsc";"#).unwrap_err().to_string();
+    assert!(err.contains("cycle/a.minsc -> cycle/b.minsc -> cycle/a.minsc"));
+    assert!(run(r#"import "missing.minsc";"#).is_err());
+    // Imports are not available without an importer
+    assert!(minsc::run(r#"import "keys.minsc"; 1"#).is_err());
+}
+
+#[test]
+fn test_error_location() {
+    let code = "fn f($x) = $x + $y;\n\nf(1)";
+    let err = run(code).unwrap_err().render("test.minsc", code);
+    assert!(err.starts_with("error: Undefined variable: $y\n --> test.minsc:1:17\n"));
+    assert!(err.contains("1 | fn f($x) = $x + $y;\n  |                 ^^"));
+    assert!(err.ends_with("= in f(), called at test.minsc:3:1"));
+
+    let code = "pk(A) &&";
+    let err = run(code).unwrap_err().render("test.minsc", code);
+    assert!(err.contains(" --> test.minsc:1:9"));
+
+    let code = "$a = 1;\n$s = \"a={$a}, b={ $c }\";";
+    let err = run(code).unwrap_err().render("test.minsc", code);
+    assert!(err.starts_with("error: Undefined variable: $c\n --> test.minsc:2:19\n"));
+}
+
 fn replace_dummy(s: &str) -> String {
     s.replace(
         "A",
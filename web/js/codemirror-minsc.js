@@ -8,9 +8,10 @@ CodeMirror.defineSimpleMode("minsc",{
     {regex: /\b([a-f0-9]{8}|[a-f0-9]{40,130}|[xt]pub[0-9a-zA-Z]{100,120})\b/, token: "number"},
     {regex: /\b\d+\b/, token: "number"},
     {regex: /\b(fn)(\s+)([$a-zA-Z_]\w*)/, token: ["keyword", null, "def"]},
-    {regex: /\b(of|return|let|heightwise|likely)\b/, token: "keyword"},
+    {regex: /\b(of|return|let|if|else|import|as|heightwise|likely)\b/, token: "keyword"},
     {regex: /\/\/.*/, token: "comment"},
-    {regex: /[-+\/*=<>!;@]+|&&|\|\|/, token: "operator"}, // */
+    {regex: /"(?:[^\\"]|\\.)*"/, token: "string"},
+    {regex: /[-+\/*=<>!;@]+|&&|\|\||\?\?/, token: "operator"}, // */
     {regex: /\b(or|and|thresh)\b/, token: "builtin"},
     {regex: /\b(pk|older|after|(sha|hash)256|(ripemd|hash)160|any|all|prob|wsh|wpkh|sh|miniscript|address|script_pubkey|script_witness)\b/, token: "builtin"},
     {regex: /([$a-zA-Z_][$a-zA-Z_0-9]*)\s*(\()/, token: ["atom", null]},
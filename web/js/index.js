@@ -42,6 +42,7 @@ if (gist_id) {
 worker.addEventListener('message', ({ data }) => {
   loading_el.style.display = 'none'
   clearErrorMark()
+  data.log.forEach(line => console.log(line))
 
   if (data.error) {
     error_el.innerText = snipRegexes(data.error)
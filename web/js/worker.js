@@ -19,9 +19,9 @@ addEventListener('message', ({ data: req }) => {
 function run({ code, network }) {
   try {
     const result = minsc.run_playground(code, network)
-    postMessage({ result })
+    postMessage({ result, log: minsc.takeLog() })
   } catch (err) {
     console.error(''+err, err.stack)
-    postMessage({ error: err.toString(), input: code })
+    postMessage({ error: err.toString(), input: code, log: minsc.takeLog() })
   }
 }